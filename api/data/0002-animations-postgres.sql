@@ -0,0 +1 @@
+ALTER TABLE videos ADD animation SMALLINT NOT NULL DEFAULT 0;
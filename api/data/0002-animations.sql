@@ -0,0 +1 @@
+ALTER TABLE videos ADD animation BIT NOT NULL DEFAULT 0;
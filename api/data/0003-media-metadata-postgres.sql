@@ -0,0 +1,8 @@
+ALTER TABLE photos ADD width BIGINT;
+ALTER TABLE photos ADD height BIGINT;
+ALTER TABLE photos ADD blurhash TEXT;
+
+ALTER TABLE videos ADD width BIGINT;
+ALTER TABLE videos ADD height BIGINT;
+ALTER TABLE videos ADD duration_ms BIGINT;
+ALTER TABLE videos ADD blurhash TEXT;
@@ -0,0 +1,8 @@
+ALTER TABLE photos ADD width INTEGER;
+ALTER TABLE photos ADD height INTEGER;
+ALTER TABLE photos ADD blurhash TEXT;
+
+ALTER TABLE videos ADD width INTEGER;
+ALTER TABLE videos ADD height INTEGER;
+ALTER TABLE videos ADD duration_ms INTEGER;
+ALTER TABLE videos ADD blurhash TEXT;
@@ -0,0 +1,4 @@
+ALTER TABLE posts_photos ADD alt TEXT;
+ALTER TABLE posts_videos ADD alt TEXT;
+ALTER TABLE posts_videos ADD captions TEXT;
+ALTER TABLE posts_audios ADD alt TEXT;
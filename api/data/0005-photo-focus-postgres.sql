@@ -0,0 +1,2 @@
+ALTER TABLE photos ADD focus_x DOUBLE PRECISION;
+ALTER TABLE photos ADD focus_y DOUBLE PRECISION;
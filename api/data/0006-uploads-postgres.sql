@@ -0,0 +1,10 @@
+CREATE TABLE uploads (
+    id BIGINT PRIMARY KEY GENERATED BY DEFAULT AS IDENTITY NOT NULL,
+    user_id BIGINT NOT NULL,
+    media_type TEXT NOT NULL,
+    length BIGINT NOT NULL,
+    received BIGINT NOT NULL,
+    crop TEXT,
+    focus TEXT,
+    created_at BIGINT NOT NULL
+);
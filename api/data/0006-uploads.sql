@@ -0,0 +1,10 @@
+CREATE TABLE uploads (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    user_id INTEGER NOT NULL,
+    media_type TEXT NOT NULL,
+    length INTEGER NOT NULL,
+    received INTEGER NOT NULL,
+    crop TEXT,
+    focus TEXT,
+    created_at INTEGER NOT NULL
+);
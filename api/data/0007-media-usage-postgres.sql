@@ -0,0 +1,7 @@
+CREATE TABLE media_usage (
+    user_id BIGINT PRIMARY KEY NOT NULL,
+    storage_bytes BIGINT NOT NULL,
+    day BIGINT NOT NULL,
+    daily_uploads BIGINT NOT NULL,
+    daily_bytes BIGINT NOT NULL
+);
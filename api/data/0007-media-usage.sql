@@ -0,0 +1,7 @@
+CREATE TABLE media_usage (
+    user_id INTEGER PRIMARY KEY NOT NULL,
+    storage_bytes INTEGER NOT NULL,
+    day INTEGER NOT NULL,
+    daily_uploads INTEGER NOT NULL,
+    daily_bytes INTEGER NOT NULL
+);
@@ -0,0 +1,3 @@
+ALTER TABLE photos ADD created_at INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE videos ADD created_at INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE audios ADD created_at INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,13 @@
+ALTER TABLE photos ADD content_hash TEXT;
+ALTER TABLE photos ADD source_id INTEGER;
+ALTER TABLE videos ADD content_hash TEXT;
+ALTER TABLE videos ADD source_id INTEGER;
+ALTER TABLE audios ADD content_hash TEXT;
+ALTER TABLE audios ADD source_id INTEGER;
+
+CREATE INDEX photos_content_hash ON photos (content_hash);
+CREATE INDEX photos_source_id ON photos (source_id);
+CREATE INDEX videos_content_hash ON videos (content_hash);
+CREATE INDEX videos_source_id ON videos (source_id);
+CREATE INDEX audios_content_hash ON audios (content_hash);
+CREATE INDEX audios_source_id ON audios (source_id);
@@ -0,0 +1,3 @@
+ALTER TABLE audios ADD duration_ms BIGINT;
+ALTER TABLE audios ADD waveform TEXT;
+ALTER TABLE audios ADD opus_96k BYTEA;
@@ -0,0 +1 @@
+ALTER TABLE audios ADD voice SMALLINT NOT NULL DEFAULT 0;
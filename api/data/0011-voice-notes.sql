@@ -0,0 +1 @@
+ALTER TABLE audios ADD voice BIT NOT NULL DEFAULT 0;
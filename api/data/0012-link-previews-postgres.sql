@@ -0,0 +1,20 @@
+CREATE TABLE link_previews (
+    id BIGINT PRIMARY KEY GENERATED BY DEFAULT AS IDENTITY NOT NULL,
+    url TEXT NOT NULL,
+    processing SMALLINT NOT NULL,
+    processing_error TEXT,
+    title TEXT,
+    description TEXT,
+    site_name TEXT,
+    photo_id BIGINT,
+    fetched_at BIGINT NOT NULL
+);
+
+CREATE UNIQUE INDEX link_previews_url ON link_previews (url);
+
+CREATE TABLE posts_link_previews (
+    post_id BIGINT NOT NULL,
+    link_preview_id BIGINT NOT NULL
+);
+
+CREATE INDEX posts_link_previews_post_id ON posts_link_previews (post_id);
@@ -0,0 +1,20 @@
+CREATE TABLE link_previews (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    url TEXT NOT NULL,
+    processing BIT NOT NULL,
+    processing_error TEXT,
+    title TEXT,
+    description TEXT,
+    site_name TEXT,
+    photo_id INTEGER,
+    fetched_at INTEGER NOT NULL
+);
+
+CREATE UNIQUE INDEX link_previews_url ON link_previews (url);
+
+CREATE TABLE posts_link_previews (
+    post_id INTEGER NOT NULL,
+    link_preview_id INTEGER NOT NULL
+);
+
+CREATE INDEX posts_link_previews_post_id ON posts_link_previews (post_id);
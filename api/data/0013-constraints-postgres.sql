@@ -0,0 +1,145 @@
+ALTER TABLE likes RENAME TO likes_old;
+
+CREATE TABLE likes (
+    post_id BIGINT NOT NULL REFERENCES posts (id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    PRIMARY KEY (post_id, user_id)
+);
+
+INSERT INTO likes (post_id, user_id)
+SELECT DISTINCT post_id, user_id FROM likes_old
+WHERE post_id IN (SELECT id FROM posts) AND user_id IN (SELECT id FROM users);
+
+DROP TABLE likes_old;
+
+CREATE INDEX likes_user_id ON likes (user_id);
+
+ALTER TABLE reposts RENAME TO reposts_old;
+
+CREATE TABLE reposts (
+    post_id BIGINT NOT NULL REFERENCES posts (id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    PRIMARY KEY (post_id, user_id)
+);
+
+INSERT INTO reposts (post_id, user_id)
+SELECT DISTINCT post_id, user_id FROM reposts_old
+WHERE post_id IN (SELECT id FROM posts) AND user_id IN (SELECT id FROM users);
+
+DROP TABLE reposts_old;
+
+CREATE INDEX reposts_user_id ON reposts (user_id);
+
+ALTER TABLE comments RENAME TO comments_old;
+
+CREATE TABLE comments (
+    post_id BIGINT NOT NULL REFERENCES posts (id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    comment_post_id BIGINT NOT NULL REFERENCES posts (id) ON DELETE CASCADE,
+    PRIMARY KEY (post_id, comment_post_id)
+);
+
+INSERT INTO comments (post_id, user_id, comment_post_id)
+SELECT post_id, MIN(user_id), comment_post_id FROM comments_old
+WHERE post_id IN (SELECT id FROM posts) AND comment_post_id IN (SELECT id FROM posts)
+    AND user_id IN (SELECT id FROM users)
+GROUP BY post_id, comment_post_id;
+
+DROP TABLE comments_old;
+
+CREATE UNIQUE INDEX comments_comment_post_id ON comments (comment_post_id);
+CREATE INDEX comments_user_id ON comments (user_id);
+
+ALTER TABLE follows RENAME TO follows_old;
+
+CREATE TABLE follows (
+    user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    sub_user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    PRIMARY KEY (user_id, sub_user_id)
+);
+
+INSERT INTO follows (user_id, sub_user_id)
+SELECT DISTINCT user_id, sub_user_id FROM follows_old
+WHERE user_id IN (SELECT id FROM users) AND sub_user_id IN (SELECT id FROM users);
+
+DROP TABLE follows_old;
+
+CREATE INDEX follows_sub_user_id ON follows (sub_user_id);
+
+ALTER TABLE posts_photos RENAME TO posts_photos_old;
+
+CREATE TABLE posts_photos (
+    post_id BIGINT NOT NULL REFERENCES posts (id) ON DELETE CASCADE,
+    photo_id BIGINT NOT NULL REFERENCES photos (id) ON DELETE CASCADE,
+    alt TEXT,
+    PRIMARY KEY (post_id, photo_id)
+);
+
+INSERT INTO posts_photos (post_id, photo_id, alt)
+SELECT post_id, photo_id, MAX(alt) FROM posts_photos_old
+WHERE post_id IN (SELECT id FROM posts) AND photo_id IN (SELECT id FROM photos)
+GROUP BY post_id, photo_id;
+
+DROP TABLE posts_photos_old;
+
+CREATE INDEX posts_photos_photo_id ON posts_photos (photo_id);
+
+ALTER TABLE posts_videos RENAME TO posts_videos_old;
+
+CREATE TABLE posts_videos (
+    post_id BIGINT NOT NULL REFERENCES posts (id) ON DELETE CASCADE,
+    video_id BIGINT NOT NULL REFERENCES videos (id) ON DELETE CASCADE,
+    alt TEXT,
+    captions TEXT,
+    PRIMARY KEY (post_id, video_id)
+);
+
+INSERT INTO posts_videos (post_id, video_id, alt, captions)
+SELECT post_id, video_id, MAX(alt), MAX(captions) FROM posts_videos_old
+WHERE post_id IN (SELECT id FROM posts) AND video_id IN (SELECT id FROM videos)
+GROUP BY post_id, video_id;
+
+DROP TABLE posts_videos_old;
+
+CREATE INDEX posts_videos_video_id ON posts_videos (video_id);
+
+ALTER TABLE posts_audios RENAME TO posts_audios_old;
+
+CREATE TABLE posts_audios (
+    post_id BIGINT NOT NULL REFERENCES posts (id) ON DELETE CASCADE,
+    audio_id BIGINT NOT NULL REFERENCES audios (id) ON DELETE CASCADE,
+    alt TEXT,
+    PRIMARY KEY (post_id, audio_id)
+);
+
+INSERT INTO posts_audios (post_id, audio_id, alt)
+SELECT post_id, audio_id, MAX(alt) FROM posts_audios_old
+WHERE post_id IN (SELECT id FROM posts) AND audio_id IN (SELECT id FROM audios)
+GROUP BY post_id, audio_id;
+
+DROP TABLE posts_audios_old;
+
+CREATE INDEX posts_audios_audio_id ON posts_audios (audio_id);
+
+ALTER TABLE posts_link_previews RENAME TO posts_link_previews_old;
+
+CREATE TABLE posts_link_previews (
+    post_id BIGINT NOT NULL REFERENCES posts (id) ON DELETE CASCADE,
+    link_preview_id BIGINT NOT NULL REFERENCES link_previews (id) ON DELETE CASCADE,
+    PRIMARY KEY (post_id, link_preview_id)
+);
+
+INSERT INTO posts_link_previews (post_id, link_preview_id)
+SELECT DISTINCT post_id, link_preview_id FROM posts_link_previews_old
+WHERE post_id IN (SELECT id FROM posts) AND link_preview_id IN (SELECT id FROM link_previews);
+
+DROP TABLE posts_link_previews_old;
+
+CREATE INDEX posts_link_previews_link_preview_id ON posts_link_previews (link_preview_id);
+
+CREATE INDEX posts_user_id ON posts (user_id);
+CREATE INDEX photos_user_id ON photos (user_id);
+CREATE INDEX videos_user_id ON videos (user_id);
+CREATE INDEX audios_user_id ON audios (user_id);
+CREATE INDEX uploads_user_id ON uploads (user_id);
+CREATE INDEX link_previews_photo_id ON link_previews (photo_id);
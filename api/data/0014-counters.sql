@@ -0,0 +1,10 @@
+ALTER TABLE posts ADD like_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE posts ADD comment_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE users ADD follower_count INTEGER NOT NULL DEFAULT 0;
+
+UPDATE posts SET
+    like_count = (SELECT COUNT(*) FROM likes WHERE likes.post_id = posts.id),
+    comment_count = (SELECT COUNT(*) FROM comments WHERE comments.post_id = posts.id);
+
+UPDATE users SET
+    follower_count = (SELECT COUNT(*) FROM follows WHERE follows.sub_user_id = users.id);
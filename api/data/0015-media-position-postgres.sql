@@ -0,0 +1,3 @@
+ALTER TABLE posts_photos ADD position BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE posts_videos ADD position BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE posts_audios ADD position BIGINT NOT NULL DEFAULT 0;
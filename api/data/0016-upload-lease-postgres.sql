@@ -0,0 +1 @@
+ALTER TABLE uploads ADD locked_until_ms BIGINT;
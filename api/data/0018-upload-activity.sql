@@ -0,0 +1,2 @@
+ALTER TABLE uploads ADD updated_at INTEGER NOT NULL DEFAULT 0;
+UPDATE uploads SET updated_at = created_at;
@@ -1,6 +1,6 @@
 #!/bin/bash
 rm main.db
-for file in data/0000-base-schema.sql data/0001-media-update.sql; do
+for file in data/0000-base-schema.sql data/0001-media-update.sql data/0002-animations.sql data/0003-media-metadata.sql data/0004-media-descriptions.sql data/0005-photo-focus.sql data/0006-uploads.sql data/0007-media-usage.sql data/0008-media-created-at.sql data/0009-media-dedup.sql data/0010-audio-waveform.sql data/0011-voice-notes.sql data/0012-link-previews.sql data/0013-constraints.sql data/0014-counters.sql data/0015-media-position.sql data/0016-upload-lease.sql data/0017-link-preview-lease.sql data/0018-upload-activity.sql; do
     echo "Running transpile $file"
     python data/transpile.py $file
 done
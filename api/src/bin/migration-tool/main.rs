@@ -1,8 +1,10 @@
-use futures::TryStreamExt;
+use std::{error::Error, pin::Pin};
+
+use futures::{StreamExt, TryStreamExt, future};
 use sqlite2pg::Migrate;
-use sqlx::{PgPool, QueryBuilder, SqlitePool, prelude::FromRow};
+use sqlx::{PgPool, SqlitePool};
 
-#[derive(FromRow, Migrate)]
+#[derive(Migrate)]
 #[table("posts")]
 struct Post {
     #[seq_key]
@@ -11,52 +13,73 @@ struct Post {
     message: Option<String>,
     comment: i16,
     deleted: i16,
+    like_count: i64,
+    comment_count: i64,
 }
 
-#[derive(FromRow, Migrate)]
+#[derive(Migrate)]
 #[table("likes")]
 struct Like {
+    #[migrate(key)]
     post_id: i64,
+    #[migrate(key)]
     user_id: i64,
 }
 
-#[derive(FromRow, Migrate)]
+#[derive(Migrate)]
 #[table("comments")]
 struct Comment {
+    #[migrate(key)]
     post_id: i64,
     user_id: i64,
+    #[migrate(key)]
     comment_post_id: i64,
 }
 
-#[derive(FromRow, Migrate)]
+#[derive(Migrate)]
 #[table("reposts")]
 struct Repost {
+    #[migrate(key)]
     post_id: i64,
+    #[migrate(key)]
     user_id: i64,
 }
 
-#[derive(FromRow, Migrate)]
+#[derive(Migrate)]
 #[table("posts_photos")]
 struct PostPhoto {
+    #[migrate(key)]
     post_id: i64,
+    #[migrate(key)]
     photo_id: i64,
+    alt: Option<String>,
+    position: i64,
 }
 
-#[derive(FromRow, Migrate)]
+#[derive(Migrate)]
 #[table("posts_videos")]
 struct PostVideo {
+    #[migrate(key)]
     post_id: i64,
+    #[migrate(key)]
     video_id: i64,
+    alt: Option<String>,
+    captions: Option<String>,
+    position: i64,
 }
 
-#[derive(FromRow, Migrate)]
+#[derive(Migrate)]
 #[table("posts_audios")]
 struct PostAudio {
+    #[migrate(key)]
     post_id: i64,
+    #[migrate(key)]
     audio_id: i64,
+    alt: Option<String>,
+    position: i64,
 }
 
-#[derive(FromRow, Migrate)]
+#[derive(Migrate)]
 #[table("users")]
 struct User {
     #[seq_key]
@@ -68,16 +91,19 @@ struct User {
     banner_photo_id: Option<i64>,
     bio: Option<String>,
     deleted: i16,
+    follower_count: i64,
 }
 
-#[derive(FromRow, Migrate)]
+#[derive(Migrate)]
 #[table("follows")]
 struct Follow {
+    #[migrate(key)]
     user_id: i64,
+    #[migrate(key)]
     sub_user_id: i64,
 }
 
-#[derive(FromRow, Migrate)]
+#[derive(Migrate)]
 #[table("photos")]
 struct Photo {
     #[seq_key]
@@ -87,12 +113,20 @@ struct Photo {
     processing_error: Option<String>,
     profile_picture: i16,
     banner: i16,
+    width: Option<i64>,
+    height: Option<i64>,
+    focus_x: Option<f64>,
+    focus_y: Option<f64>,
+    blurhash: Option<String>,
     jpg_small: Option<Vec<u8>>,
     jpg_medium: Option<Vec<u8>>,
     jpg_large: Option<Vec<u8>>,
+    created_at: i64,
+    content_hash: Option<String>,
+    source_id: Option<i64>,
 }
 
-#[derive(FromRow, Migrate)]
+#[derive(Migrate)]
 #[table("videos")]
 struct Video {
     #[seq_key]
@@ -100,11 +134,19 @@ struct Video {
     user_id: i64,
     processing: i16,
     processing_error: Option<String>,
+    animation: i16,
+    width: Option<i64>,
+    height: Option<i64>,
+    duration_ms: Option<i64>,
+    blurhash: Option<String>,
     thumbnail: Option<Vec<u8>>,
     mp4_480p: Option<Vec<u8>>,
+    created_at: i64,
+    content_hash: Option<String>,
+    source_id: Option<i64>,
 }
 
-#[derive(FromRow, Migrate)]
+#[derive(Migrate)]
 #[table("audios")]
 struct Audio {
     #[seq_key]
@@ -112,32 +154,487 @@ struct Audio {
     user_id: i64,
     processing: i16,
     processing_error: Option<String>,
+    voice: i16,
     title: Option<String>,
     artist: Option<String>,
     mp3_128k: Option<Vec<u8>>,
     thumbnail: Option<Vec<u8>>,
+    duration_ms: Option<i64>,
+    waveform: Option<String>,
+    opus_96k: Option<Vec<u8>>,
+    created_at: i64,
+    content_hash: Option<String>,
+    source_id: Option<i64>,
+}
+
+#[derive(Migrate)]
+#[table("media_usage")]
+struct MediaUsage {
+    #[migrate(key)]
+    user_id: i64,
+    storage_bytes: i64,
+    day: i64,
+    daily_uploads: i64,
+    daily_bytes: i64,
+}
+
+#[derive(Migrate)]
+#[table("link_previews")]
+struct LinkPreview {
+    #[seq_key]
+    id: i64,
+    url: String,
+    processing: i16,
+    processing_error: Option<String>,
+    title: Option<String>,
+    description: Option<String>,
+    site_name: Option<String>,
+    photo_id: Option<i64>,
+    fetched_at: i64,
+}
+
+#[derive(Migrate)]
+#[table("posts_link_previews")]
+struct PostLinkPreview {
+    #[migrate(key)]
+    post_id: i64,
+    #[migrate(key)]
+    link_preview_id: i64,
+}
+
+type Job<'a, T> = Pin<Box<dyn Future<Output = Result<T, sqlx::Error>> + 'a>>;
+
+struct Table {
+    name: &'static str,
+    parents: &'static [&'static str],
+    sqlite_to_pg: for<'a> fn(&'a SqlitePool, &'a PgPool, usize) -> Job<'a, u64>,
+    pg_to_sqlite: for<'a> fn(&'a PgPool, &'a SqlitePool, usize) -> Job<'a, u64>,
+    count_rows: for<'a> fn(&'a SqlitePool, &'a PgPool) -> Job<'a, (i64, i64)>,
+    sqlite_checksum: for<'a> fn(&'a SqlitePool) -> Job<'a, (i64, u64)>,
+    pg_checksum: for<'a> fn(&'a PgPool) -> Job<'a, (i64, u64)>,
+    truncate_sqlite: for<'a> fn(&'a SqlitePool) -> Job<'a, ()>,
+    truncate_pg: for<'a> fn(&'a PgPool) -> Job<'a, ()>,
+    clear_progress_sqlite: for<'a> fn(&'a SqlitePool) -> Job<'a, ()>,
+    clear_progress_pg: for<'a> fn(&'a PgPool) -> Job<'a, ()>,
+}
+
+macro_rules! table {
+    ($table:ident $(, $parent:ident)*) => {
+        Table {
+            name: $table::TABLE,
+            parents: &[$($parent::TABLE),*],
+            sqlite_to_pg: |source, target, batch_size| {
+                Box::pin($table::sqlite_to_pg(source, target, batch_size))
+            },
+            pg_to_sqlite: |source, target, batch_size| {
+                Box::pin($table::pg_to_sqlite(source, target, batch_size))
+            },
+            count_rows: |sqlite, pg| Box::pin($table::count_rows(sqlite, pg)),
+            sqlite_checksum: |pool| Box::pin($table::sqlite_checksum(pool)),
+            pg_checksum: |pool| Box::pin($table::pg_checksum(pool)),
+            truncate_sqlite: |pool| Box::pin($table::truncate_sqlite(pool)),
+            truncate_pg: |pool| Box::pin($table::truncate_pg(pool)),
+            clear_progress_sqlite: |pool| Box::pin($table::clear_progress_sqlite(pool)),
+            clear_progress_pg: |pool| Box::pin($table::clear_progress_pg(pool)),
+        }
+    };
+}
+
+fn tables() -> Vec<Table> {
+    vec![
+        table!(User),
+        table!(Post),
+        table!(Photo),
+        table!(Video),
+        table!(Audio),
+        table!(MediaUsage),
+        table!(LinkPreview),
+        table!(Like, Post, User),
+        table!(Comment, Post, User),
+        table!(Repost, Post, User),
+        table!(Follow, User),
+        table!(PostPhoto, Post, Photo),
+        table!(PostVideo, Post, Video),
+        table!(PostAudio, Post, Audio),
+        table!(PostLinkPreview, Post, LinkPreview),
+    ]
+}
+
+const USAGE: &str =
+    "usage: migration-tool [sqlite2pg|pg2sqlite|dry-run|verify] [--tables a,b] [--truncate-target]";
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    std::process::exit(2);
+}
+
+async fn copy(
+    tables: &[&Table],
+    sqlite: &SqlitePool,
+    postgres: &PgPool,
+    to_pg: bool,
+    batch_size: usize,
+    parallelism: usize,
+) -> Result<(), sqlx::Error> {
+    let mut pending = tables.to_vec();
+    while !pending.is_empty() {
+        let (ready, blocked): (Vec<&Table>, Vec<&Table>) = pending.iter().partition(|table| {
+            table
+                .parents
+                .iter()
+                .all(|parent| pending.iter().all(|t| t.name != *parent))
+        });
+        futures::stream::iter(ready.iter().map(|table| async move {
+            let copied = if to_pg {
+                (table.sqlite_to_pg)(sqlite, postgres, batch_size).await?
+            } else {
+                (table.pg_to_sqlite)(postgres, sqlite, batch_size).await?
+            };
+            Ok::<_, sqlx::Error>((table.name, copied))
+        }))
+        .buffer_unordered(parallelism)
+        .try_for_each(|(name, copied)| {
+            println!("{name}: copied {copied} rows");
+            future::ready(Ok(()))
+        })
+        .await?;
+        pending = blocked;
+    }
+    Ok(())
+}
+
+async fn verify(
+    tables: &[&Table],
+    sqlite: &SqlitePool,
+    postgres: &PgPool,
+    parallelism: usize,
+) -> Result<bool, sqlx::Error> {
+    let results = futures::stream::iter(tables.iter().map(|table| async move {
+        futures::try_join!(
+            (table.sqlite_checksum)(sqlite),
+            (table.pg_checksum)(postgres)
+        )
+    }))
+    .buffered(parallelism)
+    .try_collect::<Vec<_>>()
+    .await?;
+
+    let mut mismatched = false;
+    for (table, ((sqlite_rows, sqlite_sum), (pg_rows, pg_sum))) in tables.iter().zip(results) {
+        let status = if sqlite_rows != pg_rows {
+            "ROW COUNT MISMATCH"
+        } else if sqlite_sum != pg_sum {
+            "CHECKSUM MISMATCH"
+        } else {
+            "ok"
+        };
+        mismatched |= status != "ok";
+        println!(
+            "{}: sqlite {sqlite_rows} rows, postgres {pg_rows} rows, {status}",
+            table.name
+        );
+    }
+    Ok(!mismatched)
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<(), Box<dyn Error>> {
+    let mut command = None;
+    let mut filter = None;
+    let mut truncate_target = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tables" => {
+                filter = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage())
+                        .split(',')
+                        .map(|t| t.trim().to_string())
+                        .collect::<Vec<_>>(),
+                )
+            }
+            "--truncate-target" => truncate_target = true,
+            "sqlite2pg" | "pg2sqlite" | "dry-run" | "verify" if command.is_none() => {
+                command = Some(arg)
+            }
+            _ => usage(),
+        }
+    }
+    let command = command.unwrap_or_else(|| "sqlite2pg".to_string());
+
+    let tables = tables();
+    if let Some(unknown) = filter
+        .iter()
+        .flatten()
+        .find(|name| tables.iter().all(|t| t.name != name.as_str()))
+    {
+        eprintln!("unknown table {unknown}");
+        usage();
+    }
+    let mut selected = tables
+        .iter()
+        .filter(|t| {
+            filter
+                .as_ref()
+                .is_none_or(|f| f.iter().any(|n| n == t.name))
+        })
+        .collect::<Vec<_>>();
+    if truncate_target {
+        while let Some(dependent) = tables.iter().find(|t| {
+            selected.iter().all(|s| s.name != t.name)
+                && t.parents
+                    .iter()
+                    .any(|p| selected.iter().any(|s| s.name == *p))
+        }) {
+            println!(
+                "{}: included because truncating its parent tables deletes its rows",
+                dependent.name
+            );
+            selected.push(dependent);
+        }
+        selected.sort_by_key(|s| tables.iter().position(|t| t.name == s.name));
+    }
+
     let postgres =
-        PgPool::connect_lazy(&std::env::var("POSTGRES_URL").expect("POSTGRES_URL not set"))
-            .unwrap();
+        PgPool::connect_lazy(&std::env::var("POSTGRES_URL").expect("POSTGRES_URL not set"))?;
 
     let sqlite =
-        SqlitePool::connect_lazy(&std::env::var("SQLITE_URL").expect("SQLITE_URL not set"))
+        SqlitePool::connect_lazy(&std::env::var("SQLITE_URL").expect("SQLITE_URL not set"))?;
+
+    let batch_size = std::env::var("MIGRATION_BATCH_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(500);
+
+    let parallelism = std::env::var("MIGRATION_PARALLELISM")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(4usize)
+        .max(1);
+
+    match command.as_str() {
+        "dry-run" => {
+            let mut mismatched = false;
+            for table in &selected {
+                let (sqlite_rows, pg_rows) = (table.count_rows)(&sqlite, &postgres).await?;
+                mismatched |= sqlite_rows != pg_rows;
+                println!("{}: sqlite {sqlite_rows}, postgres {pg_rows}", table.name);
+            }
+            if mismatched {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        "verify" => {}
+        direction => {
+            let to_pg = direction == "sqlite2pg";
+            // created before tables are copied in parallel, concurrent CREATE TABLE IF NOT EXISTS races on Postgres
+            if to_pg {
+                User::create_progress_pg(&postgres).await?;
+            } else {
+                User::create_progress_sqlite(&sqlite).await?;
+            }
+            if truncate_target {
+                for table in selected.iter().rev() {
+                    if to_pg {
+                        (table.truncate_pg)(&postgres).await?;
+                    } else {
+                        (table.truncate_sqlite)(&sqlite).await?;
+                    }
+                    println!("{}: truncated", table.name);
+                }
+            }
+            // SQLite allows a single writer, so copying into it in parallel only adds lock contention
+            let parallelism = if to_pg { parallelism } else { 1 };
+            copy(
+                &selected,
+                &sqlite,
+                &postgres,
+                to_pg,
+                batch_size,
+                parallelism,
+            )
+            .await?;
+        }
+    }
+
+    if !verify(&selected, &sqlite, &postgres, parallelism).await? {
+        std::process::exit(1);
+    }
+
+    if command != "verify" {
+        for table in &selected {
+            if command == "sqlite2pg" {
+                (table.clear_progress_pg)(&postgres).await?;
+            } else {
+                (table.clear_progress_sqlite)(&sqlite).await?;
+            }
+        }
+        // the progress table is shared, so it is dropped only once no table has progress left in it
+        if command == "sqlite2pg" {
+            User::drop_progress_pg(&postgres).await?;
+        } else {
+            User::drop_progress_sqlite(&sqlite).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlite2pg::Migrate;
+    use sqlx::{PgPool, SqlitePool, postgres::PgPoolOptions, sqlite::SqlitePoolOptions};
+
+    #[derive(Migrate)]
+    #[table("migrate_items")]
+    struct Item {
+        #[seq_key]
+        id: i64,
+        name: String,
+    }
+
+    #[derive(Migrate)]
+    #[table("migrate_tags")]
+    struct Tag {
+        #[migrate(key)]
+        item_id: i64,
+        #[migrate(key)]
+        tag: i64,
+        weight: i64,
+    }
+
+    const SQLITE_SCHEMA: &str = "CREATE TABLE migrate_items (id INTEGER PRIMARY KEY, name TEXT NOT NULL);
+        CREATE TABLE migrate_tags (item_id BIGINT NOT NULL, tag BIGINT NOT NULL, weight BIGINT NOT NULL, PRIMARY KEY (item_id, tag))";
+
+    async fn sqlite() -> SqlitePool {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::raw_sql(SQLITE_SCHEMA).execute(&pool).await.unwrap();
+        pool
+    }
+
+    async fn postgres(url: &str) -> PgPool {
+        let schema = format!("migrate_{}", chrono::Utc::now().timestamp_micros());
+        let admin = PgPool::connect(url).await.unwrap();
+        sqlx::query(&format!("CREATE SCHEMA {schema}"))
+            .execute(&admin)
+            .await
+            .unwrap();
+        admin.close().await;
+        let pool = PgPoolOptions::new()
+            .max_connections(1)
+            .after_connect(move |conn, _| {
+                let schema = schema.clone();
+                Box::pin(async move {
+                    sqlx::query(&format!("SET search_path TO {schema}"))
+                        .execute(conn)
+                        .await?;
+                    Ok(())
+                })
+            })
+            .connect(url)
+            .await
             .unwrap();
+        sqlx::raw_sql(
+            "CREATE TABLE migrate_items (id BIGSERIAL PRIMARY KEY, name TEXT NOT NULL CHECK (name <> 'bad'));
+            CREATE TABLE migrate_tags (item_id BIGINT NOT NULL, tag BIGINT NOT NULL, weight BIGINT NOT NULL CHECK (weight >= 0), PRIMARY KEY (item_id, tag))",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        pool
+    }
+
+    #[tokio::test]
+    async fn resume() {
+        let Some(url) = std::env::var("TEST_DATABASE_URL")
+            .ok()
+            .filter(|url| url.starts_with("postgres"))
+        else {
+            return;
+        };
+        let source = sqlite().await;
+        let target = postgres(&url).await;
+        for id in 1..=10 {
+            sqlx::query("INSERT INTO migrate_items (id, name) VALUES ($1, $2)")
+                .bind(id)
+                .bind(if id == 6 { "bad" } else { "item" })
+                .execute(&source)
+                .await
+                .unwrap();
+        }
+        for item_id in 1..=3 {
+            for tag in 1..=3 {
+                sqlx::query("INSERT INTO migrate_tags (item_id, tag, weight) VALUES ($1, $2, $3)")
+                    .bind(item_id)
+                    .bind(tag)
+                    .bind(if (item_id, tag) == (2, 3) { -1 } else { 1 })
+                    .execute(&source)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        Item::create_progress_pg(&target).await.unwrap();
+        assert!(Item::sqlite_to_pg(&source, &target, 2).await.is_err());
+        assert!(Tag::sqlite_to_pg(&source, &target, 2).await.is_err());
+        assert_eq!(Item::count_rows(&source, &target).await.unwrap(), (10, 4));
+        assert_eq!(Tag::count_rows(&source, &target).await.unwrap(), (9, 4));
+
+        sqlx::query("UPDATE migrate_items SET name = 'fixed' WHERE id = 6")
+            .execute(&source)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE migrate_tags SET weight = 0 WHERE weight < 0")
+            .execute(&source)
+            .await
+            .unwrap();
+        assert_eq!(Item::sqlite_to_pg(&source, &target, 2).await.unwrap(), 6);
+        assert_eq!(Tag::sqlite_to_pg(&source, &target, 2).await.unwrap(), 5);
+        assert_eq!(
+            Item::sqlite_checksum(&source).await.unwrap(),
+            Item::pg_checksum(&target).await.unwrap()
+        );
+        assert_eq!(
+            Tag::sqlite_checksum(&source).await.unwrap(),
+            Tag::pg_checksum(&target).await.unwrap()
+        );
+        let id: i64 =
+            sqlx::query_scalar("INSERT INTO migrate_items (name) VALUES ('new') RETURNING id")
+                .fetch_one(&target)
+                .await
+                .unwrap();
+        assert_eq!(id, 11);
+
+        let copy = sqlite().await;
+        Item::create_progress_sqlite(&copy).await.unwrap();
+        assert_eq!(Item::pg_to_sqlite(&target, &copy, 3).await.unwrap(), 11);
+        assert_eq!(Tag::pg_to_sqlite(&target, &copy, 3).await.unwrap(), 9);
+        assert_eq!(
+            Tag::sqlite_checksum(&copy).await.unwrap(),
+            Tag::pg_checksum(&target).await.unwrap()
+        );
 
-    Post::migrate(&sqlite, &postgres).await;
-    Like::migrate(&sqlite, &postgres).await;
-    Comment::migrate(&sqlite, &postgres).await;
-    Repost::migrate(&sqlite, &postgres).await;
-    PostPhoto::migrate(&sqlite, &postgres).await;
-    PostVideo::migrate(&sqlite, &postgres).await;
-    PostAudio::migrate(&sqlite, &postgres).await;
-    User::migrate(&sqlite, &postgres).await;
-    Follow::migrate(&sqlite, &postgres).await;
-    Photo::migrate(&sqlite, &postgres).await;
-    Video::migrate(&sqlite, &postgres).await;
-    Audio::migrate(&sqlite, &postgres).await;
+        Item::clear_progress_pg(&target).await.unwrap();
+        Item::drop_progress_pg(&target).await.unwrap();
+        let progress: Option<String> =
+            sqlx::query_scalar("SELECT to_regclass('sqlite2pg_progress')::text")
+                .fetch_one(&target)
+                .await
+                .unwrap();
+        assert!(progress.is_some());
+        Tag::clear_progress_pg(&target).await.unwrap();
+        Tag::drop_progress_pg(&target).await.unwrap();
+        let progress: Option<String> =
+            sqlx::query_scalar("SELECT to_regclass('sqlite2pg_progress')::text")
+                .fetch_one(&target)
+                .await
+                .unwrap();
+        assert!(progress.is_none());
+    }
 }
@@ -3,7 +3,7 @@ use std::sync::Arc;
 use crate::{
     SharedState, create_token,
     errors::{CANNOT_INSERT_USER, INVALID_USERNAME_OR_PASSWORD},
-    models::user::User,
+    models::{is_unique_violation, user::User},
 };
 use axum::{Json, Router, extract::State, http::StatusCode, response::IntoResponse, routing::post};
 use regex::Regex;
@@ -34,7 +34,7 @@ async fn auth_login(
     State(state): State<Arc<SharedState>>,
     Json(request): Json<LoginRequest>,
 ) -> axum::response::Result<impl IntoResponse> {
-    let user = User::find(&state.db, None, Some(&request.username), None)
+    let user = User::find(&state.db(), None, Some(&request.username), None)
         .await
         .map_err(|_| (StatusCode::UNAUTHORIZED, INVALID_USERNAME_OR_PASSWORD))?;
 
@@ -80,17 +80,6 @@ async fn auth_register(
         return Err((StatusCode::BAD_REQUEST, "no").into());
     };
 
-    if User::find(&state.db, None, Some(&request.username), None)
-        .await
-        .is_ok()
-    {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "user with this username already exists",
-        )
-            .into());
-    }
-
     let user_id = User::insert(
         &state.rwdb,
         &request.username,
@@ -99,6 +88,12 @@ async fn auth_register(
     )
     .await
     .map_err(|e| {
+        if is_unique_violation(&e) {
+            return (
+                StatusCode::BAD_REQUEST,
+                "user with this username already exists",
+            );
+        }
         println!("{e:?}");
         (StatusCode::INTERNAL_SERVER_ERROR, CANNOT_INSERT_USER)
     })?;
@@ -142,6 +137,19 @@ mod tests {
         let data = json::<UserMixedAuthResponse>(response).await;
         assert!(data.token.len() != 0);
 
+        let response = send_post(
+            state.clone(),
+            "/api/auth/register",
+            None,
+            &RegisterRequest {
+                realname: "test2".to_string(),
+                username: "test2".to_string(),
+                password: "test2test2test2".to_string(),
+            },
+        )
+        .await;
+        assert!(response.status() == StatusCode::BAD_REQUEST);
+
         let response = send_post(
             state.clone(),
             "/api/auth/login",
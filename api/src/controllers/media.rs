@@ -1,27 +1,40 @@
-use std::{sync::Arc, time::Duration};
+use std::{io::SeekFrom, path::PathBuf, sync::Arc};
 
 use axum::{
     Json, Router,
-    extract::{Multipart, Path, State},
+    body::Body,
+    extract::{DefaultBodyLimit, Multipart, Path, State},
     http::{HeaderMap, StatusCode, header},
     response::IntoResponse,
     routing::{get, head, post},
 };
 use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
-use tokio::time::sleep;
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tempfile::{NamedTempFile, TempPath};
+use tokio::{
+    fs,
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
 
 use crate::{
     Claims, SharedState,
-    errors::{CANNOT_USE_THIS_MEDIA_TYPE, INVALID_MEDIA_ID, MEDIA_IS_PROCESSING, MEDIA_NOT_FOUND},
+    errors::{
+        CANNOT_USE_THIS_MEDIA_TYPE, INVALID_MEDIA_ID, MEDIA_IS_PROCESSING, MEDIA_NOT_FOUND,
+        UPLOAD_IS_INCOMPLETE, UPLOAD_IS_LOCKED, UPLOAD_NOT_FOUND, UPLOAD_OFFSET_MISMATCH,
+        UPLOAD_QUOTA_EXCEEDED, UPLOAD_TOO_LARGE,
+    },
     models::{
-        Audio, Photo, Video, audio::AudioUpdateQuery, photo::PhotoUpdateQuery,
+        Audio, MediaUsage, Photo, Upload, Video, audio::AudioUpdateQuery, photo::PhotoUpdateQuery,
         video::VideoUpdateQuery,
     },
-    services::media,
+    services::{consistency::Consistency, media},
 };
 
 enum MediaIdVersion {
     V1 = 1,
+    V2 = 2,
 }
 
 #[derive(PartialEq)]
@@ -31,57 +44,204 @@ pub enum MediaType {
     Audio = 3,
     ProfilePicture = 4,
     Banner = 5,
+    Animation = 6,
 }
 
-#[derive(serde::Serialize)]
-struct MediaResponse {
+pub const MAX_UPLOAD_SIZE: usize = 256 * 1024 * 1024;
+pub const MAX_UPLOAD_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+const UPLOAD_LEASE_MS: i64 = 5 * 60 * 1000;
+const UPLOAD_MEDIA_TYPES: [&str; 7] = [
+    "photo",
+    "profile_picture",
+    "banner",
+    "video",
+    "animation",
+    "audio",
+    "voice",
+];
+const UPLOAD_OFFSET_HEADER: &str = "upload-offset";
+const UPLOAD_LENGTH_HEADER: &str = "upload-length";
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct UploadCreateRequest {
+    #[serde(rename = "type")]
+    pub media_type: String,
+    pub length: i64,
+    pub crop: Option<String>,
+    pub focus: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct UploadResponse {
+    pub id: i64,
+    pub offset: i64,
+    pub length: i64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct MediaUsageResponse {
+    pub storage_bytes: i64,
+    pub storage_quota: i64,
+    pub daily_uploads: i64,
+    pub daily_upload_limit: i64,
+    pub daily_bytes: i64,
+    pub daily_bytes_limit: i64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct QuotaErrorResponse {
+    pub error: String,
+    pub quota: String,
+    pub used: i64,
+    pub limit: i64,
+}
+
+pub struct MediaQuota {
+    pub storage_quota: i64,
+    pub daily_upload_limit: i64,
+    pub daily_bytes_limit: i64,
+}
+
+impl MediaQuota {
+    pub fn from_env() -> Self {
+        let limit = |name: &str, default: i64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+        Self {
+            storage_quota: limit("MEDIA_STORAGE_QUOTA", 1024 * 1024 * 1024),
+            daily_upload_limit: limit("MEDIA_DAILY_UPLOAD_LIMIT", 100),
+            daily_bytes_limit: limit("MEDIA_DAILY_BYTES_LIMIT", 1024 * 1024 * 1024),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ProcessingError {
+    pub code: media::MediaErrorCode,
+    pub message: String,
+}
+
+impl ProcessingError {
+    fn from_column(value: Option<String>) -> Option<Self> {
+        value.map(|value| {
+            let code = media::MediaErrorCode::parse(&value);
+            Self {
+                code,
+                message: code.message().to_string(),
+            }
+        })
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct MediaResponse {
     pub id: String,
     pub processing: bool,
-    pub processing_error: Option<String>,
+    pub processing_error: Option<ProcessingError>,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub focus_x: Option<f64>,
+    pub focus_y: Option<f64>,
+    pub duration_ms: Option<i64>,
+    pub blurhash: Option<String>,
 }
 
-pub fn encode_media_id(media_type: MediaType, id: i64) -> String {
-    let mut buf = vec![];
-    buf.extend_from_slice(&(MediaIdVersion::V1 as i8).to_le_bytes());
-    buf.extend_from_slice(&(media_type as i8).to_le_bytes());
-    buf.extend_from_slice(&id.to_le_bytes());
-    BASE64_URL_SAFE_NO_PAD.encode(&buf)
+const MEDIA_ID_MAC_SIZE: usize = 8;
+
+pub struct MediaIdKey {
+    key: Vec<u8>,
+    v1_max_id: Option<i64>,
 }
 
-pub fn parse_media_id(id: &str) -> Result<(MediaType, i64), (StatusCode, &'static str)> {
-    let data = BASE64_URL_SAFE_NO_PAD
-        .decode(&id)
-        .map_err(|_| (StatusCode::BAD_REQUEST, INVALID_MEDIA_ID))?;
-    if data.len() < 1 {
-        return Err((StatusCode::BAD_REQUEST, INVALID_MEDIA_ID));
+impl MediaIdKey {
+    pub fn new(secret: &[u8], v1_max_id: Option<i64>) -> Self {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(b"media-id");
+        Self {
+            key: mac.finalize().into_bytes().to_vec(),
+            v1_max_id,
+        }
     }
-    let version = match data[0] {
-        1 => MediaIdVersion::V1,
-        _ => return Err((StatusCode::BAD_REQUEST, INVALID_MEDIA_ID)),
-    };
-    match version {
-        MediaIdVersion::V1 => {
-            if data.len() < 10 {
-                return Err((StatusCode::BAD_REQUEST, INVALID_MEDIA_ID));
+
+    pub fn from_env() -> Self {
+        let secret = std::env::var("MEDIA_ID_SECRET")
+            .or_else(|_| std::env::var("JWT_SECRET"))
+            .expect("JWT_SECRET not set");
+        Self::new(
+            secret.as_bytes(),
+            std::env::var("MEDIA_ID_V1_MAX_ID")
+                .ok()
+                .and_then(|v| v.parse().ok()),
+        )
+    }
+
+    fn mac(&self, payload: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(payload);
+        mac
+    }
+
+    pub fn encode(&self, media_type: MediaType, id: i64) -> String {
+        let mut buf = vec![];
+        buf.extend_from_slice(&(MediaIdVersion::V2 as i8).to_le_bytes());
+        buf.extend_from_slice(&(media_type as i8).to_le_bytes());
+        buf.extend_from_slice(&id.to_le_bytes());
+        let mac = self.mac(&buf).finalize().into_bytes();
+        buf.extend_from_slice(&mac[..MEDIA_ID_MAC_SIZE]);
+        BASE64_URL_SAFE_NO_PAD.encode(&buf)
+    }
+
+    pub fn parse(&self, id: &str) -> Result<(MediaType, i64), (StatusCode, &'static str)> {
+        let data = BASE64_URL_SAFE_NO_PAD
+            .decode(id)
+            .map_err(|_| (StatusCode::BAD_REQUEST, INVALID_MEDIA_ID))?;
+        if data.is_empty() {
+            return Err((StatusCode::BAD_REQUEST, INVALID_MEDIA_ID));
+        }
+        let version = match data[0] {
+            1 if self.v1_max_id.is_some() => MediaIdVersion::V1,
+            2 => MediaIdVersion::V2,
+            _ => return Err((StatusCode::BAD_REQUEST, INVALID_MEDIA_ID)),
+        };
+        let id_data = match version {
+            MediaIdVersion::V1 => {
+                if data.len() != 10 {
+                    return Err((StatusCode::BAD_REQUEST, INVALID_MEDIA_ID));
+                }
+                &data[2..10]
             }
-            let mut x = [0u8; 8];
-            let Some(id_data) = data.get(2..) else {
-                return Err((StatusCode::BAD_REQUEST, INVALID_MEDIA_ID));
-            };
-            x.clone_from_slice(&id_data);
-
-            Ok((
-                match data[1] {
-                    1 => MediaType::Photo,
-                    2 => MediaType::Video,
-                    3 => MediaType::Audio,
-                    4 => MediaType::ProfilePicture,
-                    5 => MediaType::Banner,
-                    _ => return Err((StatusCode::BAD_REQUEST, INVALID_MEDIA_ID)),
-                },
-                i64::from_le_bytes(x),
-            ))
+            MediaIdVersion::V2 => {
+                if data.len() != 10 + MEDIA_ID_MAC_SIZE {
+                    return Err((StatusCode::BAD_REQUEST, INVALID_MEDIA_ID));
+                }
+                self.mac(&data[..10])
+                    .verify_truncated_left(&data[10..])
+                    .map_err(|_| (StatusCode::BAD_REQUEST, INVALID_MEDIA_ID))?;
+                &data[2..10]
+            }
+        };
+        let mut x = [0u8; 8];
+        x.clone_from_slice(id_data);
+        let id = i64::from_le_bytes(x);
+        if matches!(version, MediaIdVersion::V1) && self.v1_max_id.is_none_or(|max| id > max) {
+            return Err((StatusCode::BAD_REQUEST, INVALID_MEDIA_ID));
         }
+
+        Ok((
+            match data[1] {
+                1 => MediaType::Photo,
+                2 => MediaType::Video,
+                3 => MediaType::Audio,
+                4 => MediaType::ProfilePicture,
+                5 => MediaType::Banner,
+                6 => MediaType::Animation,
+                _ => return Err((StatusCode::BAD_REQUEST, INVALID_MEDIA_ID)),
+            },
+            id,
+        ))
     }
 }
 
@@ -94,18 +254,22 @@ struct MediaMetadata {
     pub title: Option<String>,
     pub artist: Option<String>,
     pub thumbnail: bool,
+    pub duration_ms: Option<i64>,
+    pub waveform: Option<Vec<u8>>,
+    pub opus: bool,
+    pub voice: bool,
 }
 
 async fn media_metadata(
     State(state): State<Arc<SharedState>>,
     Path(id): Path<String>,
 ) -> axum::response::Result<impl IntoResponse> {
-    let (media_type, num_id) = parse_media_id(&id)?;
+    let (media_type, num_id) = state.media_ids.parse(&id)?;
     if media_type != MediaType::Audio {
         return Err((StatusCode::BAD_REQUEST, CANNOT_USE_THIS_MEDIA_TYPE).into());
     }
 
-    let audio = Audio::find(&state.db, num_id)
+    let audio = Audio::find(&state.db(), num_id)
         .await
         .map_err(|_| (StatusCode::NOT_FOUND, MEDIA_NOT_FOUND))?;
 
@@ -115,6 +279,10 @@ async fn media_metadata(
             title: audio.title,
             artist: audio.artist,
             thumbnail: audio.thumbnail.is_some(),
+            duration_ms: audio.duration_ms,
+            waveform: audio.waveform,
+            opus: audio.opus_96k.is_some(),
+            voice: audio.voice,
         }),
     ))
 }
@@ -128,7 +296,7 @@ async fn media_handler(
         return Err((StatusCode::BAD_REQUEST, CANNOT_USE_THIS_MEDIA_TYPE).into());
     };
 
-    let (media_type, num_id) = parse_media_id(id)?;
+    let (media_type, num_id) = state.media_ids.parse(id)?;
     let mut headers = HeaderMap::new();
 
     if ext.len() < 3 {
@@ -139,7 +307,7 @@ async fn media_handler(
         MediaType::Audio => "128k",
         MediaType::Photo | MediaType::Banner => "medium",
         MediaType::ProfilePicture => "small",
-        MediaType::Video => "480p",
+        MediaType::Video | MediaType::Animation => "480p",
     };
     let (_, preferred_res) = ext.split_once(":").unwrap_or((ext, default_res));
 
@@ -148,7 +316,7 @@ async fn media_handler(
             headers.insert(header::CONTENT_TYPE, "image/jpeg".parse().unwrap());
             let (media, actual_res) = match media_type {
                 MediaType::Audio => {
-                    let audio = Audio::find(&state.db, num_id)
+                    let audio = Audio::find(&state.db(), num_id)
                         .await
                         .map_err(|_| (StatusCode::NOT_FOUND, MEDIA_NOT_FOUND))?;
                     if audio.processing || audio.thumbnail == None {
@@ -156,8 +324,8 @@ async fn media_handler(
                     }
                     (audio.thumbnail, "thumbnail")
                 }
-                MediaType::Video => {
-                    let video = Video::find(&state.db, num_id)
+                MediaType::Video | MediaType::Animation => {
+                    let video = Video::find(&state.db(), num_id)
                         .await
                         .map_err(|_| (StatusCode::NOT_FOUND, MEDIA_NOT_FOUND))?;
                     if video.thumbnail == None {
@@ -166,10 +334,13 @@ async fn media_handler(
                     if video.processing {
                         return Err((StatusCode::NO_CONTENT, MEDIA_IS_PROCESSING).into());
                     }
+                    if video.animation != (media_type == MediaType::Animation) {
+                        return Err((StatusCode::BAD_REQUEST, CANNOT_USE_THIS_MEDIA_TYPE).into());
+                    }
                     (video.thumbnail, "thumbnail")
                 }
                 MediaType::Photo | MediaType::Banner | MediaType::ProfilePicture => {
-                    let photo = Photo::find(&state.db, num_id)
+                    let photo = Photo::find(&state.db(), num_id)
                         .await
                         .map_err(|_| (StatusCode::NOT_FOUND, MEDIA_NOT_FOUND))?;
                     if photo.processing {
@@ -205,16 +376,19 @@ async fn media_handler(
             (media.unwrap(), format!("{id}_{actual_res}.jpg"))
         }
         "mp4" => {
-            if media_type != MediaType::Video {
+            if media_type != MediaType::Video && media_type != MediaType::Animation {
                 return Err((StatusCode::BAD_REQUEST, CANNOT_USE_THIS_MEDIA_TYPE).into());
             }
             headers.insert(header::CONTENT_TYPE, "video/mp4".parse().unwrap());
-            let video = Video::find(&state.db, num_id)
+            let video = Video::find(&state.db(), num_id)
                 .await
                 .map_err(|_| (StatusCode::NOT_FOUND, MEDIA_NOT_FOUND))?;
             if video.processing {
                 return Err((StatusCode::NO_CONTENT, MEDIA_IS_PROCESSING).into());
             }
+            if video.animation != (media_type == MediaType::Animation) {
+                return Err((StatusCode::BAD_REQUEST, CANNOT_USE_THIS_MEDIA_TYPE).into());
+            }
             let formats = [(video.mp4_480p, "480p")];
             let mut best_format = formats
                 .clone()
@@ -234,7 +408,7 @@ async fn media_handler(
                 return Err((StatusCode::BAD_REQUEST, CANNOT_USE_THIS_MEDIA_TYPE).into());
             }
             headers.insert(header::CONTENT_TYPE, "audio/mpeg".parse().unwrap());
-            let audio = Audio::find(&state.db, num_id)
+            let audio = Audio::find(&state.db(), num_id)
                 .await
                 .map_err(|_| (StatusCode::NOT_FOUND, MEDIA_NOT_FOUND))?;
             if audio.processing {
@@ -254,6 +428,22 @@ async fn media_handler(
             let (media, actual_res) = best_format.unwrap();
             (media.unwrap(), format!("{id}_{actual_res}.mp3"))
         }
+        "ogg" => {
+            if media_type != MediaType::Audio {
+                return Err((StatusCode::BAD_REQUEST, CANNOT_USE_THIS_MEDIA_TYPE).into());
+            }
+            headers.insert(header::CONTENT_TYPE, "audio/ogg".parse().unwrap());
+            let audio = Audio::find(&state.db(), num_id)
+                .await
+                .map_err(|_| (StatusCode::NOT_FOUND, MEDIA_NOT_FOUND))?;
+            if audio.processing {
+                return Err((StatusCode::NO_CONTENT, MEDIA_IS_PROCESSING).into());
+            }
+            let Some(media) = audio.opus_96k else {
+                return Err((StatusCode::NOT_FOUND, MEDIA_NOT_FOUND).into());
+            };
+            (media, format!("{id}_96k.ogg"))
+        }
         _ => return Err((StatusCode::BAD_REQUEST, CANNOT_USE_THIS_MEDIA_TYPE).into()),
     };
 
@@ -302,107 +492,461 @@ async fn media_handler(
     Ok((StatusCode::OK, headers, media))
 }
 
-async fn media_upload(
-    State(state): State<Arc<SharedState>>,
-    claims: Claims,
-    mut multipart: Multipart,
-) -> axum::response::Result<impl IntoResponse> {
-    let mut media_type = None;
-    let mut media_data = None;
+pub fn upload_path(upload_dir: &std::path::Path, id: i64) -> PathBuf {
+    upload_dir.join(format!("{id}.part"))
+}
 
-    while let Some(field) = multipart.next_field().await? {
-        let name = field.name().unwrap().to_string();
-        let data = field
-            .bytes()
-            .await
-            .map_err(|_| (StatusCode::BAD_REQUEST, "cannot read body"))?;
+pub fn upload_root() -> PathBuf {
+    std::env::var("UPLOAD_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| std::env::temp_dir().join("rutwt-uploads"))
+}
 
-        match name.as_str() {
-            "type" => media_type = Some(String::from_utf8(data.to_vec()).unwrap()),
-            "data" => media_data = Some(data.to_vec()),
-            _ => {}
+async fn upload_dir() -> Result<PathBuf, (StatusCode, &'static str)> {
+    let dir = upload_root();
+    fs::create_dir_all(&dir).await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "cannot create upload dir",
+        )
+    })?;
+    Ok(dir)
+}
+
+fn parse_crop(crop: Option<&str>) -> Result<Option<media::CropRect>, (StatusCode, &'static str)> {
+    let Some(crop) = crop else {
+        return Ok(None);
+    };
+    let values = crop
+        .split(',')
+        .map(|v| v.trim().parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| (StatusCode::BAD_REQUEST, "invalid crop"))?;
+    let [x, y, width, height] = values[..] else {
+        return Err((StatusCode::BAD_REQUEST, "invalid crop"));
+    };
+    Ok(Some(media::CropRect {
+        x,
+        y,
+        width,
+        height,
+    }))
+}
+
+fn parse_focus(focus: Option<&str>) -> Result<Option<(f64, f64)>, (StatusCode, &'static str)> {
+    let Some(focus) = focus else {
+        return Ok(None);
+    };
+    let values = focus
+        .split(',')
+        .map(|v| v.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| (StatusCode::BAD_REQUEST, "invalid focus"))?;
+    let [x, y] = values[..] else {
+        return Err((StatusCode::BAD_REQUEST, "invalid focus"));
+    };
+    if !(0.0..=1.0).contains(&x) || !(0.0..=1.0).contains(&y) {
+        return Err((StatusCode::BAD_REQUEST, "invalid focus"));
+    }
+    Ok(Some((x, y)))
+}
+
+fn processing_response(id: String) -> MediaResponse {
+    MediaResponse {
+        id,
+        processing: true,
+        processing_error: None,
+        width: None,
+        height: None,
+        focus_x: None,
+        focus_y: None,
+        duration_ms: None,
+        blurhash: None,
+    }
+}
+
+pub async fn media_owned_by(
+    state: &SharedState,
+    media_type: MediaType,
+    id: i64,
+    user_id: i64,
+) -> bool {
+    match media_type {
+        MediaType::Photo | MediaType::ProfilePicture | MediaType::Banner => {
+            Photo::owned_by(&state.primary(), id, user_id).await
         }
+        MediaType::Video | MediaType::Animation => {
+            Video::owned_by(&state.primary(), id, user_id).await
+        }
+        MediaType::Audio => Audio::owned_by(&state.primary(), id, user_id).await,
     }
+}
 
-    let Some(media_type) = media_type else {
-        return Err((StatusCode::BAD_REQUEST, "cannot find media type").into());
+fn quota_exceeded(
+    usage: &MediaUsage,
+    quota: &MediaQuota,
+    bytes: i64,
+) -> Option<(StatusCode, &'static str, i64, i64)> {
+    if usage.storage_bytes + bytes > quota.storage_quota {
+        Some((
+            StatusCode::INSUFFICIENT_STORAGE,
+            "storage",
+            usage.storage_bytes,
+            quota.storage_quota,
+        ))
+    } else if usage.daily_uploads >= quota.daily_upload_limit {
+        Some((
+            StatusCode::TOO_MANY_REQUESTS,
+            "daily_uploads",
+            usage.daily_uploads,
+            quota.daily_upload_limit,
+        ))
+    } else if usage.daily_bytes + bytes > quota.daily_bytes_limit {
+        Some((
+            StatusCode::TOO_MANY_REQUESTS,
+            "daily_bytes",
+            usage.daily_bytes,
+            quota.daily_bytes_limit,
+        ))
+    } else {
+        None
+    }
+}
+
+fn quota_error(
+    (status, quota, used, limit): (StatusCode, &'static str, i64, i64),
+) -> axum::response::ErrorResponse {
+    (
+        status,
+        Json(QuotaErrorResponse {
+            error: UPLOAD_QUOTA_EXCEEDED.to_string(),
+            quota: quota.to_string(),
+            used,
+            limit,
+        }),
+    )
+        .into_response()
+        .into()
+}
+
+async fn check_quota(state: &SharedState, user_id: i64, bytes: i64) -> axum::response::Result<()> {
+    let usage = MediaUsage::find(&state.rwdb, user_id)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "cannot find media usage"))?;
+    match quota_exceeded(&usage, &state.quota, bytes) {
+        Some(exceeded) => Err(quota_error(exceeded)),
+        None => Ok(()),
+    }
+}
+
+async fn reserve_quota(
+    state: &SharedState,
+    user_id: i64,
+    bytes: i64,
+) -> axum::response::Result<()> {
+    let quota = &state.quota;
+    let reserved = MediaUsage::reserve(
+        &state.rwdb,
+        user_id,
+        bytes,
+        quota.storage_quota,
+        quota.daily_upload_limit,
+        quota.daily_bytes_limit,
+    )
+    .await
+    .map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "cannot update media usage",
+        )
+    })?;
+    if reserved {
+        return Ok(());
+    }
+    let usage = MediaUsage::find(&state.rwdb, user_id)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "cannot find media usage"))?;
+    Err(quota_error(
+        quota_exceeded(&usage, &quota, bytes).unwrap_or((
+            StatusCode::INSUFFICIENT_STORAGE,
+            "storage",
+            usage.storage_bytes,
+            quota.storage_quota,
+        )),
+    ))
+}
+
+async fn release_quota(state: &SharedState, user_id: i64, bytes: i64) {
+    let result = match state.rwdb.acquire().await {
+        Ok(mut conn) => MediaUsage::add_storage(&mut conn, user_id, -bytes).await,
+        Err(e) => Err(e),
     };
+    if let Err(e) = result {
+        println!("cannot release media quota for user {user_id}: {e}");
+    }
+}
 
-    let Some(media_data) = media_data else {
-        return Err((StatusCode::BAD_REQUEST, "cannot find data").into());
+async fn reuse_media(
+    state: &SharedState,
+    user_id: i64,
+    media_type: &str,
+    content_hash: &str,
+) -> axum::response::Result<Option<String>> {
+    let media_type = match media_type {
+        "profile_picture" => MediaType::ProfilePicture,
+        "banner" => MediaType::Banner,
+        "video" => MediaType::Video,
+        "animation" => MediaType::Animation,
+        "audio" | "voice" => MediaType::Audio,
+        _ => MediaType::Photo,
+    };
+    let source_id = match media_type {
+        MediaType::Photo | MediaType::ProfilePicture | MediaType::Banner => {
+            Photo::find_by_hash(&state.rwdb, content_hash).await
+        }
+        MediaType::Video | MediaType::Animation => {
+            Video::find_by_hash(&state.rwdb, content_hash).await
+        }
+        MediaType::Audio => Audio::find_by_hash(&state.rwdb, content_hash).await,
+    };
+    match source_id {
+        Some(source_id) => insert_reference(state, user_id, media_type, source_id).await,
+        None => Ok(None),
+    }
+}
+
+async fn insert_reference(
+    state: &SharedState,
+    user_id: i64,
+    media_type: MediaType,
+    source_id: i64,
+) -> axum::response::Result<Option<String>> {
+    let id = match media_type {
+        MediaType::Photo | MediaType::ProfilePicture | MediaType::Banner => {
+            Photo::insert_reference(&state.rwdb, user_id, source_id).await
+        }
+        MediaType::Video | MediaType::Animation => {
+            Video::insert_reference(&state.rwdb, user_id, source_id).await
+        }
+        MediaType::Audio => Audio::insert_reference(&state.rwdb, user_id, source_id).await,
+    };
+    match id {
+        Ok(id) => Ok(Some(state.media_ids.encode(media_type, id))),
+        // the source was collected after find_by_hash, so the upload is processed from scratch
+        Err(sqlx::Error::RowNotFound) => Ok(None),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "cannot reuse media").into()),
+    }
+}
+
+async fn start_processing(
+    state: Arc<SharedState>,
+    user_id: i64,
+    media_type: &str,
+    media_data: TempPath,
+    crop: Option<media::CropRect>,
+    focus: Option<(f64, f64)>,
+) -> axum::response::Result<String> {
+    if !UPLOAD_MEDIA_TYPES.contains(&media_type) {
+        return Err((StatusCode::BAD_REQUEST, "cannot find media type").into());
+    }
+    let size = fs::metadata(&media_data)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "cannot read upload file"))?
+        .len() as i64;
+    let reserved = size;
+    reserve_quota(&state, user_id, reserved).await?;
+
+    let hash_key = format!(
+        "{media_type}:{}:{}",
+        crop.map(|c| format!("{},{},{},{}", c.x, c.y, c.width, c.height))
+            .unwrap_or_default(),
+        focus
+            .map(|f| format!("{},{}", f.0, f.1))
+            .unwrap_or_default()
+    );
+    let content_hash = match media::content_hash(&media_data, &hash_key).await {
+        Ok(hash) => hash,
+        Err(_) => {
+            release_quota(&state, user_id, reserved).await;
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "cannot read upload file").into());
+        }
     };
+    match reuse_media(&state, user_id, media_type, &content_hash).await {
+        Ok(None) => {}
+        Ok(Some(id)) => {
+            release_quota(&state, user_id, reserved).await;
+            return Ok(id);
+        }
+        Err(e) => {
+            release_quota(&state, user_id, reserved).await;
+            return Err(e);
+        }
+    }
 
+    let media_type = media_type.to_string();
+    let media_ids = state.media_ids.clone();
     let id = match media_type.as_str() {
         "photo" | "profile_picture" | "banner" => {
-            let id = Photo::insert(&state.rwdb, claims.user_id).await.unwrap();
+            let id = Photo::insert(&state.rwdb, user_id).await.unwrap();
             let media_type_id = match media_type.as_str() {
                 "profile_picture" => MediaType::ProfilePicture,
                 "banner" => MediaType::Banner,
                 _ => MediaType::Photo,
             };
+            let options = media::PhotoOptions {
+                crop,
+                focus,
+                aspect_ratio: match media_type_id {
+                    MediaType::ProfilePicture => Some((1, 1)),
+                    MediaType::Banner => Some((3, 1)),
+                    _ => None,
+                },
+            };
             tokio::spawn(async move {
                 let mut query = PhotoUpdateQuery::default();
-                let result = match media::process_photo(media_data).await {
+                let result = match state.media.process_photo(&media_data, options).await {
                     Ok(r) => r,
                     Err(e) => {
-                        println!("FFMPEG ERROR: {:?}", e.ffmpeg_error);
+                        println!(
+                            "MEDIA ERROR {}: {} {:?}",
+                            e.code.as_str(),
+                            e.error,
+                            e.ffmpeg_error
+                        );
                         query.processing = Some(false);
-                        query.processing_error = Some(e.error);
+                        query.processing_error = Some(e.code.as_str().to_string());
+                        release_quota(&state, user_id, reserved).await;
                         query.update(&state.rwdb, id).await.unwrap();
-                        sleep(Duration::from_secs(10)).await;
-                        Photo::delete(&state.rwdb, id).await.unwrap();
                         return;
                     }
                 };
+                let size = result.jpg_small.len()
+                    + result.jpg_medium.as_ref().map_or(0, Vec::len)
+                    + result.jpg_large.as_ref().map_or(0, Vec::len);
                 query.processing = Some(false);
+                query.width = Some(result.width);
+                query.height = Some(result.height);
+                query.focus_x = result.focus.map(|f| f.0);
+                query.focus_y = result.focus.map(|f| f.1);
+                query.blurhash = Some(result.blurhash);
                 query.jpg_small = Some(result.jpg_small);
                 query.jpg_medium = result.jpg_medium;
                 query.jpg_large = result.jpg_large;
                 query.profile_picture = Some(media_type == "profile_picture");
                 query.banner = Some(media_type == "banner");
+                query.content_hash = Some(content_hash);
                 query.update(&state.rwdb, id).await.unwrap();
+                let mut conn = state.rwdb.acquire().await.unwrap();
+                MediaUsage::add_storage(&mut conn, user_id, size as i64 - reserved)
+                    .await
+                    .unwrap();
             });
-            encode_media_id(media_type_id, id)
+            media_ids.encode(media_type_id, id)
         }
         "video" => {
-            let id = Video::insert(&state.rwdb, claims.user_id).await.unwrap();
+            let id = Video::insert(&state.rwdb, user_id).await.unwrap();
+            tokio::spawn(async move {
+                let mut query = VideoUpdateQuery::default();
+                let result = match state.media.process_video(&media_data).await {
+                    Ok(r) => r,
+                    Err(e) => {
+                        println!(
+                            "MEDIA ERROR {}: {} {:?}",
+                            e.code.as_str(),
+                            e.error,
+                            e.ffmpeg_error
+                        );
+                        query.processing = Some(false);
+                        query.processing_error = Some(e.code.as_str().to_string());
+                        release_quota(&state, user_id, reserved).await;
+                        query.update(&state.rwdb, id).await.unwrap();
+                        return;
+                    }
+                };
+                let size = result.thumbnail.len() + result.mp4_480p.len();
+                query.processing = Some(false);
+                query.width = result.width;
+                query.height = result.height;
+                query.duration_ms = result.duration_ms;
+                query.blurhash = Some(result.blurhash);
+                query.thumbnail = Some(result.thumbnail);
+                query.mp4_480p = Some(result.mp4_480p);
+                query.content_hash = Some(content_hash);
+                query.update(&state.rwdb, id).await.unwrap();
+                let mut conn = state.rwdb.acquire().await.unwrap();
+                MediaUsage::add_storage(&mut conn, user_id, size as i64 - reserved)
+                    .await
+                    .unwrap();
+            });
+            media_ids.encode(MediaType::Video, id)
+        }
+        "animation" => {
+            let id = Video::insert(&state.rwdb, user_id).await.unwrap();
             tokio::spawn(async move {
                 let mut query = VideoUpdateQuery::default();
-                let result = match media::process_video(media_data).await {
+                let result = match state.media.process_animation(&media_data).await {
                     Ok(r) => r,
                     Err(e) => {
-                        println!("FFMPEG ERROR: {:?}", e.ffmpeg_error);
+                        println!(
+                            "MEDIA ERROR {}: {} {:?}",
+                            e.code.as_str(),
+                            e.error,
+                            e.ffmpeg_error
+                        );
                         query.processing = Some(false);
-                        query.processing_error = Some(e.error);
+                        query.processing_error = Some(e.code.as_str().to_string());
+                        release_quota(&state, user_id, reserved).await;
                         query.update(&state.rwdb, id).await.unwrap();
-                        sleep(Duration::from_secs(10)).await;
-                        Video::delete(&state.rwdb, id).await.unwrap();
                         return;
                     }
                 };
+                let size = result.thumbnail.len() + result.mp4_480p.len();
                 query.processing = Some(false);
+                query.animation = Some(true);
+                query.width = result.width;
+                query.height = result.height;
+                query.duration_ms = result.duration_ms;
+                query.blurhash = Some(result.blurhash);
                 query.thumbnail = Some(result.thumbnail);
                 query.mp4_480p = Some(result.mp4_480p);
+                query.content_hash = Some(content_hash);
                 query.update(&state.rwdb, id).await.unwrap();
+                let mut conn = state.rwdb.acquire().await.unwrap();
+                MediaUsage::add_storage(&mut conn, user_id, size as i64 - reserved)
+                    .await
+                    .unwrap();
             });
-            encode_media_id(MediaType::Video, id)
+            media_ids.encode(MediaType::Animation, id)
         }
-        "audio" => {
-            let id = Audio::insert(&state.rwdb, claims.user_id).await.unwrap();
+        "audio" | "voice" => {
+            let id = Audio::insert(&state.rwdb, user_id).await.unwrap();
+            let voice = media_type == "voice";
             tokio::spawn(async move {
                 let mut query = AudioUpdateQuery::default();
-                let result = match media::process_audio(media_data).await {
+                let result = if voice {
+                    state.media.process_voice(&media_data).await
+                } else {
+                    state.media.process_audio(&media_data).await
+                };
+                let result = match result {
                     Ok(r) => r,
                     Err(e) => {
-                        println!("FFMPEG ERROR: {:?}", e.ffmpeg_error);
+                        println!(
+                            "MEDIA ERROR {}: {} {:?}",
+                            e.code.as_str(),
+                            e.error,
+                            e.ffmpeg_error
+                        );
                         query.processing = Some(false);
-                        query.processing_error = Some(e.error);
+                        query.processing_error = Some(e.code.as_str().to_string());
+                        release_quota(&state, user_id, reserved).await;
                         query.update(&state.rwdb, id).await.unwrap();
-                        sleep(Duration::from_secs(10)).await;
-                        Audio::delete(&state.rwdb, id).await.unwrap();
                         return;
                     }
                 };
+                let size = result.thumbnail.as_ref().map_or(0, Vec::len)
+                    + result.mp3_128k.len()
+                    + result.opus_96k.as_ref().map_or(0, Vec::len);
                 query.processing = Some(false);
                 query.title = result.title.and_then(|t| {
                     Some(if t.len() > 100 {
@@ -419,56 +963,375 @@ async fn media_upload(
                     })
                 });
                 query.thumbnail = result.thumbnail;
+                query.voice = Some(voice);
+                query.duration_ms = result.duration_ms;
+                query.waveform = serde_json::to_string(&result.waveform).ok();
                 query.mp3_128k = Some(result.mp3_128k);
+                query.opus_96k = result.opus_96k;
+                query.content_hash = Some(content_hash);
                 query.update(&state.rwdb, id).await.unwrap();
+                let mut conn = state.rwdb.acquire().await.unwrap();
+                MediaUsage::add_storage(&mut conn, user_id, size as i64 - reserved)
+                    .await
+                    .unwrap();
             });
-            encode_media_id(MediaType::Audio, id)
+            media_ids.encode(MediaType::Audio, id)
         }
         _ => return Err((StatusCode::BAD_REQUEST, "cannot find media type").into()),
     };
 
-    Ok(Json(MediaResponse {
-        id,
-        processing: true,
-        processing_error: None,
+    Ok(id)
+}
+
+async fn media_upload(
+    State(state): State<Arc<SharedState>>,
+    claims: Claims,
+    consistency: Consistency,
+    mut multipart: Multipart,
+) -> axum::response::Result<impl IntoResponse> {
+    let mut media_type = None;
+    let mut media_data = None;
+    let mut crop = None;
+    let mut focus = None;
+
+    while let Some(mut field) = multipart.next_field().await? {
+        let name = field.name().unwrap().to_string();
+        if name == "data" {
+            let (file, path) = NamedTempFile::new_in(upload_dir().await?)
+                .map_err(|_| {
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "cannot create upload file",
+                    )
+                })?
+                .into_parts();
+            let mut file = fs::File::from_std(file);
+            while let Some(chunk) = field
+                .chunk()
+                .await
+                .map_err(|_| (StatusCode::BAD_REQUEST, "cannot read body"))?
+            {
+                file.write_all(&chunk).await.map_err(|_| {
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "cannot write upload file",
+                    )
+                })?;
+            }
+            media_data = Some(path);
+            continue;
+        }
+
+        let data = field
+            .bytes()
+            .await
+            .map_err(|_| (StatusCode::BAD_REQUEST, "cannot read body"))?;
+
+        match name.as_str() {
+            "type" => media_type = Some(String::from_utf8(data.to_vec()).unwrap()),
+            "crop" => crop = Some(String::from_utf8(data.to_vec()).unwrap_or_default()),
+            "focus" => focus = Some(String::from_utf8(data.to_vec()).unwrap_or_default()),
+            _ => {}
+        }
+    }
+
+    let Some(media_type) = media_type else {
+        return Err((StatusCode::BAD_REQUEST, "cannot find media type").into());
+    };
+
+    let Some(media_data) = media_data else {
+        return Err((StatusCode::BAD_REQUEST, "cannot find data").into());
+    };
+
+    let crop = parse_crop(crop.as_deref())?;
+    let focus = parse_focus(focus.as_deref())?;
+
+    let id = start_processing(state, claims.user_id, &media_type, media_data, crop, focus).await?;
+    consistency.wrote();
+
+    Ok(Json(processing_response(id)))
+}
+
+async fn uploads_create(
+    State(state): State<Arc<SharedState>>,
+    claims: Claims,
+    Json(request): Json<UploadCreateRequest>,
+) -> axum::response::Result<impl IntoResponse> {
+    if !UPLOAD_MEDIA_TYPES.contains(&request.media_type.as_str()) {
+        return Err((StatusCode::BAD_REQUEST, "cannot find media type").into());
+    }
+    if request.length <= 0 || request.length > MAX_UPLOAD_SIZE as i64 {
+        return Err((StatusCode::PAYLOAD_TOO_LARGE, UPLOAD_TOO_LARGE).into());
+    }
+    parse_crop(request.crop.as_deref())?;
+    parse_focus(request.focus.as_deref())?;
+    check_quota(&state, claims.user_id, request.length).await?;
+
+    let id = Upload::insert(
+        &state.rwdb,
+        claims.user_id,
+        &request.media_type,
+        request.length,
+        request.crop.as_deref(),
+        request.focus.as_deref(),
+    )
+    .await
+    .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "cannot create upload"))?;
+
+    fs::File::create(upload_path(&upload_dir().await?, id))
+        .await
+        .map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "cannot create upload file",
+            )
+        })?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(UploadResponse {
+            id,
+            offset: 0,
+            length: request.length,
+        }),
+    ))
+}
+
+async fn uploads_head(
+    State(state): State<Arc<SharedState>>,
+    claims: Claims,
+    Path(id): Path<i64>,
+) -> axum::response::Result<impl IntoResponse> {
+    let upload = Upload::find(&state.rwdb, id, claims.user_id)
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, UPLOAD_NOT_FOUND))?;
+    Ok([
+        (UPLOAD_OFFSET_HEADER, upload.received.to_string()),
+        (UPLOAD_LENGTH_HEADER, upload.length.to_string()),
+        (header::CACHE_CONTROL.as_str(), "no-store".to_string()),
+    ])
+}
+
+async fn uploads_patch(
+    State(state): State<Arc<SharedState>>,
+    claims: Claims,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+    body: Body,
+) -> axum::response::Result<impl IntoResponse> {
+    let upload = Upload::find(&state.rwdb, id, claims.user_id)
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, UPLOAD_NOT_FOUND))?;
+    let Some(offset) = headers
+        .get(UPLOAD_OFFSET_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<i64>().ok())
+    else {
+        return Err((StatusCode::BAD_REQUEST, UPLOAD_OFFSET_MISMATCH).into());
+    };
+    if offset != upload.received {
+        return Err((StatusCode::CONFLICT, UPLOAD_OFFSET_MISMATCH).into());
+    }
+
+    let locked_until_ms = chrono::Utc::now().timestamp_millis() + UPLOAD_LEASE_MS;
+    if !Upload::lock(&state.rwdb, id, offset, locked_until_ms)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "cannot update upload"))?
+    {
+        return Err((StatusCode::CONFLICT, UPLOAD_IS_LOCKED).into());
+    }
+
+    let mut received = offset;
+    let result = write_upload_chunk(&upload, offset, &mut received, locked_until_ms, body).await;
+
+    if !Upload::advance(&state.rwdb, id, offset, received, locked_until_ms)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "cannot update upload"))?
+    {
+        return Err((StatusCode::CONFLICT, UPLOAD_IS_LOCKED).into());
+    }
+    result?;
+
+    Ok((
+        StatusCode::NO_CONTENT,
+        [(UPLOAD_OFFSET_HEADER, received.to_string())],
+    ))
+}
+
+async fn write_upload_chunk(
+    upload: &Upload,
+    offset: i64,
+    received: &mut i64,
+    locked_until_ms: i64,
+    body: Body,
+) -> Result<(), (StatusCode, &'static str)> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .open(upload_path(&upload_dir().await?, upload.id))
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, UPLOAD_NOT_FOUND))?;
+    file.seek(SeekFrom::Start(offset as u64))
+        .await
+        .map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "cannot write upload file",
+            )
+        })?;
+
+    let mut written = offset;
+    let mut result = Ok(());
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let Ok(chunk) = chunk else {
+            result = Err((StatusCode::BAD_REQUEST, "cannot read body"));
+            break;
+        };
+        let end = written + chunk.len() as i64;
+        if end > upload.length || end - offset > MAX_UPLOAD_CHUNK_SIZE as i64 {
+            result = Err((StatusCode::PAYLOAD_TOO_LARGE, UPLOAD_TOO_LARGE));
+            break;
+        }
+        if chrono::Utc::now().timestamp_millis() > locked_until_ms {
+            result = Err((StatusCode::REQUEST_TIMEOUT, "upload chunk took too long"));
+            break;
+        }
+        if file.write_all(&chunk).await.is_err() {
+            result = Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "cannot write upload file",
+            ));
+            break;
+        }
+        written = end;
+    }
+    if file.flush().await.is_err() {
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "cannot write upload file",
+        ));
+    }
+    *received = written;
+    result
+}
+
+async fn uploads_finalize(
+    State(state): State<Arc<SharedState>>,
+    claims: Claims,
+    consistency: Consistency,
+    Path(id): Path<i64>,
+) -> axum::response::Result<impl IntoResponse> {
+    let upload = Upload::find(&state.rwdb, id, claims.user_id)
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, UPLOAD_NOT_FOUND))?;
+    if upload.received != upload.length {
+        return Err((StatusCode::CONFLICT, UPLOAD_IS_INCOMPLETE).into());
+    }
+    let crop = parse_crop(upload.crop.as_deref())?;
+    let focus = parse_focus(upload.focus.as_deref())?;
+    if !Upload::claim_complete(&state.rwdb, upload.id, claims.user_id)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "cannot update upload"))?
+    {
+        return Err((StatusCode::CONFLICT, UPLOAD_IS_LOCKED).into());
+    }
+
+    let path = TempPath::from_path(upload_path(&upload_dir().await?, upload.id));
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, UPLOAD_NOT_FOUND))?
+        .set_len(upload.length as u64)
+        .await
+        .map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "cannot write upload file",
+            )
+        })?;
+
+    let id = start_processing(state, claims.user_id, &upload.media_type, path, crop, focus).await?;
+    consistency.wrote();
+
+    Ok(Json(processing_response(id)))
+}
+
+async fn media_usage(
+    State(state): State<Arc<SharedState>>,
+    claims: Claims,
+    consistency: Consistency,
+) -> axum::response::Result<impl IntoResponse> {
+    let usage = MediaUsage::find(&state.reader(&consistency), claims.user_id)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "cannot find media usage"))?;
+    let quota = &state.quota;
+    Ok(Json(MediaUsageResponse {
+        storage_bytes: usage.storage_bytes,
+        storage_quota: quota.storage_quota,
+        daily_uploads: usage.daily_uploads,
+        daily_upload_limit: quota.daily_upload_limit,
+        daily_bytes: usage.daily_bytes,
+        daily_bytes_limit: quota.daily_bytes_limit,
     }))
 }
 
 async fn media_check(
     State(state): State<Arc<SharedState>>,
+    consistency: Consistency,
     Path(id): Path<String>,
 ) -> axum::response::Result<impl IntoResponse> {
-    let (media_type, num_id) = parse_media_id(&id)?;
+    let (media_type, num_id) = state.media_ids.parse(&id)?;
     Ok(Json(match media_type {
         MediaType::Photo | MediaType::Banner | MediaType::ProfilePicture => {
-            let photo = Photo::find(&state.db, num_id).await.map_err(|e| {
-                println!("{e:?}");
-                (StatusCode::NOT_FOUND, MEDIA_NOT_FOUND)
-            })?;
+            let photo = Photo::find(&state.reader(&consistency), num_id)
+                .await
+                .map_err(|e| {
+                    println!("{e:?}");
+                    (StatusCode::NOT_FOUND, MEDIA_NOT_FOUND)
+                })?;
             MediaResponse {
                 id,
                 processing: photo.processing,
-                processing_error: photo.processing_error,
+                processing_error: ProcessingError::from_column(photo.processing_error),
+                width: photo.width,
+                height: photo.height,
+                focus_x: photo.focus_x,
+                focus_y: photo.focus_y,
+                duration_ms: None,
+                blurhash: photo.blurhash,
             }
         }
-        MediaType::Video => {
-            let video = Video::find(&state.db, num_id)
+        MediaType::Video | MediaType::Animation => {
+            let video = Video::find(&state.reader(&consistency), num_id)
                 .await
                 .map_err(|_| (StatusCode::NOT_FOUND, MEDIA_NOT_FOUND))?;
             MediaResponse {
                 id,
                 processing: video.processing,
-                processing_error: video.processing_error,
+                processing_error: ProcessingError::from_column(video.processing_error),
+                width: video.width,
+                height: video.height,
+                focus_x: None,
+                focus_y: None,
+                duration_ms: video.duration_ms,
+                blurhash: video.blurhash,
             }
         }
         MediaType::Audio => {
-            let audio = Audio::find(&state.db, num_id)
+            let audio = Audio::find(&state.reader(&consistency), num_id)
                 .await
                 .map_err(|_| (StatusCode::NOT_FOUND, MEDIA_NOT_FOUND))?;
             MediaResponse {
                 id,
                 processing: audio.processing,
-                processing_error: audio.processing_error,
+                processing_error: ProcessingError::from_column(audio.processing_error),
+                width: None,
+                height: None,
+                focus_x: None,
+                focus_y: None,
+                duration_ms: audio.duration_ms,
+                blurhash: None,
             }
         }
     }))
@@ -476,9 +1339,493 @@ async fn media_check(
 
 pub fn routes() -> Router<Arc<SharedState>> {
     Router::new()
-        .route("/upload", post(media_upload))
+        .route(
+            "/upload",
+            post(media_upload).layer(DefaultBodyLimit::max(MAX_UPLOAD_SIZE)),
+        )
+        .route("/uploads", post(uploads_create))
+        .route("/uploads/{id}", head(uploads_head).patch(uploads_patch))
+        .route("/uploads/{id}/finalize", post(uploads_finalize))
+        .route("/usage", get(media_usage))
         .route("/check/{id}", get(media_check))
         .route("/metadata/{id}", get(media_metadata))
         .route("/{id}", get(media_handler))
         .route("/{id}", head(media_handler_head))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode, header},
+    };
+    use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+    use http_body_util::BodyExt;
+
+    use crate::{
+        SharedState,
+        controllers::media::{
+            MediaIdKey, MediaResponse, MediaType, MediaUsageResponse, QuotaErrorResponse,
+            UploadCreateRequest, UploadResponse, insert_reference,
+        },
+        errors::UPLOAD_IS_LOCKED,
+        models::{MediaUsage, Upload, Video, video::VideoUpdateQuery},
+        services::media,
+        test::instrumentation::{
+            FAKE_BLURHASH, init, json, send, send_get, send_multipart, send_post,
+        },
+    };
+
+    fn request(
+        method: &str,
+        uri: &str,
+        token: &str,
+        offset: Option<i64>,
+        body: &[u8],
+    ) -> Request<Body> {
+        let mut builder = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {token}"));
+        if let Some(offset) = offset {
+            builder = builder.header("Upload-Offset", offset.to_string());
+        }
+        builder.body(Body::from(body.to_vec())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn media_ids() {
+        let (state, _) = init().await;
+        let id = state.media_ids.encode(MediaType::Video, 42);
+        let (media_type, num_id) = state.media_ids.parse(&id).unwrap();
+        assert!(media_type == MediaType::Video);
+        assert_eq!(num_id, 42);
+        assert!(MediaIdKey::new(b"other", None).parse(&id).is_err());
+
+        let mut data = BASE64_URL_SAFE_NO_PAD.decode(&id).unwrap();
+        data[2] = 43;
+        assert!(
+            state
+                .media_ids
+                .parse(&BASE64_URL_SAFE_NO_PAD.encode(&data))
+                .is_err()
+        );
+        assert!(
+            state
+                .media_ids
+                .parse(&BASE64_URL_SAFE_NO_PAD.encode(&data[..10]))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn legacy_media_ids() {
+        let v1 = |id: i64| {
+            let mut data = vec![1u8, 1];
+            data.extend_from_slice(&id.to_le_bytes());
+            BASE64_URL_SAFE_NO_PAD.encode(&data)
+        };
+        let key = MediaIdKey::new(b"test", Some(10));
+        let (media_type, num_id) = key.parse(&v1(7)).unwrap();
+        assert!(media_type == MediaType::Photo);
+        assert_eq!(num_id, 7);
+        assert!(key.parse(&v1(11)).is_err());
+        assert!(MediaIdKey::new(b"test", None).parse(&v1(7)).is_err());
+    }
+
+    #[tokio::test]
+    async fn chunked_upload() {
+        let (state, token) = init().await;
+        let data = include_bytes!("../../testdata/input.gif");
+        let response = send_post(
+            state.clone(),
+            "/api/media/uploads",
+            Some(&token),
+            &UploadCreateRequest {
+                media_type: "animation".to_string(),
+                length: data.len() as i64,
+                crop: None,
+                focus: None,
+            },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let upload: UploadResponse = json(response).await;
+        assert_eq!(upload.offset, 0);
+        let uri = format!("/api/media/uploads/{}", upload.id);
+
+        let (first, second) = data.split_at(data.len() / 2);
+        let response = send(
+            state.clone(),
+            request("PATCH", &uri, &token, Some(0), first),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = send(
+            state.clone(),
+            request("PATCH", &uri, &token, Some(0), second),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let response = send(
+            state.clone(),
+            request("POST", &format!("{uri}/finalize"), &token, None, &[]),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let response = send(state.clone(), request("HEAD", &uri, &token, None, &[])).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["Upload-Offset"],
+            first.len().to_string().as_str()
+        );
+
+        let response = send(
+            state.clone(),
+            request("PATCH", &uri, &token, Some(first.len() as i64), second),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = send(
+            state.clone(),
+            request("POST", &format!("{uri}/finalize"), &token, None, &[]),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let media: MediaResponse = json(response).await;
+        assert!(media.processing);
+
+        let response = send(state.clone(), request("HEAD", &uri, &token, None, &[])).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = send_get(state.clone(), "/api/media/usage", Some(&token)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let usage: MediaUsageResponse = json(response).await;
+        assert_eq!(usage.daily_uploads, 1);
+        assert_eq!(usage.daily_bytes, data.len() as i64);
+    }
+
+    #[tokio::test]
+    async fn upload_lease() {
+        let (state, token) = init().await;
+        let data = include_bytes!("../../testdata/input.gif");
+        let response = send_post(
+            state.clone(),
+            "/api/media/uploads",
+            Some(&token),
+            &UploadCreateRequest {
+                media_type: "animation".to_string(),
+                length: data.len() as i64,
+                crop: None,
+                focus: None,
+            },
+        )
+        .await;
+        let upload: UploadResponse = json(response).await;
+        let uri = format!("/api/media/uploads/{}", upload.id);
+        let (first, second) = data.split_at(data.len() / 2);
+
+        let until = chrono::Utc::now().timestamp_millis() + 60_000;
+        assert!(
+            Upload::lock(&state.rwdb, upload.id, 0, until)
+                .await
+                .unwrap()
+        );
+        let response = send(
+            state.clone(),
+            request("PATCH", &uri, &token, Some(0), first),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, UPLOAD_IS_LOCKED);
+        assert!(
+            Upload::advance(&state.rwdb, upload.id, 0, 0, until)
+                .await
+                .unwrap()
+        );
+
+        let chunks: Vec<Result<Vec<u8>, std::io::Error>> = vec![
+            Ok(first.to_vec()),
+            Err(std::io::Error::other("client disconnected")),
+        ];
+        let mut interrupted = request("PATCH", &uri, &token, Some(0), &[]);
+        *interrupted.body_mut() = Body::from_stream(futures::stream::iter(chunks));
+        let response = send(state.clone(), interrupted).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = send(state.clone(), request("HEAD", &uri, &token, None, &[])).await;
+        assert_eq!(
+            response.headers()["Upload-Offset"],
+            first.len().to_string().as_str()
+        );
+
+        assert!(
+            !Upload::claim_complete(&state.rwdb, upload.id, 1)
+                .await
+                .unwrap()
+        );
+        let response = send(
+            state.clone(),
+            request("PATCH", &uri, &token, Some(first.len() as i64), second),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let finalize = format!("{uri}/finalize");
+        let response = send(state.clone(), request("POST", &finalize, &token, None, &[])).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(state.clone(), request("POST", &finalize, &token, None, &[])).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn upload_quota() {
+        let (state, token) = init().await;
+        let mut conn = state.rwdb.acquire().await.unwrap();
+        MediaUsage::add_storage(&mut conn, 1, 1024 * 1024 * 1024)
+            .await
+            .unwrap();
+        drop(conn);
+        let response = send_post(
+            state.clone(),
+            "/api/media/uploads",
+            Some(&token),
+            &UploadCreateRequest {
+                media_type: "photo".to_string(),
+                length: 1024,
+                crop: None,
+                focus: None,
+            },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+        let error: QuotaErrorResponse = json(response).await;
+        assert_eq!(error.quota, "storage");
+        assert_eq!(error.limit, 1024 * 1024 * 1024);
+    }
+
+    #[tokio::test]
+    async fn concurrent_upload_quota() {
+        let (state, token) = init().await;
+        let mut conn = state.rwdb.acquire().await.unwrap();
+        MediaUsage::add_storage(&mut conn, 1, 1024 * 1024 * 1024 - 25)
+            .await
+            .unwrap();
+        drop(conn);
+        let data = (0..5).map(|i| format!("photo {i:04}")).collect::<Vec<_>>();
+        let fields = data
+            .iter()
+            .map(|data| [("type", &b"photo"[..]), ("data", data.as_bytes())])
+            .collect::<Vec<_>>();
+        let responses = futures::future::join_all(fields.iter().map(|fields| {
+            send_multipart(state.clone(), "/api/media/upload", Some(&token), fields)
+        }))
+        .await;
+        let accepted = responses
+            .iter()
+            .filter(|r| r.status() == StatusCode::OK)
+            .count();
+        let rejected = responses
+            .iter()
+            .filter(|r| r.status() == StatusCode::INSUFFICIENT_STORAGE)
+            .count();
+        assert_eq!((accepted, rejected), (2, 3));
+        let usage = MediaUsage::find(&state.rwdb, 1).await.unwrap();
+        assert_eq!(usage.storage_bytes, 1024 * 1024 * 1024 - 5);
+    }
+
+    #[tokio::test]
+    async fn deduplicated_upload() {
+        let (state, token) = init().await;
+        let path = std::path::Path::new("testdata/input.gif");
+        let source_id = Video::insert(&state.rwdb, 2).await.unwrap();
+        VideoUpdateQuery {
+            processing: Some(false),
+            animation: Some(true),
+            width: Some(16),
+            height: Some(16),
+            thumbnail: Some(vec![1, 2, 3]),
+            mp4_480p: Some(vec![4, 5, 6]),
+            content_hash: Some(media::content_hash(path, "animation::").await.unwrap()),
+            ..Default::default()
+        }
+        .update(&state.rwdb, source_id)
+        .await
+        .unwrap();
+
+        let data = std::fs::read(path).unwrap();
+        let response = send_post(
+            state.clone(),
+            "/api/media/uploads",
+            Some(&token),
+            &UploadCreateRequest {
+                media_type: "animation".to_string(),
+                length: data.len() as i64,
+                crop: None,
+                focus: None,
+            },
+        )
+        .await;
+        let upload: UploadResponse = json(response).await;
+        let uri = format!("/api/media/uploads/{}", upload.id);
+        let response = send(
+            state.clone(),
+            request("PATCH", &uri, &token, Some(0), &data),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = send(
+            state.clone(),
+            request("POST", &format!("{uri}/finalize"), &token, None, &[]),
+        )
+        .await;
+        let media: MediaResponse = json(response).await;
+        assert_ne!(
+            media.id,
+            state.media_ids.encode(MediaType::Animation, source_id)
+        );
+
+        let response = send_get(
+            state.clone(),
+            &format!("/api/media/check/{}", media.id),
+            Some(&token),
+        )
+        .await;
+        let check: MediaResponse = json(response).await;
+        assert!(!check.processing);
+        assert_eq!(check.width, Some(16));
+
+        let response = send_get(
+            state.clone(),
+            &format!("/api/media/{}.jpg", media.id),
+            Some(&token),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], &[1, 2, 3]);
+
+        let (_, id) = state.media_ids.parse(&media.id).unwrap();
+        assert!(Video::owned_by(&state.db(), id, 1).await);
+    }
+
+    #[tokio::test]
+    async fn reference_to_collected_source() {
+        let (state, _) = init().await;
+        let source_id = Video::insert(&state.rwdb, 2).await.unwrap();
+        let id = insert_reference(&state, 1, MediaType::Video, source_id)
+            .await
+            .unwrap();
+        assert!(id.is_some_and(|id| id != state.media_ids.encode(MediaType::Video, source_id)));
+
+        sqlx::query("DELETE FROM videos WHERE id = $1")
+            .bind(source_id)
+            .execute(&state.rwdb.0)
+            .await
+            .unwrap();
+        let id = insert_reference(&state, 1, MediaType::Video, source_id)
+            .await
+            .unwrap();
+        assert!(id.is_none());
+    }
+
+    async fn wait_processed(state: Arc<SharedState>, token: &str, id: &str) -> MediaResponse {
+        for _ in 0..100 {
+            let response = send_get(
+                state.clone(),
+                &format!("/api/media/check/{id}"),
+                Some(token),
+            )
+            .await;
+            let media: MediaResponse = json(response).await;
+            if !media.processing {
+                return media;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("media is still processing");
+    }
+
+    #[tokio::test]
+    async fn upload_photo() {
+        let (state, token) = init().await;
+        let response = send_multipart(
+            state.clone(),
+            "/api/media/upload",
+            Some(&token),
+            &[
+                ("type", b"photo"),
+                ("crop", b"0,0,320,240"),
+                ("data", b"photo data"),
+            ],
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let media: MediaResponse = json(response).await;
+        assert!(media.processing);
+
+        let media = wait_processed(state.clone(), &token, &media.id).await;
+        assert!(media.processing_error.is_none());
+        assert_eq!((media.width, media.height), (Some(320), Some(240)));
+        assert_eq!(media.blurhash.as_deref(), Some(FAKE_BLURHASH));
+
+        let response = send_get(
+            state.clone(),
+            &format!("/api/media/{}.jpg:small", media.id),
+            None,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/jpeg");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"photo data");
+    }
+
+    #[tokio::test]
+    async fn upload_audio() {
+        let (state, token) = init().await;
+        let response = send_multipart(
+            state.clone(),
+            "/api/media/upload",
+            Some(&token),
+            &[("type", b"voice"), ("data", b"voice data")],
+        )
+        .await;
+        let media: MediaResponse = json(response).await;
+        let media = wait_processed(state.clone(), &token, &media.id).await;
+        assert_eq!(media.duration_ms, Some(1000));
+
+        let response = send_get(state.clone(), &format!("/api/media/{}.mp3", media.id), None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"voice data");
+
+        let response = send_get(state.clone(), &format!("/api/media/{}.ogg", media.id), None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn upload_failure() {
+        let (state, token) = init().await;
+        let response = send_multipart(
+            state.clone(),
+            "/api/media/upload",
+            Some(&token),
+            &[("type", b"video"), ("data", b"invalid video")],
+        )
+        .await;
+        let media: MediaResponse = json(response).await;
+        let media = wait_processed(state.clone(), &token, &media.id).await;
+        let error = media.processing_error.unwrap();
+        assert_eq!(error.code, media::MediaErrorCode::UnsupportedContainer);
+        assert_eq!(error.message, "This file format is not supported");
+        let usage = MediaUsage::find(&state.rwdb, 1).await.unwrap();
+        assert_eq!(usage.storage_bytes, 0);
+    }
+}
@@ -1,4 +1,5 @@
 pub mod auth;
 pub mod media;
 pub mod posts;
+pub mod status;
 pub mod users;
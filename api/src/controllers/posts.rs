@@ -2,53 +2,121 @@ use crate::{
     Claims, SharedState,
     errors::{
         CANNOT_DELETE_POST, CANNOT_FIND_POST, CANNOT_INSERT_POST, CANNOT_USE_THIS_MEDIA_TYPE,
-        MEDIA_NOT_FOUND, POST_IS_ALREADY_LIKED, POST_IS_NOT_LIKED,
+        MEDIA_IS_NOT_OWNED, MEDIA_NOT_FOUND, POST_IS_ALREADY_LIKED, POST_IS_NOT_LIKED,
     },
-    models::{Post, post::PostFindQuery},
+    models::{Post, is_foreign_key_violation, is_unique_violation, post::PostFindQuery},
+    services::{consistency::Consistency, unfurl},
 };
 use axum::{
     Json, Router,
     extract::{Query, State},
-    http::StatusCode,
+    http::{StatusCode, header},
     response::IntoResponse,
     routing::{get, post},
 };
 use std::sync::Arc;
 
 use super::{
-    media::{MediaType, parse_media_id},
+    media::{MediaType, media_owned_by},
     users::UserResponse,
 };
 
 #[derive(serde::Serialize, serde::Deserialize)]
 struct PostRequest {
     message: Option<String>,
-    media: Vec<String>,
+    media: Vec<PostRequestMedia>,
     comment_post_id: Option<i64>,
 }
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+enum PostRequestMedia {
+    Id(String),
+    Described {
+        id: String,
+        alt: Option<String>,
+        captions: Option<String>,
+    },
+}
+
+impl PostRequestMedia {
+    fn id(&self) -> &str {
+        match self {
+            PostRequestMedia::Id(id) => id,
+            PostRequestMedia::Described { id, .. } => id,
+        }
+    }
+
+    fn alt(&self) -> Option<String> {
+        match self {
+            PostRequestMedia::Id(_) => None,
+            PostRequestMedia::Described { alt, .. } => alt
+                .as_ref()
+                .map(|a| {
+                    a.chars()
+                        .map(|c| if c.is_control() { ' ' } else { c })
+                        .collect::<String>()
+                })
+                .map(|a| a.trim().to_string())
+                .filter(|a| !a.is_empty()),
+        }
+    }
+
+    fn captions(&self) -> Option<&str> {
+        match self {
+            PostRequestMedia::Id(_) => None,
+            PostRequestMedia::Described { captions, .. } => captions.as_deref(),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct PostMedia {
     pub photo: Option<String>,
     pub video: Option<String>,
+    pub animation: Option<String>,
     pub audio: Option<PostMediaAudio>,
+    pub alt: Option<String>,
+    pub captions: bool,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub focus_x: Option<f64>,
+    pub focus_y: Option<f64>,
+    pub duration_ms: Option<i64>,
+    pub blurhash: Option<String>,
 }
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct PostMediaAudio {
     pub id: String,
     pub title: Option<String>,
     pub artist: Option<String>,
     pub thumbnail: bool,
+    pub opus: bool,
+    pub voice: bool,
+    pub duration_ms: Option<i64>,
+    pub waveform: Option<Vec<u8>>,
 }
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PostLinkPreview {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub site_name: Option<String>,
+    pub photo: Option<String>,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub blurhash: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct PostMention {
     user_id: i64,
     username: String,
 }
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct PostResponse {
     pub id: i64,
     pub message: Option<String>,
@@ -57,11 +125,12 @@ pub struct PostResponse {
     pub liked: bool,
     pub user: UserResponse,
     pub media: Vec<PostMedia>,
+    pub link_preview: Option<PostLinkPreview>,
     pub mentions: Vec<PostMention>,
     pub comment: bool,
 }
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct PostTruncatedResponse {
     pub id: i64,
 }
@@ -81,36 +150,62 @@ pub struct IdQuery {
     pub id: i64,
 }
 
+#[derive(serde::Deserialize)]
+pub struct CaptionsQuery {
+    pub id: i64,
+    pub media: String,
+}
+
 async fn posts_like(
     claims: Claims,
+    consistency: Consistency,
     State(state): State<Arc<SharedState>>,
     Query(query): Query<IdQuery>,
 ) -> axum::response::Result<impl IntoResponse> {
-    if Post::like_exists(&state.db, query.id, claims.user_id).await {
-        return Err((StatusCode::BAD_REQUEST, POST_IS_ALREADY_LIKED).into());
-    };
-    Post::like_insert(&state.rwdb, query.id, claims.user_id)
+    let mut conn = state
+        .rwdb
+        .acquire()
         .await
         .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, CANNOT_INSERT_POST))?;
+    Post::like_insert(&mut conn, query.id, claims.user_id)
+        .await
+        .map_err(|e| {
+            if is_unique_violation(&e) {
+                (StatusCode::BAD_REQUEST, POST_IS_ALREADY_LIKED)
+            } else if is_foreign_key_violation(&e) {
+                (StatusCode::NOT_FOUND, CANNOT_FIND_POST)
+            } else {
+                (StatusCode::INTERNAL_SERVER_ERROR, CANNOT_INSERT_POST)
+            }
+        })?;
+    consistency.wrote();
     Ok((StatusCode::OK, ""))
 }
 
 async fn posts_unlike(
     claims: Claims,
+    consistency: Consistency,
     State(state): State<Arc<SharedState>>,
     Query(query): Query<IdQuery>,
 ) -> axum::response::Result<impl IntoResponse> {
-    if !Post::like_exists(&state.db, query.id, claims.user_id).await {
-        return Err((StatusCode::BAD_REQUEST, POST_IS_NOT_LIKED).into());
-    };
-    Post::like_delete(&state.rwdb, query.id, claims.user_id)
+    let mut conn = state
+        .rwdb
+        .acquire()
         .await
         .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, CANNOT_DELETE_POST))?;
+    if !Post::like_delete(&mut conn, query.id, claims.user_id)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, CANNOT_DELETE_POST))?
+    {
+        return Err((StatusCode::BAD_REQUEST, POST_IS_NOT_LIKED).into());
+    }
+    consistency.wrote();
     Ok((StatusCode::OK, ""))
 }
 
 async fn posts_find(
     claims: Claims,
+    consistency: Consistency,
     State(state): State<Arc<SharedState>>,
     Query(query): Query<FindQuery>,
 ) -> axum::response::Result<impl IntoResponse> {
@@ -131,20 +226,32 @@ async fn posts_find(
     post_query.feed = query.feed.unwrap_or_default();
     post_query.self_user_id = claims.user_id;
 
-    let posts = Post::find(&state.db, post_query)
+    let posts = Post::find(&state.reader(&consistency), post_query)
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
     Ok(Json(
         posts
             .into_iter()
-            .map(Post::into)
+            .map(|post| post.into_response(&state.media_ids))
             .collect::<Vec<PostResponse>>(),
     ))
 }
 
+fn is_webvtt(captions: &str) -> bool {
+    captions
+        .strip_prefix('\u{feff}')
+        .unwrap_or(captions)
+        .strip_prefix("WEBVTT")
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with([' ', '\t', '\r', '\n']))
+        && !captions
+            .chars()
+            .any(|c| c.is_control() && !matches!(c, '\t' | '\r' | '\n'))
+}
+
 async fn posts_create(
     claims: Claims,
+    consistency: Consistency,
     State(state): State<Arc<SharedState>>,
     Json(request): Json<PostRequest>,
 ) -> axum::response::Result<impl IntoResponse> {
@@ -169,14 +276,40 @@ async fn posts_create(
         None
     };
 
+    for media in &request.media {
+        if media.alt().is_some_and(|a| a.chars().count() > 1500) {
+            return Err((StatusCode::BAD_REQUEST, "alt text too long").into());
+        }
+        if let Some(captions) = media.captions() {
+            if captions.len() > 64 * 1024 {
+                return Err((StatusCode::BAD_REQUEST, "captions too long").into());
+            }
+            if !is_webvtt(captions) {
+                return Err((StatusCode::BAD_REQUEST, "captions must be in WebVTT format").into());
+            }
+        }
+    }
+
+    for media in &request.media {
+        let (media_type, media_inner_id) = state.media_ids.parse(media.id())?;
+        if !media_owned_by(&state, media_type, media_inner_id, claims.user_id).await {
+            return Err((StatusCode::FORBIDDEN, MEDIA_IS_NOT_OWNED).into());
+        }
+    }
+
     if let Some(comment_post_id) = request.comment_post_id {
-        if !Post::exists(&state.db, comment_post_id).await {
+        if !Post::exists(&state.primary(), comment_post_id).await {
             return Err((StatusCode::INTERNAL_SERVER_ERROR, CANNOT_FIND_POST).into());
         }
     }
 
+    let mut tx = state
+        .rwdb
+        .begin()
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, CANNOT_INSERT_POST))?;
     let id = Post::insert(
-        &state.rwdb,
+        &mut tx,
         claims.user_id,
         filtered_message.as_deref(),
         request.comment_post_id.is_some(),
@@ -184,12 +317,31 @@ async fn posts_create(
     .await
     .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, CANNOT_INSERT_POST))?;
 
-    for media_id in request.media {
-        let (media_type, media_inner_id) = parse_media_id(&media_id)?;
+    for (position, media) in request.media.into_iter().enumerate() {
+        let position = position as i64;
+        let (media_type, media_inner_id) = state.media_ids.parse(media.id())?;
+        let alt = media.alt();
+        if media.captions().is_some() && media_type != MediaType::Video {
+            return Err((StatusCode::BAD_REQUEST, CANNOT_USE_THIS_MEDIA_TYPE).into());
+        }
         match media_type {
-            MediaType::Photo => Post::photo_insert(&state.rwdb, id, media_inner_id).await,
-            MediaType::Video => Post::video_insert(&state.rwdb, id, media_inner_id).await,
-            MediaType::Audio => Post::audio_insert(&state.rwdb, id, media_inner_id).await,
+            MediaType::Photo => {
+                Post::photo_insert(&mut tx, id, media_inner_id, alt.as_deref(), position).await
+            }
+            MediaType::Video | MediaType::Animation => {
+                Post::video_insert(
+                    &mut tx,
+                    id,
+                    media_inner_id,
+                    alt.as_deref(),
+                    media.captions(),
+                    position,
+                )
+                .await
+            }
+            MediaType::Audio => {
+                Post::audio_insert(&mut tx, id, media_inner_id, alt.as_deref(), position).await
+            }
             MediaType::ProfilePicture | MediaType::Banner => {
                 return Err((
                     StatusCode::INTERNAL_SERVER_ERROR,
@@ -202,29 +354,78 @@ async fn posts_create(
     }
 
     if let Some(comment_post_id) = request.comment_post_id {
-        Post::comment_insert(&state.rwdb, comment_post_id, claims.user_id, id)
+        Post::comment_insert(&mut tx, comment_post_id, claims.user_id, id)
             .await
             .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, CANNOT_INSERT_POST))?;
     }
 
+    tx.commit()
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, CANNOT_INSERT_POST))?;
+    consistency.wrote();
+
+    if let Some(url) = filtered_message.as_deref().and_then(unfurl::find_url) {
+        if let Err(e) = unfurl::enqueue(state.clone(), id, &url).await {
+            println!("LINK PREVIEW ERROR: {url} {e:?}");
+        }
+    }
+
     Ok(Json(PostTruncatedResponse { id }))
 }
 
+async fn posts_captions(
+    State(state): State<Arc<SharedState>>,
+    Query(query): Query<CaptionsQuery>,
+) -> axum::response::Result<impl IntoResponse> {
+    let (media_type, media_inner_id) = state.media_ids.parse(&query.media)?;
+    if media_type != MediaType::Video {
+        return Err((StatusCode::BAD_REQUEST, CANNOT_USE_THIS_MEDIA_TYPE).into());
+    }
+    let Some(captions) = Post::video_captions(&state.db(), query.id, media_inner_id)
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, MEDIA_NOT_FOUND))?
+    else {
+        return Err((StatusCode::NOT_FOUND, MEDIA_NOT_FOUND).into());
+    };
+    Ok((
+        [(header::CONTENT_TYPE, "text/vtt; charset=utf-8")],
+        captions,
+    ))
+}
+
 pub fn routes() -> Router<Arc<SharedState>> {
     Router::new()
         .route("/create", post(posts_create))
         .route("/like", get(posts_like))
         .route("/unlike", get(posts_unlike))
         .route("/find", get(posts_find))
+        .route("/captions", get(posts_captions))
 }
 
 #[cfg(test)]
 mod tests {
-    use axum::http::StatusCode;
+    use std::{sync::Arc, time::Duration};
+
+    use axum::{
+        Router,
+        http::{StatusCode, header},
+        routing::get,
+    };
+    use http_body_util::BodyExt;
 
     use crate::{
-        controllers::posts::PostRequest,
-        test::instrumentation::{init, send_post},
+        SharedState,
+        controllers::{
+            media::MediaType,
+            posts::{PostRequest, PostRequestMedia, PostResponse, PostTruncatedResponse},
+        },
+        errors::{MEDIA_NOT_FOUND, POST_IS_ALREADY_LIKED, POST_IS_NOT_LIKED},
+        models::{
+            Audio, LinkPreview, Photo, Post, Video, audio::AudioUpdateQuery,
+            link_preview::LinkPreviewData, photo::PhotoUpdateQuery,
+        },
+        services::{consistency::LAST_WRITE_COOKIE, unfurl::UnfurlConfig},
+        test::instrumentation::{init, json, send_get, send_post},
     };
 
     #[tokio::test]
@@ -243,4 +444,506 @@ mod tests {
         .await;
         assert!(response.status() == StatusCode::OK);
     }
+
+    #[tokio::test]
+    async fn like() {
+        let (state, token) = init().await;
+        let response = send_post(
+            state.clone(),
+            "/api/posts/create",
+            Some(&token),
+            &PostRequest {
+                message: Some("test".to_string()),
+                media: vec![],
+                comment_post_id: None,
+            },
+        )
+        .await;
+        let post = json::<PostTruncatedResponse>(response).await;
+
+        let like = format!("/api/posts/like?id={}", post.id);
+        let unlike = format!("/api/posts/unlike?id={}", post.id);
+        for (uri, status, error) in [
+            (&like, StatusCode::OK, ""),
+            (&like, StatusCode::BAD_REQUEST, POST_IS_ALREADY_LIKED),
+            (&unlike, StatusCode::OK, ""),
+            (&unlike, StatusCode::BAD_REQUEST, POST_IS_NOT_LIKED),
+        ] {
+            let response = send_get(state.clone(), uri, Some(&token)).await;
+            assert_eq!(response.status(), status);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(&body[..], error.as_bytes());
+        }
+
+        let response = send_get(state.clone(), "/api/posts/like?id=1000", Some(&token)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        send_get(state.clone(), &like, Some(&token)).await;
+        sqlx::query("UPDATE posts SET like_count = 5, comment_count = 2")
+            .execute(&state.rwdb.0)
+            .await
+            .unwrap();
+        assert_eq!(Post::recount(&state.rwdb).await.unwrap(), 1);
+        assert_eq!(Post::recount(&state.rwdb).await.unwrap(), 0);
+
+        let response = send_get(
+            state.clone(),
+            &format!("/api/posts/find?id={}", post.id),
+            Some(&token),
+        )
+        .await;
+        let posts = json::<Vec<PostResponse>>(response).await;
+        assert_eq!(posts[0].like_count, 1);
+        assert_eq!(posts[0].comment_count, 0);
+    }
+
+    #[tokio::test]
+    async fn post_with_media() {
+        let (state, token) = init().await;
+        let photo_id = Photo::insert(&state.rwdb, 1).await.unwrap();
+        PhotoUpdateQuery {
+            processing: Some(false),
+            width: Some(640),
+            height: Some(480),
+            blurhash: Some("LEHV6nWB2yk8pyo0adR*.7kCMdnj".to_string()),
+            ..Default::default()
+        }
+        .update(&state.rwdb, photo_id)
+        .await
+        .unwrap();
+
+        let response = send_post(
+            state.clone(),
+            "/api/posts/create",
+            Some(&token),
+            &PostRequest {
+                message: None,
+                media: vec![PostRequestMedia::Described {
+                    id: state.media_ids.encode(MediaType::Photo, photo_id),
+                    alt: Some("A \"quoted\" \\ cat\non a mat".to_string()),
+                    captions: None,
+                }],
+                comment_post_id: None,
+            },
+        )
+        .await;
+        assert!(response.status() == StatusCode::OK);
+        let post = json::<PostTruncatedResponse>(response).await;
+
+        let response = send_get(
+            state.clone(),
+            &format!("/api/posts/find?id={}", post.id),
+            Some(&token),
+        )
+        .await;
+        assert!(response.status() == StatusCode::OK);
+        let posts = json::<Vec<PostResponse>>(response).await;
+        let media = &posts[0].media[0];
+        assert!(media.photo == Some(state.media_ids.encode(MediaType::Photo, photo_id)));
+        assert!(media.alt.as_deref() == Some("A \"quoted\" \\ cat on a mat"));
+        assert!(media.width == Some(640) && media.height == Some(480));
+        assert!(media.blurhash.as_deref() == Some("LEHV6nWB2yk8pyo0adR*.7kCMdnj"));
+    }
+
+    #[tokio::test]
+    async fn post_rollback() {
+        let (state, token) = init().await;
+        let photo_id = Photo::insert(&state.rwdb, 1).await.unwrap();
+        let id = state.media_ids.encode(MediaType::Photo, photo_id);
+        let response = send_post(
+            state.clone(),
+            "/api/posts/create",
+            Some(&token),
+            &PostRequest {
+                message: Some("test".to_string()),
+                media: vec![PostRequestMedia::Id(id.clone()), PostRequestMedia::Id(id)],
+                comment_post_id: None,
+            },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], MEDIA_NOT_FOUND.as_bytes());
+
+        let response = send_get(state.clone(), "/api/posts/find", Some(&token)).await;
+        assert!(json::<Vec<PostResponse>>(response).await.is_empty());
+        for table in ["posts", "posts_photos"] {
+            let count: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {table}"))
+                .fetch_one(&state.rwdb.0)
+                .await
+                .unwrap();
+            assert_eq!(count, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn comment() {
+        let (state, token) = init().await;
+        let mut ids = vec![];
+        for comment_post_id in [None, Some(1)] {
+            let response = send_post(
+                state.clone(),
+                "/api/posts/create",
+                Some(&token),
+                &PostRequest {
+                    message: Some("test".to_string()),
+                    media: vec![],
+                    comment_post_id,
+                },
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            ids.push(json::<PostTruncatedResponse>(response).await.id);
+        }
+
+        let response = send_get(
+            state.clone(),
+            &format!("/api/posts/find?id={}&comments=true", ids[0]),
+            Some(&token),
+        )
+        .await;
+        let comments = json::<Vec<PostResponse>>(response).await;
+        assert_eq!(comments.len(), 1);
+        assert_eq!(comments[0].id, ids[1]);
+
+        let response = send_get(
+            state.clone(),
+            &format!("/api/posts/find?id={}", ids[0]),
+            Some(&token),
+        )
+        .await;
+        assert_eq!(
+            json::<Vec<PostResponse>>(response).await[0].comment_count,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn post_with_audio() {
+        let (state, token) = init().await;
+        let audio_id = Audio::insert(&state.rwdb, 1).await.unwrap();
+        AudioUpdateQuery {
+            processing: Some(false),
+            title: Some("back\\slash\n\"title\"".to_string()),
+            artist: Some("artist".to_string()),
+            voice: Some(true),
+            duration_ms: Some(12345),
+            waveform: Some("[0,128,255]".to_string()),
+            ..Default::default()
+        }
+        .update(&state.rwdb, audio_id)
+        .await
+        .unwrap();
+        let photo_id = Photo::insert(&state.rwdb, 1).await.unwrap();
+
+        let response = send_post(
+            state.clone(),
+            "/api/posts/create",
+            Some(&token),
+            &PostRequest {
+                message: None,
+                media: vec![
+                    PostRequestMedia::Id(state.media_ids.encode(MediaType::Audio, audio_id)),
+                    PostRequestMedia::Id(state.media_ids.encode(MediaType::Photo, photo_id)),
+                ],
+                comment_post_id: None,
+            },
+        )
+        .await;
+        assert!(response.status() == StatusCode::OK);
+        let post = json::<PostTruncatedResponse>(response).await;
+
+        let response = send_get(
+            state.clone(),
+            &format!("/api/posts/find?id={}", post.id),
+            Some(&token),
+        )
+        .await;
+        let posts = json::<Vec<PostResponse>>(response).await;
+        let audio = posts[0].media[0].audio.as_ref().unwrap();
+        assert!(audio.title.as_deref() == Some("back\\slash\n\"title\""));
+        assert!(audio.duration_ms == Some(12345));
+        assert!(audio.waveform.as_deref() == Some(&[0, 128, 255][..]));
+        assert!(audio.voice && !audio.thumbnail && !audio.opus);
+        assert!(
+            posts[0].media[1].photo == Some(state.media_ids.encode(MediaType::Photo, photo_id))
+        );
+    }
+
+    #[tokio::test]
+    async fn post_with_foreign_media() {
+        let (state, token) = init().await;
+        let photo_id = Photo::insert(&state.rwdb, 2).await.unwrap();
+        let response = send_post(
+            state.clone(),
+            "/api/posts/create",
+            Some(&token),
+            &PostRequest {
+                message: None,
+                media: vec![PostRequestMedia::Id(
+                    state.media_ids.encode(MediaType::Photo, photo_id),
+                )],
+                comment_post_id: None,
+            },
+        )
+        .await;
+        assert!(response.status() == StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn post_with_captions() {
+        let (state, token) = init().await;
+        let video_id = Video::insert(&state.rwdb, 1).await.unwrap();
+        let media_id = state.media_ids.encode(MediaType::Video, video_id);
+
+        for captions in [
+            "srt is not webvtt",
+            "WEBVTTX\n",
+            "WEBVTT\n\n00:00.000 --> 00:01.000\nHello\0\n",
+        ] {
+            let response = send_post(
+                state.clone(),
+                "/api/posts/create",
+                Some(&token),
+                &PostRequest {
+                    message: None,
+                    media: vec![PostRequestMedia::Described {
+                        id: media_id.clone(),
+                        alt: None,
+                        captions: Some(captions.to_string()),
+                    }],
+                    comment_post_id: None,
+                },
+            )
+            .await;
+            assert!(response.status() == StatusCode::BAD_REQUEST);
+        }
+
+        let captions = "WEBVTT\n\n00:00.000 --> 00:01.000\nHello\n";
+        let response = send_post(
+            state.clone(),
+            "/api/posts/create",
+            Some(&token),
+            &PostRequest {
+                message: None,
+                media: vec![PostRequestMedia::Described {
+                    id: media_id.clone(),
+                    alt: None,
+                    captions: Some(captions.to_string()),
+                }],
+                comment_post_id: None,
+            },
+        )
+        .await;
+        assert!(response.status() == StatusCode::OK);
+        let post = json::<PostTruncatedResponse>(response).await;
+
+        let response = send_get(
+            state.clone(),
+            &format!("/api/posts/captions?id={}&media={media_id}", post.id),
+            None,
+        )
+        .await;
+        assert!(response.status() == StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(body == captions.as_bytes());
+
+        for sql in [
+            "UPDATE posts SET deleted = 1",
+            "UPDATE posts SET deleted = 0; UPDATE users SET deleted = 1",
+        ] {
+            sqlx::raw_sql(sql).execute(&state.rwdb.0).await.unwrap();
+            let response = send_get(
+                state.clone(),
+                &format!("/api/posts/captions?id={}&media={media_id}", post.id),
+                None,
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn post_with_link_preview() {
+        let (state, token) = init().await;
+        let state = Arc::new(SharedState {
+            unfurl: Arc::new(UnfurlConfig {
+                allow_private: true,
+                ..UnfurlConfig::from_env()
+            }),
+            ..(*state).clone()
+        });
+        let app = Router::new()
+            .route(
+                "/",
+                get(|| async {
+                    (
+                        [(header::CONTENT_TYPE, "text/html")],
+                        r#"<meta property="og:title" content="A &quot;page&quot;"><meta property="og:image" content="/image.png">"#,
+                    )
+                }),
+            )
+            .route(
+                "/image.png",
+                get(|| async { ([(header::CONTENT_TYPE, "image/png")], "image") }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let mut previews = vec![];
+        for _ in 0..2 {
+            let response = send_post(
+                state.clone(),
+                "/api/posts/create",
+                Some(&token),
+                &PostRequest {
+                    message: Some(format!("look at {url}.")),
+                    media: vec![],
+                    comment_post_id: None,
+                },
+            )
+            .await;
+            assert!(response.status() == StatusCode::OK);
+            let post = json::<PostTruncatedResponse>(response).await;
+
+            let mut preview = None;
+            for _ in 0..100 {
+                let response = send_get(
+                    state.clone(),
+                    &format!("/api/posts/find?id={}", post.id),
+                    Some(&token),
+                )
+                .await;
+                let mut posts = json::<Vec<PostResponse>>(response).await;
+                preview = posts.remove(0).link_preview;
+                if preview.is_some() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            previews.push(preview.unwrap());
+        }
+        assert_eq!(previews[0].url, url);
+        assert_eq!(previews[0].title.as_deref(), Some("A \"page\""));
+        assert!(previews[0].photo.is_some());
+        assert_eq!(previews[0].photo, previews[1].photo);
+    }
+
+    #[tokio::test]
+    async fn link_preview_refresh() {
+        let (state, token) = init().await;
+        let response = send_post(
+            state.clone(),
+            "/api/posts/create",
+            Some(&token),
+            &PostRequest {
+                message: Some("hello".to_string()),
+                media: vec![],
+                comment_post_id: None,
+            },
+        )
+        .await;
+        let post = json::<PostTruncatedResponse>(response).await;
+        let preview = async || {
+            let response = send_get(
+                state.clone(),
+                &format!("/api/posts/find?id={}", post.id),
+                Some(&token),
+            )
+            .await;
+            json::<Vec<PostResponse>>(response)
+                .await
+                .remove(0)
+                .link_preview
+                .and_then(|p| p.title)
+        };
+        let url = "https://example.com/";
+        let data = |title: &str| LinkPreviewData {
+            title: Some(title.to_string()),
+            ..Default::default()
+        };
+
+        let id = LinkPreview::claim(&state.rwdb, url, 100, 0, 200)
+            .await
+            .unwrap()
+            .unwrap();
+        LinkPreview::attach(&state.rwdb, post.id, id).await.unwrap();
+        assert!(preview().await.is_none());
+        assert!(
+            LinkPreview::claim(&state.rwdb, url, 150, 0, 250)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(
+            LinkPreview::claim(&state.rwdb, url, 201, 0, 300)
+                .await
+                .unwrap(),
+            Some(id)
+        );
+        LinkPreview::store(&state.rwdb, id, &data("first"), 210)
+            .await
+            .unwrap();
+        assert_eq!(preview().await.as_deref(), Some("first"));
+
+        assert!(
+            LinkPreview::claim(&state.rwdb, url, 220, 200, 320)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(
+            LinkPreview::claim(&state.rwdb, url, 230, 215, 330)
+                .await
+                .unwrap(),
+            Some(id)
+        );
+        assert_eq!(preview().await.as_deref(), Some("first"));
+        LinkPreview::fail(&state.rwdb, id, "cannot connect", 240)
+            .await
+            .unwrap();
+        assert_eq!(preview().await.as_deref(), Some("first"));
+
+        assert_eq!(
+            LinkPreview::claim(&state.rwdb, url, 250, 245, 350)
+                .await
+                .unwrap(),
+            Some(id)
+        );
+        LinkPreview::store(&state.rwdb, id, &data("second"), 260)
+            .await
+            .unwrap();
+        assert_eq!(preview().await.as_deref(), Some("second"));
+    }
+
+    #[tokio::test]
+    async fn last_write_cookie() {
+        let (state, token) = init().await;
+        let response = send_post(
+            state.clone(),
+            "/api/posts/create",
+            Some(&token),
+            &PostRequest {
+                message: Some("test".to_string()),
+                media: vec![],
+                comment_post_id: None,
+            },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        let value = cookie
+            .split(';')
+            .next()
+            .unwrap()
+            .strip_prefix(&format!("{LAST_WRITE_COOKIE}="))
+            .unwrap();
+        assert!(state.sticky.verify(value).is_some());
+        json::<PostTruncatedResponse>(response).await;
+
+        let response = send_get(state.clone(), "/api/posts/find", Some(&token)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(header::SET_COOKIE));
+    }
 }
@@ -0,0 +1,134 @@
+use std::sync::Arc;
+
+use crate::{SharedState, errors::UNAUTHORIZED, services::replicas::ReplicaStatus};
+use axum::{
+    Json, Router,
+    extract::State,
+    http::{HeaderMap, StatusCode, header},
+    response::IntoResponse,
+    routing::get,
+};
+
+#[derive(Clone, Copy, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    Up,
+    Down,
+}
+
+impl From<&ReplicaStatus> for Health {
+    fn from(status: &ReplicaStatus) -> Self {
+        if status.healthy {
+            Health::Up
+        } else {
+            Health::Down
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct StatusResponse {
+    pub primary: Health,
+    pub replicas: Vec<Health>,
+    pub reads_from_primary: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct StatusDetailsResponse {
+    pub primary: ReplicaStatus,
+    pub replicas: Vec<ReplicaStatus>,
+    pub reads_from_primary: bool,
+}
+
+async fn status(State(state): State<Arc<SharedState>>) -> impl IntoResponse {
+    let replicas = state.replicas.status();
+    Json(StatusResponse {
+        primary: Health::from(&state.replicas.primary_status()),
+        reads_from_primary: !replicas.iter().any(|r| r.healthy),
+        replicas: replicas.iter().map(Health::from).collect(),
+    })
+}
+
+fn token_matches(provided: &str, expected: &str) -> bool {
+    provided.len() == expected.len()
+        && provided
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+async fn status_details(
+    State(state): State<Arc<SharedState>>,
+    headers: HeaderMap,
+) -> axum::response::Result<impl IntoResponse> {
+    let Some(expected) = state.replicas.config().status_token.as_deref() else {
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if !token_matches(provided, expected) {
+        return Err((StatusCode::UNAUTHORIZED, UNAUTHORIZED).into());
+    }
+    let replicas = state.replicas.status();
+    Ok(Json(StatusDetailsResponse {
+        primary: state.replicas.primary_status(),
+        reads_from_primary: !replicas.iter().any(|r| r.healthy),
+        replicas,
+    }))
+}
+
+pub fn routes() -> Router<Arc<SharedState>> {
+    Router::new()
+        .route("/", get(status))
+        .route("/details", get(status_details))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::http::StatusCode;
+
+    use crate::{
+        SharedState,
+        controllers::status::{Health, StatusDetailsResponse, StatusResponse},
+        services::replicas::{ReplicaConfig, Replicas},
+        test::instrumentation::{init, json, send_get},
+    };
+
+    #[tokio::test]
+    async fn status() {
+        let (state, token) = init().await;
+        let response = send_get(state.clone(), "/api/status", None).await;
+        let status = json::<StatusResponse>(response).await;
+        assert_eq!(status.primary, Health::Up);
+        assert!(status.replicas.is_empty());
+        assert!(status.reads_from_primary);
+
+        let response = send_get(state.clone(), "/api/status/details", Some(&token)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let state = Arc::new(SharedState {
+            replicas: Arc::new(Replicas::new(
+                state.rwdb.0.clone(),
+                vec![],
+                ReplicaConfig {
+                    status_token: Some("secret".to_string()),
+                    ..ReplicaConfig::from_env()
+                },
+            )),
+            ..(*state).clone()
+        });
+        state.replicas.check().await;
+        let response = send_get(state.clone(), "/api/status/details", Some(&token)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = send_get(state.clone(), "/api/status/details", Some("secret")).await;
+        let details = json::<StatusDetailsResponse>(response).await;
+        assert!(details.primary.healthy);
+        assert!(details.primary.latency_ms.is_some());
+    }
+}
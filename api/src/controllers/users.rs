@@ -5,9 +5,11 @@ use crate::{
     controllers::auth::RESTRICTED_USERNAMES,
     errors::{
         CANNOT_DELETE_USER, CANNOT_FIND_USER, CANNOT_FOLLOW_SELF, CANNOT_INSERT_USER,
-        CANNOT_UNFOLLOW_SELF, CANNOT_UPDATE_USER, USER_IS_ALREADY_FOLLOWED, USER_IS_NOT_FOLLOWED,
+        CANNOT_UNFOLLOW_SELF, CANNOT_UPDATE_USER, CANNOT_USE_THIS_MEDIA_TYPE, MEDIA_IS_NOT_OWNED,
+        USER_IS_ALREADY_FOLLOWED, USER_IS_NOT_FOLLOWED,
     },
-    models::{User, user::UserUpdateQuery},
+    models::{User, is_foreign_key_violation, is_unique_violation, user::UserUpdateQuery},
+    services::consistency::Consistency,
 };
 use axum::{
     Json, Router,
@@ -18,9 +20,13 @@ use axum::{
 };
 use regex::Regex;
 
-use super::{auth::USERNAME_REGEX, media::parse_media_id, posts::IdQuery};
+use super::{
+    auth::USERNAME_REGEX,
+    media::{MediaType, media_owned_by},
+    posts::IdQuery,
+};
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct UserResponse {
     pub id: i64,
     pub followers: i64,
@@ -43,65 +49,113 @@ pub struct UserSettingsRequest {
 
 async fn users_username(
     claims: Claims,
+    consistency: Consistency,
     State(state): State<Arc<SharedState>>,
     Path(username): Path<String>,
 ) -> axum::response::Result<impl IntoResponse> {
     Ok(Json::<UserResponse>(
-        User::find(&state.db, None, Some(&username), Some(claims.user_id))
-            .await
-            .map_err(|_| (StatusCode::NOT_FOUND, CANNOT_FIND_USER))?
-            .into(),
+        User::find(
+            &state.reader(&consistency),
+            None,
+            Some(&username),
+            Some(claims.user_id),
+        )
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, CANNOT_FIND_USER))?
+        .into_response(&state.media_ids),
     ))
 }
 
 async fn users_self(
     claims: Claims,
+    consistency: Consistency,
     State(state): State<Arc<SharedState>>,
 ) -> axum::response::Result<impl IntoResponse> {
     Ok(Json::<UserResponse>(
-        User::find(&state.db, Some(claims.user_id), None, Some(claims.user_id))
-            .await
-            .map_err(|_| (StatusCode::NOT_FOUND, CANNOT_FIND_USER))?
-            .into(),
+        User::find(
+            &state.reader(&consistency),
+            Some(claims.user_id),
+            None,
+            Some(claims.user_id),
+        )
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, CANNOT_FIND_USER))?
+        .into_response(&state.media_ids),
     ))
 }
 
 async fn users_follow(
     claims: Claims,
+    consistency: Consistency,
     State(state): State<Arc<SharedState>>,
     Query(query): Query<IdQuery>,
 ) -> axum::response::Result<impl IntoResponse> {
     if claims.user_id == query.id {
         return Err((StatusCode::BAD_REQUEST, CANNOT_FOLLOW_SELF).into());
     }
-    if User::follow_exists(&state.db, claims.user_id, query.id).await {
-        return Err((StatusCode::BAD_REQUEST, USER_IS_ALREADY_FOLLOWED).into());
-    };
-    User::follow_insert(&state.rwdb, claims.user_id, query.id)
+    let mut conn = state
+        .rwdb
+        .acquire()
         .await
         .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, CANNOT_INSERT_USER))?;
+    User::follow_insert(&mut conn, claims.user_id, query.id)
+        .await
+        .map_err(|e| {
+            if is_unique_violation(&e) {
+                (StatusCode::BAD_REQUEST, USER_IS_ALREADY_FOLLOWED)
+            } else if is_foreign_key_violation(&e) {
+                (StatusCode::NOT_FOUND, CANNOT_FIND_USER)
+            } else {
+                (StatusCode::INTERNAL_SERVER_ERROR, CANNOT_INSERT_USER)
+            }
+        })?;
+    consistency.wrote();
     Ok((StatusCode::OK, ""))
 }
 
 async fn users_unfollow(
     claims: Claims,
+    consistency: Consistency,
     State(state): State<Arc<SharedState>>,
     Query(query): Query<IdQuery>,
 ) -> axum::response::Result<impl IntoResponse> {
     if claims.user_id == query.id {
         return Err((StatusCode::BAD_REQUEST, CANNOT_UNFOLLOW_SELF).into());
     }
-    if !User::follow_exists(&state.db, claims.user_id, query.id).await {
-        return Err((StatusCode::BAD_REQUEST, USER_IS_NOT_FOLLOWED).into());
-    };
-    User::follow_delete(&state.rwdb, claims.user_id, query.id)
+    let mut conn = state
+        .rwdb
+        .acquire()
         .await
         .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, CANNOT_DELETE_USER))?;
+    if !User::follow_delete(&mut conn, claims.user_id, query.id)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, CANNOT_DELETE_USER))?
+    {
+        return Err((StatusCode::BAD_REQUEST, USER_IS_NOT_FOLLOWED).into());
+    }
+    consistency.wrote();
     Ok((StatusCode::OK, ""))
 }
 
+async fn owned_photo_id(
+    state: &SharedState,
+    id: &str,
+    expected_type: MediaType,
+    user_id: i64,
+) -> axum::response::Result<i64> {
+    let (media_type, id) = state.media_ids.parse(id)?;
+    if media_type != expected_type {
+        return Err((StatusCode::BAD_REQUEST, CANNOT_USE_THIS_MEDIA_TYPE).into());
+    }
+    if !media_owned_by(state, media_type, id, user_id).await {
+        return Err((StatusCode::FORBIDDEN, MEDIA_IS_NOT_OWNED).into());
+    }
+    Ok(id)
+}
+
 async fn users_settings(
     claims: Claims,
+    consistency: Consistency,
     State(state): State<Arc<SharedState>>,
     Json(request): Json<UserSettingsRequest>,
 ) -> axum::response::Result<impl IntoResponse> {
@@ -133,10 +187,10 @@ async fn users_settings(
         {
             return Err((StatusCode::BAD_REQUEST, "no").into());
         };
-        let self_user = User::find(&state.db, Some(claims.user_id), None, None)
+        let self_user = User::find(&state.primary(), Some(claims.user_id), None, None)
             .await
             .map_err(|_| (StatusCode::NOT_FOUND, CANNOT_FIND_USER))?;
-        if User::find(&state.db, None, Some(&username), None)
+        if User::find(&state.primary(), None, Some(&username), None)
             .await
             .ok()
             .map(|u| u.username != self_user.username)
@@ -154,16 +208,19 @@ async fn users_settings(
     query.realname = request.realname;
     query.username = request.username;
     query.bio = request.bio;
-    query.profile_picture_photo_id = request
-        .profile_picture_photo_id
-        .and_then(|id| parse_media_id(&id).ok().and_then(|id| Some(id.1)));
-    query.banner_photo_id = request
-        .banner_photo_id
-        .and_then(|id| parse_media_id(&id).ok().and_then(|id| Some(id.1)));
+    if let Some(id) = request.profile_picture_photo_id {
+        query.profile_picture_photo_id =
+            Some(owned_photo_id(&state, &id, MediaType::ProfilePicture, claims.user_id).await?);
+    }
+    if let Some(id) = request.banner_photo_id {
+        query.banner_photo_id =
+            Some(owned_photo_id(&state, &id, MediaType::Banner, claims.user_id).await?);
+    }
 
     User::update(&state.rwdb, claims.user_id, query)
         .await
         .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, CANNOT_UPDATE_USER))?;
+    consistency.wrote();
 
     Ok((StatusCode::OK, ""))
 }
@@ -176,3 +233,57 @@ pub fn routes() -> Router<Arc<SharedState>> {
         .route("/unfollow", get(users_unfollow))
         .route("/settings", post(users_settings))
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use http_body_util::BodyExt;
+
+    use crate::{
+        controllers::{auth::RegisterRequest, users::UserResponse},
+        errors::{USER_IS_ALREADY_FOLLOWED, USER_IS_NOT_FOLLOWED},
+        test::instrumentation::{init, json, send_get, send_post},
+    };
+
+    #[tokio::test]
+    async fn follow() {
+        let (state, token) = init().await;
+        let response = send_post(
+            state.clone(),
+            "/api/auth/register",
+            None,
+            &RegisterRequest {
+                realname: "test2".to_string(),
+                username: "test2".to_string(),
+                password: "test2test2test2".to_string(),
+            },
+        )
+        .await;
+        assert!(response.status() == StatusCode::OK);
+        let response = send_get(state.clone(), "/api/users/test2", Some(&token)).await;
+        let user = json::<UserResponse>(response).await;
+
+        let follow = format!("/api/users/follow?id={}", user.id);
+        let unfollow = format!("/api/users/unfollow?id={}", user.id);
+        for (uri, status, error) in [
+            (&follow, StatusCode::OK, ""),
+            (&follow, StatusCode::BAD_REQUEST, USER_IS_ALREADY_FOLLOWED),
+            (&unfollow, StatusCode::OK, ""),
+            (&unfollow, StatusCode::BAD_REQUEST, USER_IS_NOT_FOLLOWED),
+        ] {
+            let response = send_get(state.clone(), uri, Some(&token)).await;
+            assert_eq!(response.status(), status);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(&body[..], error.as_bytes());
+        }
+
+        let response = send_get(state.clone(), "/api/users/follow?id=1000", Some(&token)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        send_get(state.clone(), &follow, Some(&token)).await;
+        let response = send_get(state.clone(), "/api/users/test2", Some(&token)).await;
+        let user = json::<UserResponse>(response).await;
+        assert_eq!(user.followers, 1);
+        assert!(user.following);
+    }
+}
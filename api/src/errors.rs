@@ -22,3 +22,11 @@ pub const MEDIA_NOT_FOUND: &'static str = "media not found";
 pub const MEDIA_IS_PROCESSING: &'static str = "media is processing";
 pub const CANNOT_USE_THIS_MEDIA_TYPE: &'static str = "cannot use this media type";
 pub const INVALID_MEDIA_ID: &'static str = "invalid media id";
+pub const MEDIA_IS_NOT_OWNED: &'static str = "media is not owned by user";
+pub const UPLOAD_QUOTA_EXCEEDED: &'static str = "upload quota exceeded";
+
+pub const UPLOAD_NOT_FOUND: &'static str = "upload not found";
+pub const UPLOAD_OFFSET_MISMATCH: &'static str = "upload offset mismatch";
+pub const UPLOAD_IS_INCOMPLETE: &'static str = "upload is incomplete";
+pub const UPLOAD_TOO_LARGE: &'static str = "upload too large";
+pub const UPLOAD_IS_LOCKED: &'static str = "upload is being written by another request";
@@ -4,11 +4,22 @@ mod models;
 mod services;
 mod test;
 
-use crate::models::{DefaultPool, ReadOnlyPool, ReadWritePool};
+use crate::{
+    controllers::media::{MediaIdKey, MediaQuota},
+    models::{Post, ReadOnlyPool, ReadWritePool, User, migrations},
+    services::{
+        consistency::{self, Consistency, StickyPrimary},
+        gc,
+        media::{FfmpegProcessor, MediaProcessor, ProcessLimits},
+        replicas::{self, ReplicaConfig, Replicas},
+        unfurl::UnfurlConfig,
+    },
+};
 use axum::{
     RequestPartsExt, Router,
     extract::{DefaultBodyLimit, FromRequestParts},
     http::{StatusCode, request::Parts},
+    middleware,
 };
 use axum_extra::{
     TypedHeader,
@@ -32,8 +43,31 @@ macro_rules! cond {
 
 #[derive(Clone)]
 pub struct SharedState {
-    pub db: ReadOnlyPool,
+    pub replicas: Arc<Replicas>,
     pub rwdb: ReadWritePool,
+    pub media: Arc<dyn MediaProcessor>,
+    pub sticky: Arc<StickyPrimary>,
+    pub media_ids: Arc<MediaIdKey>,
+    pub quota: Arc<MediaQuota>,
+    pub unfurl: Arc<UnfurlConfig>,
+}
+
+impl SharedState {
+    pub fn db(&self) -> ReadOnlyPool {
+        self.replicas.pick()
+    }
+
+    pub fn primary(&self) -> ReadOnlyPool {
+        ReadOnlyPool(self.rwdb.0.clone())
+    }
+
+    pub fn reader(&self, consistency: &Consistency) -> ReadOnlyPool {
+        if self.sticky.is_sticky(consistency) {
+            self.primary()
+        } else {
+            self.db()
+        }
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -89,13 +123,84 @@ fn app(state: Arc<SharedState>) -> Router {
         .nest("/api/auth", controllers::auth::routes())
         .nest("/api/media", controllers::media::routes())
         .nest("/api/users", controllers::users::routes())
-        .layer(DefaultBodyLimit::max(256 * 1024 * 1024))
+        .nest("/api/status", controllers::status::routes())
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            consistency::track,
+        ))
+        .layer(DefaultBodyLimit::max(1024 * 1024))
         .layer(CorsLayer::permissive())
         .with_state(state.clone())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    let rwdb = models::connect_lazy(
+        &std::env::var("READ_WRITE_DATABASE_URL").expect("READ_WRITE_DATABASE_URL not set"),
+    )
+    .unwrap();
+    let replica_pools = std::env::var("READ_ONLY_DATABASE_URL")
+        .expect("READ_ONLY_DATABASE_URL not set")
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(|url| {
+            (
+                replicas::replica_name(url),
+                models::connect_lazy(url).unwrap(),
+            )
+        })
+        .collect();
+    let state = Arc::new(SharedState {
+        replicas: Arc::new(Replicas::new(
+            rwdb.clone(),
+            replica_pools,
+            ReplicaConfig::from_env(),
+        )),
+        rwdb: ReadWritePool(rwdb),
+        media: Arc::new(FfmpegProcessor {
+            limits: ProcessLimits::from_env(),
+        }),
+        sticky: Arc::new(StickyPrimary::from_env()),
+        media_ids: Arc::new(MediaIdKey::from_env()),
+        quota: Arc::new(MediaQuota::from_env()),
+        unfurl: Arc::new(UnfurlConfig::from_env()),
+    });
+
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.get(1).map(String::as_str) == Some("migrate") {
+        if args.get(2).map(String::as_str) == Some("baseline") {
+            let version = args
+                .get(3)
+                .and_then(|v| v.parse().ok())
+                .expect("usage: migrate baseline <version>");
+            migrations::baseline(&state.rwdb, version).await?;
+            println!("marked migrations up to {version} as applied");
+            return Ok(());
+        }
+        for name in migrations::migrate(&state.rwdb).await? {
+            println!("applied {name}");
+        }
+        return Ok(());
+    }
+
+    for name in migrations::migrate(&state.rwdb).await? {
+        println!("applied {name}");
+    }
+
+    if args.get(1).map(String::as_str) == Some("gc") {
+        let report = gc::collect(&state.rwdb, &gc::GcConfig::from_env()).await?;
+        println!("{report}");
+        return Ok(());
+    }
+
+    if args.get(1).map(String::as_str) == Some("recount") {
+        let posts = Post::recount(&state.rwdb).await?;
+        let users = User::recount(&state.rwdb).await?;
+        println!("fixed counters of {posts} posts and {users} users");
+        return Ok(());
+    }
+
     let listener = TcpListener::bind(format!(
         "0.0.0.0:{}",
         std::env::var("PORT")
@@ -105,26 +210,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
     ))
     .await?;
 
-    axum::serve(
-        listener,
-        app(Arc::new(SharedState {
-            db: ReadOnlyPool(
-                DefaultPool::connect_lazy(
-                    &std::env::var("READ_ONLY_DATABASE_URL")
-                        .expect("READ_ONLY_DATABASE_URL not set"),
-                )
-                .unwrap(),
-            ),
-            rwdb: ReadWritePool(
-                DefaultPool::connect_lazy(
-                    &std::env::var("READ_WRITE_DATABASE_URL")
-                        .expect("READ_WRITE_DATABASE_URL not set"),
-                )
-                .unwrap(),
-            ),
-        })),
-    )
-    .await?;
+    state.replicas.check().await;
+    tokio::spawn(gc::run(state.clone()));
+    tokio::spawn(replicas::run(state.clone()));
+
+    axum::serve(listener, app(state)).await?;
 
     Ok(())
 }
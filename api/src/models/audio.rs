@@ -1,16 +1,20 @@
-use sqlx::{FromRow, QueryBuilder, Row};
+use sqlx::{FromRow, Row};
 
 use crate::{bind, impl_media_common_ops};
 
-use super::{DefaultRow, ReadOnlyPool, ReadWritePool};
+use super::{DefaultRow, ReadOnlyPool, ReadWritePool, UpdateBuilder};
 
 pub struct Audio {
     pub processing: bool,
     pub processing_error: Option<String>,
+    pub voice: bool,
     pub title: Option<String>,
     pub artist: Option<String>,
+    pub duration_ms: Option<i64>,
+    pub waveform: Option<Vec<u8>>,
     pub thumbnail: Option<Vec<u8>>,
     pub mp3_128k: Option<Vec<u8>>,
+    pub opus_96k: Option<Vec<u8>>,
 }
 
 impl FromRow<'_, DefaultRow> for Audio {
@@ -18,10 +22,16 @@ impl FromRow<'_, DefaultRow> for Audio {
         Ok(Self {
             processing: row.try_get::<i16, _>("processing")? == 1,
             processing_error: row.try_get("processing_error")?,
+            voice: row.try_get::<i16, _>("voice")? == 1,
             title: row.try_get("title")?,
             artist: row.try_get("artist")?,
+            duration_ms: row.try_get("duration_ms")?,
+            waveform: row
+                .try_get::<Option<String>, _>("waveform")?
+                .and_then(|w| serde_json::from_str(&w).ok()),
             thumbnail: row.try_get("thumbnail")?,
             mp3_128k: row.try_get("mp3_128k")?,
+            opus_96k: row.try_get("opus_96k")?,
         })
     }
 }
@@ -30,31 +40,38 @@ impl FromRow<'_, DefaultRow> for Audio {
 pub struct AudioUpdateQuery {
     pub processing: Option<bool>,
     pub processing_error: Option<String>,
+    pub voice: Option<bool>,
     pub title: Option<String>,
     pub artist: Option<String>,
+    pub duration_ms: Option<i64>,
+    pub waveform: Option<String>,
     pub thumbnail: Option<Vec<u8>>,
     pub mp3_128k: Option<Vec<u8>>,
+    pub opus_96k: Option<Vec<u8>>,
+    pub content_hash: Option<String>,
 }
 
 impl AudioUpdateQuery {
     pub async fn update(&self, db: &ReadWritePool, id: i64) -> Result<(), sqlx::Error> {
-        let mut builder = QueryBuilder::new("UPDATE audios SET ");
-        let mut match_builder = builder.separated(", ");
-        bind!(match_builder, self.processing as boolint);
-        bind!(match_builder, self.processing_error);
-        bind!(match_builder, self.title);
-        bind!(match_builder, self.artist);
-        bind!(match_builder, self.thumbnail);
-        bind!(match_builder, self.mp3_128k);
-        builder.push(" WHERE id = ");
-        builder.push_bind(id);
-        builder.build().execute(&db.0).await?;
-        Ok(())
+        let mut builder = UpdateBuilder::new("audios");
+        bind!(builder, self.processing as boolint);
+        bind!(builder, self.processing_error);
+        bind!(builder, self.voice as boolint);
+        bind!(builder, self.title);
+        bind!(builder, self.artist);
+        bind!(builder, self.duration_ms);
+        bind!(builder, self.waveform);
+        bind!(builder, self.thumbnail);
+        bind!(builder, self.mp3_128k);
+        bind!(builder, self.opus_96k);
+        bind!(builder, self.content_hash);
+        builder.execute(db, id).await
     }
 }
 
 impl_media_common_ops!(
     Audio,
     "audios",
-    "INSERT INTO audios (user_id, processing, processing_error, title, artist, thumbnail, mp3_128k) VALUES ($1, 1, NULL, NULL, NULL, NULL, NULL) RETURNING id"
+    "INSERT INTO audios (user_id, processing, processing_error, title, artist, thumbnail, mp3_128k, created_at) VALUES ($1, 1, NULL, NULL, NULL, NULL, NULL, $2) RETURNING id",
+    "INSERT INTO audios (user_id, processing, processing_error, voice, title, artist, duration_ms, waveform, thumbnail, mp3_128k, opus_96k, created_at, content_hash, source_id) SELECT $1, 0, NULL, voice, title, artist, duration_ms, waveform, NULL, NULL, NULL, $2, content_hash, id FROM audios WHERE id = $3 RETURNING id"
 );
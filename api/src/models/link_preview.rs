@@ -0,0 +1,98 @@
+use super::{DefaultPool, ReadWritePool};
+
+pub struct LinkPreview;
+
+#[derive(Default)]
+pub struct LinkPreviewData {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub site_name: Option<String>,
+    pub photo_id: Option<i64>,
+}
+
+impl LinkPreview {
+    pub async fn find_id(db: &DefaultPool, url: &str) -> Result<Option<i64>, sqlx::Error> {
+        sqlx::query_scalar("SELECT id FROM link_previews WHERE url = $1")
+            .bind(url)
+            .fetch_optional(db)
+            .await
+    }
+
+    pub async fn claim(
+        db: &ReadWritePool,
+        url: &str,
+        now: i64,
+        stale_before: i64,
+        locked_until: i64,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        sqlx::query_scalar(
+            "INSERT INTO link_previews (url, processing, processing_error, fetched_at, locked_until) VALUES ($1, 1, NULL, $2, $4)
+            ON CONFLICT (url) DO UPDATE SET locked_until = $4
+            WHERE (link_previews.fetched_at < $3 OR link_previews.processing = 1)
+                AND (link_previews.locked_until IS NULL OR link_previews.locked_until < $2)
+            RETURNING id",
+        )
+        .bind(url)
+        .bind(now)
+        .bind(stale_before)
+        .bind(locked_until)
+        .fetch_optional(&db.0)
+        .await
+    }
+
+    pub async fn store(
+        db: &ReadWritePool,
+        id: i64,
+        data: &LinkPreviewData,
+        now: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE link_previews SET processing = 0, processing_error = NULL,
+                title = $2, description = $3, site_name = $4, photo_id = $5,
+                fetched_at = $6, locked_until = NULL
+            WHERE id = $1",
+        )
+        .bind(id)
+        .bind(&data.title)
+        .bind(&data.description)
+        .bind(&data.site_name)
+        .bind(data.photo_id)
+        .bind(now)
+        .execute(&db.0)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn fail(
+        db: &ReadWritePool,
+        id: i64,
+        error: &str,
+        now: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE link_previews SET
+                processing_error = CASE WHEN processing = 1 THEN $2 ELSE processing_error END,
+                processing = 0, fetched_at = $3, locked_until = NULL
+            WHERE id = $1",
+        )
+        .bind(id)
+        .bind(error)
+        .bind(now)
+        .execute(&db.0)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn attach(
+        db: &ReadWritePool,
+        post_id: i64,
+        link_preview_id: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT INTO posts_link_previews (post_id, link_preview_id) VALUES ($1, $2)")
+            .bind(post_id)
+            .bind(link_preview_id)
+            .execute(&db.0)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use sqlx::{FromRow, Row};
+
+use super::{DefaultConnection, DefaultPool, DefaultRow, ReadWritePool};
+
+#[derive(Default)]
+pub struct MediaUsage {
+    pub storage_bytes: i64,
+    pub day: i64,
+    pub daily_uploads: i64,
+    pub daily_bytes: i64,
+}
+
+impl FromRow<'_, DefaultRow> for MediaUsage {
+    fn from_row(row: &DefaultRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            storage_bytes: row.try_get("storage_bytes")?,
+            day: row.try_get("day")?,
+            daily_uploads: row.try_get("daily_uploads")?,
+            daily_bytes: row.try_get("daily_bytes")?,
+        })
+    }
+}
+
+impl MediaUsage {
+    pub fn today() -> i64 {
+        chrono::Utc::now().timestamp() / 86400
+    }
+
+    pub async fn find(db: &DefaultPool, user_id: i64) -> Result<MediaUsage, sqlx::Error> {
+        let usage: Option<MediaUsage> =
+            sqlx::query_as("SELECT * FROM media_usage WHERE user_id = $1")
+                .bind(user_id)
+                .fetch_optional(db)
+                .await?;
+        let today = Self::today();
+        Ok(match usage {
+            Some(usage) if usage.day == today => usage,
+            Some(usage) => MediaUsage {
+                storage_bytes: usage.storage_bytes,
+                day: today,
+                ..Default::default()
+            },
+            None => MediaUsage {
+                day: today,
+                ..Default::default()
+            },
+        })
+    }
+
+    pub async fn reserve(
+        db: &ReadWritePool,
+        user_id: i64,
+        bytes: i64,
+        storage_quota: i64,
+        daily_upload_limit: i64,
+        daily_bytes_limit: i64,
+    ) -> Result<bool, sqlx::Error> {
+        let today = Self::today();
+        sqlx::query(
+            "INSERT INTO media_usage (user_id, storage_bytes, day, daily_uploads, daily_bytes) VALUES ($1, 0, $2, 0, 0)
+            ON CONFLICT (user_id) DO NOTHING",
+        )
+        .bind(user_id)
+        .bind(today)
+        .execute(&db.0)
+        .await?;
+        sqlx::query(
+            "UPDATE media_usage SET day = $2, daily_uploads = 0, daily_bytes = 0 WHERE user_id = $1 AND day <> $2",
+        )
+        .bind(user_id)
+        .bind(today)
+        .execute(&db.0)
+        .await?;
+        let reserved = sqlx::query(
+            "UPDATE media_usage SET
+                storage_bytes = storage_bytes + $2,
+                daily_uploads = daily_uploads + 1,
+                daily_bytes = daily_bytes + $2
+            WHERE user_id = $1 AND day = $3
+                AND storage_bytes + $2 <= $4
+                AND daily_uploads < $5
+                AND daily_bytes + $2 <= $6",
+        )
+        .bind(user_id)
+        .bind(bytes)
+        .bind(today)
+        .bind(storage_quota)
+        .bind(daily_upload_limit)
+        .bind(daily_bytes_limit)
+        .execute(&db.0)
+        .await?
+        .rows_affected();
+        Ok(reserved == 1)
+    }
+
+    pub async fn add_storage(
+        conn: &mut DefaultConnection,
+        user_id: i64,
+        bytes: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO media_usage (user_id, storage_bytes, day, daily_uploads, daily_bytes) VALUES ($1, $2, $3, 0, 0)
+            ON CONFLICT (user_id) DO UPDATE SET storage_bytes = media_usage.storage_bytes + $2",
+        )
+        .bind(user_id)
+        .bind(bytes)
+        .bind(Self::today())
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+use super::{DefaultConnection, ReadWritePool};
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sqlite: &'static str,
+    pub postgres: &'static str,
+}
+
+macro_rules! migration {
+    ($version:literal, $name:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            sqlite: include_str!(concat!("../../data/", $name, ".sql")),
+            postgres: include_str!(concat!("../../data/", $name, "-postgres.sql")),
+        }
+    };
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    migration!(0, "0000-base-schema"),
+    migration!(1, "0001-media-update"),
+    migration!(2, "0002-animations"),
+    migration!(3, "0003-media-metadata"),
+    migration!(4, "0004-media-descriptions"),
+    migration!(5, "0005-photo-focus"),
+    migration!(6, "0006-uploads"),
+    migration!(7, "0007-media-usage"),
+    migration!(8, "0008-media-created-at"),
+    migration!(9, "0009-media-dedup"),
+    migration!(10, "0010-audio-waveform"),
+    migration!(11, "0011-voice-notes"),
+    migration!(12, "0012-link-previews"),
+    migration!(13, "0013-constraints"),
+    migration!(14, "0014-counters"),
+    migration!(15, "0015-media-position"),
+    migration!(16, "0016-upload-lease"),
+    migration!(17, "0017-link-preview-lease"),
+    migration!(18, "0018-upload-activity"),
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum MigrationError {
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("database schema version {0} is newer than the latest known version {1}")]
+    SchemaTooNew(i64, i64),
+    #[error("database has tables but no schema_migrations, run `migrate baseline <version>` first")]
+    Unversioned,
+}
+
+const RECORD_MIGRATION: &str =
+    "INSERT INTO schema_migrations (version, name, applied_at) VALUES ($1, $2, $3)";
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(-1, |m| m.version)
+}
+
+const MIGRATION_LOCK: i64 = 0x7275_7477_745f_6d67;
+
+async fn lock(conn: &mut DefaultConnection) -> Result<(), sqlx::Error> {
+    if conn.backend_name() == "PostgreSQL" {
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(MIGRATION_LOCK)
+            .execute(conn)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn create_table(db: &ReadWritePool) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+    lock(&mut tx).await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version BIGINT PRIMARY KEY NOT NULL,
+            name TEXT NOT NULL,
+            applied_at BIGINT NOT NULL
+        )",
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
+
+pub async fn current_version(db: &ReadWritePool) -> Result<Option<i64>, sqlx::Error> {
+    create_table(db).await?;
+    sqlx::query_scalar("SELECT MAX(version) FROM schema_migrations")
+        .fetch_one(&db.0)
+        .await
+}
+
+pub async fn migrate(db: &ReadWritePool) -> Result<Vec<&'static str>, MigrationError> {
+    let current = current_version(db).await?;
+    if let Some(current) = current.filter(|&v| v > latest_version()) {
+        return Err(MigrationError::SchemaTooNew(current, latest_version()));
+    }
+    if current.is_none()
+        && sqlx::query("SELECT 1 FROM posts")
+            .fetch_optional(&db.0)
+            .await
+            .is_ok()
+    {
+        return Err(MigrationError::Unversioned);
+    }
+
+    let postgres = db.acquire().await?.backend_name() == "PostgreSQL";
+    let mut applied = vec![];
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|m| current.is_none_or(|v| m.version > v))
+    {
+        let mut tx = db.begin().await?;
+        lock(&mut tx).await?;
+        let version: Option<i64> = sqlx::query_scalar("SELECT MAX(version) FROM schema_migrations")
+            .fetch_one(&mut *tx)
+            .await?;
+        if version.is_some_and(|v| v >= migration.version) {
+            continue;
+        }
+        let sql = if postgres {
+            migration.postgres
+        } else {
+            migration.sqlite
+        };
+        sqlx::raw_sql(sql).execute(&mut *tx).await?;
+        sqlx::query(RECORD_MIGRATION)
+            .bind(migration.version)
+            .bind(migration.name)
+            .bind(chrono::Utc::now().timestamp())
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        applied.push(migration.name);
+    }
+    Ok(applied)
+}
+
+pub async fn baseline(db: &ReadWritePool, version: i64) -> Result<(), MigrationError> {
+    if version > latest_version() {
+        return Err(MigrationError::SchemaTooNew(version, latest_version()));
+    }
+    let current = current_version(db).await?;
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|m| m.version <= version && current.is_none_or(|v| m.version > v))
+    {
+        sqlx::query(RECORD_MIGRATION)
+            .bind(migration.version)
+            .bind(migration.name)
+            .bind(chrono::Utc::now().timestamp())
+            .execute(&db.0)
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        models::{
+            ReadWritePool,
+            migrations::{
+                MIGRATIONS, MigrationError, baseline, current_version, latest_version, migrate,
+            },
+        },
+        test::instrumentation::{init, test_pool},
+    };
+
+    #[tokio::test]
+    async fn migrate_twice() {
+        let (state, _) = init().await;
+        assert!(migrate(&state.rwdb).await.unwrap().is_empty());
+        assert_eq!(
+            current_version(&state.rwdb).await.unwrap(),
+            Some(latest_version())
+        );
+
+        sqlx::query(
+            "INSERT INTO schema_migrations (version, name, applied_at) VALUES ($1, 'future', 0)",
+        )
+        .bind(latest_version() + 1)
+        .execute(&state.rwdb.0)
+        .await
+        .unwrap();
+        assert!(matches!(
+            migrate(&state.rwdb).await,
+            Err(MigrationError::SchemaTooNew(_, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn concurrent_migrate() {
+        let db = ReadWritePool(test_pool().await);
+        let (first, second) = tokio::join!(migrate(&db), migrate(&db));
+        assert_eq!(
+            first.unwrap().len() + second.unwrap().len(),
+            MIGRATIONS.len()
+        );
+        assert_eq!(current_version(&db).await.unwrap(), Some(latest_version()));
+    }
+
+    #[tokio::test]
+    async fn unversioned_database() {
+        let db = ReadWritePool(test_pool().await);
+        let postgres = db.acquire().await.unwrap().backend_name() == "PostgreSQL";
+        sqlx::raw_sql(if postgres {
+            MIGRATIONS[0].postgres
+        } else {
+            MIGRATIONS[0].sqlite
+        })
+        .execute(&db.0)
+        .await
+        .unwrap();
+        assert!(matches!(
+            migrate(&db).await,
+            Err(MigrationError::Unversioned)
+        ));
+
+        baseline(&db, 0).await.unwrap();
+        assert_eq!(migrate(&db).await.unwrap().len() as i64, latest_version());
+    }
+}
@@ -1,26 +1,44 @@
 pub mod audio;
+pub mod link_preview;
+pub mod media_usage;
+pub mod migrations;
 pub mod photo;
 pub mod post;
+pub mod upload;
 pub mod user;
 pub mod video;
 
-use std::ops::Deref;
+use std::{fmt::Write, ops::Deref};
+
+use sqlx::{Any, Arguments, Encode, Type, any::AnyArguments};
 
 pub use audio::Audio;
+pub use link_preview::LinkPreview;
+pub use media_usage::MediaUsage;
 pub use photo::Photo;
 pub use post::Post;
+pub use upload::Upload;
 pub use user::User;
 pub use video::Video;
 
-#[cfg(feature = "postgres")]
-pub type DefaultPool = sqlx::PgPool;
-#[cfg(feature = "postgres")]
-pub type DefaultRow = sqlx::postgres::PgRow;
+pub type DefaultPool = sqlx::AnyPool;
+pub type DefaultRow = sqlx::any::AnyRow;
+pub type DefaultConnection = sqlx::AnyConnection;
+
+pub fn connect_lazy(url: &str) -> Result<DefaultPool, sqlx::Error> {
+    sqlx::any::install_default_drivers();
+    DefaultPool::connect_lazy(url)
+}
 
-#[cfg(any(feature = "sqlite", not(all(feature = "postgres"))))]
-pub type DefaultPool = sqlx::SqlitePool;
-#[cfg(any(feature = "sqlite", not(all(feature = "postgres"))))]
-pub type DefaultRow = sqlx::sqlite::SqliteRow;
+pub fn is_unique_violation(e: &sqlx::Error) -> bool {
+    e.as_database_error()
+        .is_some_and(|e| e.is_unique_violation())
+}
+
+pub fn is_foreign_key_violation(e: &sqlx::Error) -> bool {
+    e.as_database_error()
+        .is_some_and(|e| e.is_foreign_key_violation())
+}
 
 #[derive(Clone)]
 pub struct ReadOnlyPool(pub DefaultPool);
@@ -44,46 +62,121 @@ impl Deref for ReadWritePool {
     }
 }
 
+pub struct UpdateBuilder<'q> {
+    sql: String,
+    arguments: AnyArguments<'q>,
+    empty: bool,
+}
+
+impl<'q> UpdateBuilder<'q> {
+    pub fn new(table: &str) -> Self {
+        Self {
+            sql: format!("UPDATE {table} SET "),
+            arguments: AnyArguments::default(),
+            empty: true,
+        }
+    }
+
+    pub fn set<T>(&mut self, column: &str, value: T)
+    where
+        T: 'q + Encode<'q, Any> + Type<Any>,
+    {
+        if !self.empty {
+            self.sql.push_str(", ");
+        }
+        self.arguments.add(value).unwrap();
+        write!(self.sql, "{column} = ${}", self.arguments.len()).unwrap();
+        self.empty = false;
+    }
+
+    pub async fn execute(mut self, db: &ReadWritePool, id: i64) -> Result<(), sqlx::Error> {
+        if self.empty {
+            return Ok(());
+        }
+        self.arguments.add(id).unwrap();
+        write!(self.sql, " WHERE id = ${}", self.arguments.len()).unwrap();
+        sqlx::query_with(&self.sql, self.arguments)
+            .execute(&db.0)
+            .await?;
+        Ok(())
+    }
+}
+
 #[macro_export]
 macro_rules! bind {
     ($builder:ident, $self:ident.$field:ident) => {
         if $self.$field.is_some() {
-            $builder.push(concat!(stringify!($field), " = "));
-            $builder.push_bind_unseparated(&$self.$field);
+            $builder.set(stringify!($field), &$self.$field);
         }
     };
     ($builder:ident, $self:ident.$field:ident as boolint) => {
         if $self.$field.is_some() {
-            $builder.push(concat!(stringify!($field), " = "));
-            $builder.push_bind_unseparated($self.$field.map(|v| if v == true { 1 } else { 0 }));
+            $builder.set(
+                stringify!($field),
+                $self.$field.map(|v| if v == true { 1 } else { 0 }),
+            );
         }
     };
 }
 
 #[macro_export]
 macro_rules! impl_media_common_ops {
-    ($t:ty, $table:literal, $insert_sql:literal) => {
+    ($t:ty, $table:literal, $insert_sql:literal, $reference_sql:literal) => {
         impl $t {
             pub async fn insert(db: &ReadWritePool, user_id: i64) -> Result<i64, sqlx::Error> {
                 sqlx::query_scalar($insert_sql)
                     .bind(user_id)
+                    .bind(chrono::Utc::now().timestamp())
                     .fetch_one(&db.0)
                     .await
             }
 
             pub async fn find(db: &ReadOnlyPool, id: i64) -> Result<$t, sqlx::Error> {
-                sqlx::query_as(concat!("SELECT * FROM ", $table, " WHERE id = $1"))
-                    .bind(id)
+                sqlx::query_as(concat!(
+                    "SELECT * FROM ",
+                    $table,
+                    " WHERE id = COALESCE((SELECT source_id FROM ",
+                    $table,
+                    " WHERE id = $1), $1)"
+                ))
+                .bind(id)
+                .fetch_one(&db.0)
+                .await
+            }
+
+            pub async fn find_by_hash(db: &ReadWritePool, content_hash: &str) -> Option<i64> {
+                sqlx::query_scalar(concat!(
+                    "SELECT id FROM ",
+                    $table,
+                    " WHERE content_hash = $1 AND source_id IS NULL AND processing = 0 AND processing_error IS NULL LIMIT 1"
+                ))
+                .bind(content_hash)
+                .fetch_optional(&db.0)
+                .await
+                .ok()
+                .flatten()
+            }
+
+            pub async fn insert_reference(
+                db: &ReadWritePool,
+                user_id: i64,
+                source_id: i64,
+            ) -> Result<i64, sqlx::Error> {
+                sqlx::query_scalar($reference_sql)
+                    .bind(user_id)
+                    .bind(chrono::Utc::now().timestamp())
+                    .bind(source_id)
                     .fetch_one(&db.0)
                     .await
             }
 
-            pub async fn delete(db: &ReadWritePool, id: i64) -> Result<(), sqlx::Error> {
-                sqlx::query(concat!("DELETE FROM ", $table, " WHERE id = $1"))
+            pub async fn owned_by(db: &ReadOnlyPool, id: i64, user_id: i64) -> bool {
+                sqlx::query(concat!("SELECT id FROM ", $table, " WHERE id = $1 AND user_id = $2"))
                     .bind(id)
-                    .execute(&db.0)
-                    .await?;
-                Ok(())
+                    .bind(user_id)
+                    .fetch_one(&db.0)
+                    .await
+                    .map_or(false, |_| true)
             }
         }
     };
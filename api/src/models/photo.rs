@@ -1,14 +1,19 @@
-use sqlx::{FromRow, QueryBuilder, Row};
+use sqlx::{FromRow, Row};
 
 use crate::{bind, impl_media_common_ops};
 
-use super::{DefaultRow, ReadOnlyPool, ReadWritePool};
+use super::{DefaultRow, ReadOnlyPool, ReadWritePool, UpdateBuilder};
 
 pub struct Photo {
     pub processing: bool,
     pub processing_error: Option<String>,
     pub profile_picture: bool,
     pub banner: bool,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub focus_x: Option<f64>,
+    pub focus_y: Option<f64>,
+    pub blurhash: Option<String>,
     pub jpg_small: Option<Vec<u8>>,
     pub jpg_medium: Option<Vec<u8>>,
     pub jpg_large: Option<Vec<u8>>,
@@ -21,6 +26,11 @@ impl FromRow<'_, DefaultRow> for Photo {
             processing_error: row.try_get("processing_error")?,
             profile_picture: row.try_get::<i16, _>("profile_picture")? == 1,
             banner: row.try_get::<i16, _>("banner")? == 1,
+            width: row.try_get("width")?,
+            height: row.try_get("height")?,
+            focus_x: row.try_get("focus_x")?,
+            focus_y: row.try_get("focus_y")?,
+            blurhash: row.try_get("blurhash")?,
             jpg_small: row.try_get("jpg_small")?,
             jpg_medium: row.try_get("jpg_medium")?,
             jpg_large: row.try_get("jpg_large")?,
@@ -34,31 +44,40 @@ pub struct PhotoUpdateQuery {
     pub processing_error: Option<String>,
     pub profile_picture: Option<bool>,
     pub banner: Option<bool>,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub focus_x: Option<f64>,
+    pub focus_y: Option<f64>,
+    pub blurhash: Option<String>,
     pub jpg_small: Option<Vec<u8>>,
     pub jpg_medium: Option<Vec<u8>>,
     pub jpg_large: Option<Vec<u8>>,
+    pub content_hash: Option<String>,
 }
 
 impl PhotoUpdateQuery {
     pub async fn update(&self, db: &ReadWritePool, id: i64) -> Result<(), sqlx::Error> {
-        let mut builder = QueryBuilder::new("UPDATE photos SET ");
-        let mut match_builder = builder.separated(", ");
-        bind!(match_builder, self.processing as boolint);
-        bind!(match_builder, self.processing_error);
-        bind!(match_builder, self.profile_picture as boolint);
-        bind!(match_builder, self.banner as boolint);
-        bind!(match_builder, self.jpg_small);
-        bind!(match_builder, self.jpg_medium);
-        bind!(match_builder, self.jpg_large);
-        builder.push(" WHERE id = ");
-        builder.push_bind(id);
-        builder.build().execute(&db.0).await?;
-        Ok(())
+        let mut builder = UpdateBuilder::new("photos");
+        bind!(builder, self.processing as boolint);
+        bind!(builder, self.processing_error);
+        bind!(builder, self.profile_picture as boolint);
+        bind!(builder, self.banner as boolint);
+        bind!(builder, self.width);
+        bind!(builder, self.height);
+        bind!(builder, self.focus_x);
+        bind!(builder, self.focus_y);
+        bind!(builder, self.blurhash);
+        bind!(builder, self.jpg_small);
+        bind!(builder, self.jpg_medium);
+        bind!(builder, self.jpg_large);
+        bind!(builder, self.content_hash);
+        builder.execute(db, id).await
     }
 }
 
 impl_media_common_ops!(
     Photo,
     "photos",
-    "INSERT INTO photos (user_id, processing, processing_error, profile_picture, banner, jpg_small, jpg_medium, jpg_large) VALUES ($1, 1, NULL, 0, 0, NULL, NULL, NULL) RETURNING id"
+    "INSERT INTO photos (user_id, processing, processing_error, profile_picture, banner, jpg_small, jpg_medium, jpg_large, created_at) VALUES ($1, 1, NULL, 0, 0, NULL, NULL, NULL, $2) RETURNING id",
+    "INSERT INTO photos (user_id, processing, processing_error, profile_picture, banner, width, height, focus_x, focus_y, blurhash, jpg_small, jpg_medium, jpg_large, created_at, content_hash, source_id) SELECT $1, 0, NULL, profile_picture, banner, width, height, focus_x, focus_y, blurhash, NULL, NULL, NULL, $2, content_hash, id FROM photos WHERE id = $3 RETURNING id"
 );
@@ -1,51 +1,149 @@
 use crate::controllers::{
-    media::{MediaType, encode_media_id},
-    posts::{PostMedia, PostMediaAudio, PostResponse},
+    media::{MediaIdKey, MediaType},
+    posts::{PostLinkPreview, PostMedia, PostMediaAudio, PostResponse},
     users::UserResponse,
 };
-use serde::{Deserialize, Deserializer};
-use sqlx::{FromRow, QueryBuilder, Row};
+use std::collections::HashMap;
 
-use super::{DefaultRow, ReadOnlyPool, ReadWritePool};
+use sqlx::{Connection, FromRow, QueryBuilder, Row};
+
+use super::{DefaultConnection, DefaultPool, DefaultRow, ReadOnlyPool, ReadWritePool};
 
 macro_rules! media_insert {
     ($name:literal, $fnname:ident, $idname:ident) => {
         pub async fn $fnname(
-            db: &ReadWritePool,
+            conn: &mut DefaultConnection,
             post_id: i64,
             $idname: i64,
+            alt: Option<&str>,
+            position: i64,
         ) -> Result<(), sqlx::Error> {
             sqlx::query(concat!(
                 "INSERT INTO posts_",
                 $name,
                 "s (post_id, ",
                 $name,
-                "_id) VALUES ($1, $2)"
+                "_id, alt, position) VALUES ($1, $2, $3, $4)"
             ))
             .bind(post_id)
             .bind($idname)
-            .execute(&db.0)
+            .bind(alt)
+            .bind(position)
+            .execute(conn)
             .await?;
             Ok(())
         }
     };
 }
 
-fn from_int_bool<'a, D>(deserializer: D) -> Result<bool, D::Error>
+const PHOTOS_SQL: &str = "
+    SELECT
+        posts_photos.post_id, posts_photos.position, posts_photos.alt,
+        photos.id, photos.width, photos.height, photos.focus_x, photos.focus_y, photos.blurhash
+    FROM posts_photos
+    INNER JOIN photos ON photos.id = posts_photos.photo_id
+    WHERE posts_photos.post_id IN";
+
+const VIDEOS_SQL: &str = "
+    SELECT
+        posts_videos.post_id, posts_videos.position, posts_videos.alt,
+        CASE WHEN posts_videos.captions IS NULL THEN 0 ELSE 1 END AS captions,
+        videos.id, videos.animation, videos.width, videos.height, videos.duration_ms, videos.blurhash
+    FROM posts_videos
+    INNER JOIN videos ON videos.id = posts_videos.video_id
+    WHERE posts_videos.post_id IN";
+
+const AUDIOS_SQL: &str = "
+    SELECT
+        posts_audios.post_id, posts_audios.position, posts_audios.alt,
+        audios.id, audios.title, audios.artist, audios.voice, audios.duration_ms, audios.waveform,
+        CASE WHEN audios.thumbnail IS NULL AND audio_sources.thumbnail IS NULL THEN 0 ELSE 1 END AS thumbnail,
+        CASE WHEN audios.opus_96k IS NULL AND audio_sources.opus_96k IS NULL THEN 0 ELSE 1 END AS opus
+    FROM posts_audios
+    INNER JOIN audios ON audios.id = posts_audios.audio_id
+    LEFT JOIN audios AS audio_sources ON audio_sources.id = audios.source_id
+    WHERE posts_audios.post_id IN";
+
+const LINK_PREVIEWS_SQL: &str = "
+    SELECT
+        posts_link_previews.post_id, link_previews.url, link_previews.title,
+        link_previews.description, link_previews.site_name,
+        photos.id AS photo_id, photos.width, photos.height, photos.blurhash
+    FROM posts_link_previews
+    INNER JOIN link_previews ON link_previews.id = posts_link_previews.link_preview_id
+    LEFT JOIN photos ON photos.id = link_previews.photo_id
+    WHERE link_previews.processing = 0 AND link_previews.processing_error IS NULL
+        AND posts_link_previews.post_id IN";
+
+async fn find_for_posts<T>(db: &DefaultPool, sql: &str, ids: &[i64]) -> Result<Vec<T>, sqlx::Error>
 where
-    D: Deserializer<'a>,
+    T: for<'r> FromRow<'r, DefaultRow> + Send + Unpin,
 {
-    let value: Option<u8> = Deserialize::deserialize(deserializer)?;
-    Ok(value.map(|v| v == 1).unwrap_or_default())
+    let placeholders = (1..=ids.len())
+        .map(|i| format!("${i}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let sql = format!("{sql} ({placeholders})");
+    let mut query = sqlx::query_as(&sql);
+    for id in ids {
+        query = query.bind(id);
+    }
+    query.fetch_all(db).await
+}
+
+#[derive(Debug)]
+pub struct PostPhoto {
+    pub post_id: i64,
+    pub position: i64,
+    pub id: i64,
+    pub alt: Option<String>,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub focus_x: Option<f64>,
+    pub focus_y: Option<f64>,
+    pub blurhash: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct PostVideo {
+    pub post_id: i64,
+    pub position: i64,
+    pub id: i64,
+    pub animation: bool,
+    pub alt: Option<String>,
+    pub captions: bool,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub duration_ms: Option<i64>,
+    pub blurhash: Option<String>,
 }
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug)]
 pub struct PostAudio {
+    pub post_id: i64,
+    pub position: i64,
     pub id: i64,
+    pub alt: Option<String>,
     pub title: Option<String>,
     pub artist: Option<String>,
-    #[serde(deserialize_with = "from_int_bool")]
     pub thumbnail: bool,
+    pub opus: bool,
+    pub voice: bool,
+    pub duration_ms: Option<i64>,
+    pub waveform: Option<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub struct PostPreview {
+    pub post_id: i64,
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub site_name: Option<String>,
+    pub photo_id: Option<i64>,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub blurhash: Option<String>,
 }
 
 #[derive(Debug)]
@@ -54,9 +152,11 @@ pub struct Post {
     pub post_message: Option<String>,
     pub post_like_count: i64,
     pub post_comment_count: i64,
-    pub post_photos: Vec<i64>,
-    pub post_videos: Vec<i64>,
+    pub post_photos: Vec<PostPhoto>,
+    pub post_videos: Vec<PostVideo>,
+    pub post_animations: Vec<PostVideo>,
     pub post_audios: Vec<PostAudio>,
+    pub post_link_preview: Option<PostPreview>,
     pub post_comment: bool,
     pub post_liked: bool,
     pub user_id: i64,
@@ -82,7 +182,7 @@ pub struct PostFindQuery {
 
 impl Post {
     pub async fn insert(
-        db: &ReadWritePool,
+        conn: &mut DefaultConnection,
         user_id: i64,
         message: Option<&str>,
         comment: bool,
@@ -93,7 +193,7 @@ impl Post {
         .bind(user_id)
         .bind(message)
         .bind(comment)
-        .fetch_one(&db.0)
+        .fetch_one(conn)
         .await?)
     }
 
@@ -112,17 +212,8 @@ impl Post {
             posts.id AS post_id,
             posts.message AS post_message,
             posts.comment AS post_comment,
-            (SELECT concat('[', string_agg(cast(photo_id as TEXT), ','), ']') FROM posts_photos WHERE post_id = posts.id) AS post_photos,
-            (SELECT concat('[', string_agg(cast(video_id as TEXT), ','), ']') FROM posts_videos WHERE post_id = posts.id) AS post_videos,
-            (
-                SELECT
-                    concat('[', string_agg(concat('{\"id\":', audios.id, ',\"title\":\"', replace(audios.title, '\"', '\\\"'), '\",\"artist\":\"', replace(audios.artist, '\"', '\\\"'), '\",\"thumbnail\":', cast(audios.thumbnail IS NOT NULL as INTEGER), '}'), ','), ']')
-                FROM posts_audios
-                INNER JOIN audios ON audios.id = posts_audios.audio_id
-                WHERE post_id = posts.id
-            ) AS post_audios,
-            (SELECT COUNT(*) FROM likes WHERE post_id = posts.id) AS post_like_count,
-            (SELECT COUNT(*) FROM comments WHERE post_id = posts.id) AS post_comment_count,
+            posts.like_count AS post_like_count,
+            posts.comment_count AS post_comment_count,
             (SELECT COUNT(*) FROM likes WHERE post_id = posts.id AND user_id = $5) AS post_liked,
             users.id AS user_id,
             users.username AS user_username,
@@ -131,7 +222,7 @@ impl Post {
             users.profile_picture_photo_id AS user_profile_picture_photo_id,
             users.banner_photo_id AS user_banner_photo_id,
             (SELECT COUNT(*) FROM follows WHERE user_id = $5 AND sub_user_id = users.id) AS user_following,
-            (SELECT COUNT(*) FROM follows WHERE sub_user_id = users.id) AS user_followers
+            users.follower_count AS user_followers
         FROM posts
         INNER JOIN users ON users.id = posts.user_id
         WHERE
@@ -157,7 +248,7 @@ impl Post {
         match_builder.push("users.deleted = 0");
         match_builder.push("posts.deleted = 0");
         builder.push(" ORDER BY posts.id DESC LIMIT $2 OFFSET $1");
-        builder
+        let mut posts: Vec<Post> = builder
             .build_query_as()
             .bind(query.offset)
             .bind(query.count)
@@ -165,79 +256,170 @@ impl Post {
             .bind(query.username)
             .bind(query.self_user_id)
             .fetch_all(&db.0)
-            .await
+            .await?;
+        Self::load_attachments(db, &mut posts).await?;
+        Ok(posts)
+    }
+
+    async fn load_attachments(db: &ReadOnlyPool, posts: &mut [Post]) -> Result<(), sqlx::Error> {
+        if posts.is_empty() {
+            return Ok(());
+        }
+        let ids = posts.iter().map(|p| p.post_id).collect::<Vec<_>>();
+        let index = ids
+            .iter()
+            .enumerate()
+            .map(|(i, &id)| (id, i))
+            .collect::<HashMap<_, _>>();
+
+        for photo in find_for_posts::<PostPhoto>(db, PHOTOS_SQL, &ids).await? {
+            posts[index[&photo.post_id]].post_photos.push(photo);
+        }
+        for video in find_for_posts::<PostVideo>(db, VIDEOS_SQL, &ids).await? {
+            let post = &mut posts[index[&video.post_id]];
+            if video.animation {
+                post.post_animations.push(video);
+            } else {
+                post.post_videos.push(video);
+            }
+        }
+        for audio in find_for_posts::<PostAudio>(db, AUDIOS_SQL, &ids).await? {
+            posts[index[&audio.post_id]].post_audios.push(audio);
+        }
+        for preview in find_for_posts::<PostPreview>(db, LINK_PREVIEWS_SQL, &ids).await? {
+            let post = &mut posts[index[&preview.post_id]];
+            post.post_link_preview = Some(preview);
+        }
+        Ok(())
     }
 
     pub async fn like_insert(
-        db: &ReadWritePool,
+        conn: &mut DefaultConnection,
         post_id: i64,
         user_id: i64,
     ) -> Result<(), sqlx::Error> {
+        let mut tx = conn.begin().await?;
         sqlx::query("INSERT INTO likes (post_id, user_id) VALUES ($1, $2)")
             .bind(post_id)
             .bind(user_id)
-            .execute(&db.0)
+            .execute(&mut *tx)
             .await?;
-        Ok(())
+        sqlx::query("UPDATE posts SET like_count = like_count + 1 WHERE id = $1")
+            .bind(post_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
     }
 
     pub async fn comment_insert(
-        db: &ReadWritePool,
+        conn: &mut DefaultConnection,
         post_id: i64,
         user_id: i64,
         comment_post_id: i64,
     ) -> Result<(), sqlx::Error> {
+        let mut tx = conn.begin().await?;
         sqlx::query("INSERT INTO comments (post_id, user_id, comment_post_id) VALUES ($1, $2, $3)")
             .bind(post_id)
             .bind(user_id)
             .bind(comment_post_id)
-            .execute(&db.0)
+            .execute(&mut *tx)
             .await?;
-        Ok(())
-    }
-
-    pub async fn like_exists(db: &ReadOnlyPool, post_id: i64, user_id: i64) -> bool {
-        sqlx::query("SELECT * FROM likes WHERE post_id = $1 AND user_id = $2")
+        sqlx::query("UPDATE posts SET comment_count = comment_count + 1 WHERE id = $1")
             .bind(post_id)
-            .bind(user_id)
-            .fetch_optional(&db.0)
-            .await
-            .and_then(|v| Ok(v.is_some()))
-            .unwrap_or(false)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
     }
 
     pub async fn like_delete(
-        db: &ReadWritePool,
+        conn: &mut DefaultConnection,
         post_id: i64,
         user_id: i64,
-    ) -> Result<(), sqlx::Error> {
-        sqlx::query("DELETE FROM likes WHERE post_id = $1 AND user_id = $2")
+    ) -> Result<bool, sqlx::Error> {
+        let mut tx = conn.begin().await?;
+        let deleted = sqlx::query("DELETE FROM likes WHERE post_id = $1 AND user_id = $2")
             .bind(post_id)
             .bind(user_id)
-            .execute(&db.0)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        sqlx::query("UPDATE posts SET like_count = like_count - $1 WHERE id = $2")
+            .bind(deleted as i64)
+            .bind(post_id)
+            .execute(&mut *tx)
             .await?;
+        tx.commit().await?;
+        Ok(deleted > 0)
+    }
+
+    pub async fn recount(db: &ReadWritePool) -> Result<u64, sqlx::Error> {
+        Ok(sqlx::query(
+            "UPDATE posts SET
+                like_count = (SELECT COUNT(*) FROM likes WHERE likes.post_id = posts.id),
+                comment_count = (SELECT COUNT(*) FROM comments WHERE comments.post_id = posts.id)
+            WHERE like_count <> (SELECT COUNT(*) FROM likes WHERE likes.post_id = posts.id)
+                OR comment_count <> (SELECT COUNT(*) FROM comments WHERE comments.post_id = posts.id)",
+        )
+        .execute(&db.0)
+        .await?
+        .rows_affected())
+    }
+
+    pub async fn video_insert(
+        conn: &mut DefaultConnection,
+        post_id: i64,
+        video_id: i64,
+        alt: Option<&str>,
+        captions: Option<&str>,
+        position: i64,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO posts_videos (post_id, video_id, alt, captions, position) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(post_id)
+        .bind(video_id)
+        .bind(alt)
+        .bind(captions)
+        .bind(position)
+        .execute(conn)
+        .await?;
         Ok(())
     }
 
+    pub async fn video_captions(
+        db: &ReadOnlyPool,
+        post_id: i64,
+        video_id: i64,
+    ) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT posts_videos.captions FROM posts_videos
+            JOIN posts ON posts.id = posts_videos.post_id
+            JOIN users ON users.id = posts.user_id
+            WHERE posts_videos.post_id = $1 AND posts_videos.video_id = $2
+            AND posts.deleted = 0 AND users.deleted = 0",
+        )
+        .bind(post_id)
+        .bind(video_id)
+        .fetch_one(&db.0)
+        .await
+    }
+
     media_insert!("photo", photo_insert, photo_id);
-    media_insert!("video", video_insert, video_id);
     media_insert!("audio", audio_insert, audio_id);
 }
 
 impl FromRow<'_, DefaultRow> for Post {
     fn from_row(row: &DefaultRow) -> Result<Self, sqlx::Error> {
-        let post_photos: Vec<i64> = serde_json::from_str(row.try_get("post_photos")?).unwrap();
-        let post_videos: Vec<i64> = serde_json::from_str(row.try_get("post_videos")?).unwrap();
-        let post_audios: Vec<PostAudio> =
-            serde_json::from_str(row.try_get("post_audios")?).unwrap();
         Ok(Self {
             post_id: row.try_get("post_id")?,
             post_message: row.try_get("post_message")?,
             post_like_count: row.try_get("post_like_count")?,
             post_comment_count: row.try_get("post_comment_count")?,
-            post_photos,
-            post_videos,
-            post_audios,
+            post_photos: vec![],
+            post_videos: vec![],
+            post_animations: vec![],
+            post_audios: vec![],
+            post_link_preview: None,
             post_comment: row.try_get::<i16, _>("post_comment")? == 1,
             post_liked: row.try_get::<i64, _>("post_liked")? == 1,
             user_id: row.try_get("user_id")?,
@@ -252,35 +434,165 @@ impl FromRow<'_, DefaultRow> for Post {
     }
 }
 
-impl Into<PostResponse> for Post {
-    fn into(self) -> PostResponse {
+impl FromRow<'_, DefaultRow> for PostPhoto {
+    fn from_row(row: &DefaultRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            post_id: row.try_get("post_id")?,
+            position: row.try_get("position")?,
+            id: row.try_get("id")?,
+            alt: row.try_get("alt")?,
+            width: row.try_get("width")?,
+            height: row.try_get("height")?,
+            focus_x: row.try_get("focus_x")?,
+            focus_y: row.try_get("focus_y")?,
+            blurhash: row.try_get("blurhash")?,
+        })
+    }
+}
+
+impl FromRow<'_, DefaultRow> for PostVideo {
+    fn from_row(row: &DefaultRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            post_id: row.try_get("post_id")?,
+            position: row.try_get("position")?,
+            id: row.try_get("id")?,
+            animation: row.try_get::<i16, _>("animation")? == 1,
+            alt: row.try_get("alt")?,
+            captions: row.try_get::<i64, _>("captions")? == 1,
+            width: row.try_get("width")?,
+            height: row.try_get("height")?,
+            duration_ms: row.try_get("duration_ms")?,
+            blurhash: row.try_get("blurhash")?,
+        })
+    }
+}
+
+impl FromRow<'_, DefaultRow> for PostAudio {
+    fn from_row(row: &DefaultRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            post_id: row.try_get("post_id")?,
+            position: row.try_get("position")?,
+            id: row.try_get("id")?,
+            alt: row.try_get("alt")?,
+            title: row.try_get("title")?,
+            artist: row.try_get("artist")?,
+            thumbnail: row.try_get::<i64, _>("thumbnail")? == 1,
+            opus: row.try_get::<i64, _>("opus")? == 1,
+            voice: row.try_get::<i16, _>("voice")? == 1,
+            duration_ms: row.try_get("duration_ms")?,
+            waveform: row
+                .try_get::<Option<String>, _>("waveform")?
+                .and_then(|w| serde_json::from_str(&w).ok()),
+        })
+    }
+}
+
+impl FromRow<'_, DefaultRow> for PostPreview {
+    fn from_row(row: &DefaultRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            post_id: row.try_get("post_id")?,
+            url: row.try_get("url")?,
+            title: row.try_get("title")?,
+            description: row.try_get("description")?,
+            site_name: row.try_get("site_name")?,
+            photo_id: row.try_get("photo_id")?,
+            width: row.try_get("width")?,
+            height: row.try_get("height")?,
+            blurhash: row.try_get("blurhash")?,
+        })
+    }
+}
+
+impl Post {
+    pub fn into_response(self, media_ids: &MediaIdKey) -> PostResponse {
         let mut media = vec![];
         self.post_photos.iter().for_each(|p| {
-            media.push(PostMedia {
-                photo: Some(encode_media_id(MediaType::Photo, *p)),
-                video: None,
-                audio: None,
-            })
+            media.push((
+                p.position,
+                PostMedia {
+                    photo: Some(media_ids.encode(MediaType::Photo, p.id)),
+                    video: None,
+                    animation: None,
+                    audio: None,
+                    alt: p.alt.clone(),
+                    captions: false,
+                    width: p.width,
+                    height: p.height,
+                    focus_x: p.focus_x,
+                    focus_y: p.focus_y,
+                    duration_ms: None,
+                    blurhash: p.blurhash.clone(),
+                },
+            ))
         });
         self.post_videos.iter().for_each(|p| {
-            media.push(PostMedia {
-                photo: None,
-                video: Some(encode_media_id(MediaType::Video, *p)),
-                audio: None,
-            })
+            media.push((
+                p.position,
+                PostMedia {
+                    photo: None,
+                    video: Some(media_ids.encode(MediaType::Video, p.id)),
+                    animation: None,
+                    audio: None,
+                    alt: p.alt.clone(),
+                    captions: p.captions,
+                    width: p.width,
+                    height: p.height,
+                    focus_x: None,
+                    focus_y: None,
+                    duration_ms: p.duration_ms,
+                    blurhash: p.blurhash.clone(),
+                },
+            ))
+        });
+        self.post_animations.iter().for_each(|p| {
+            media.push((
+                p.position,
+                PostMedia {
+                    photo: None,
+                    video: None,
+                    animation: Some(media_ids.encode(MediaType::Animation, p.id)),
+                    audio: None,
+                    alt: p.alt.clone(),
+                    captions: p.captions,
+                    width: p.width,
+                    height: p.height,
+                    focus_x: None,
+                    focus_y: None,
+                    duration_ms: p.duration_ms,
+                    blurhash: p.blurhash.clone(),
+                },
+            ))
         });
         self.post_audios.iter().for_each(|p| {
-            media.push(PostMedia {
-                photo: None,
-                video: None,
-                audio: Some(PostMediaAudio {
-                    id: encode_media_id(MediaType::Audio, p.id),
-                    title: p.title.clone(),
-                    artist: p.artist.clone(),
-                    thumbnail: p.thumbnail,
-                }),
-            })
+            media.push((
+                p.position,
+                PostMedia {
+                    photo: None,
+                    video: None,
+                    animation: None,
+                    audio: Some(PostMediaAudio {
+                        id: media_ids.encode(MediaType::Audio, p.id),
+                        title: p.title.clone(),
+                        artist: p.artist.clone(),
+                        thumbnail: p.thumbnail,
+                        opus: p.opus,
+                        voice: p.voice,
+                        duration_ms: p.duration_ms,
+                        waveform: p.waveform.clone(),
+                    }),
+                    alt: p.alt.clone(),
+                    captions: false,
+                    width: None,
+                    height: None,
+                    focus_x: None,
+                    focus_y: None,
+                    duration_ms: p.duration_ms,
+                    blurhash: None,
+                },
+            ))
         });
+        media.sort_by_key(|(position, _)| *position);
+        let media = media.into_iter().map(|(_, media)| media).collect();
         PostResponse {
             id: self.post_id,
             message: self.post_message,
@@ -296,12 +608,22 @@ impl Into<PostResponse> for Post {
                 following: self.user_following,
                 profile_picture_photo_id: self
                     .user_profile_picture_photo_id
-                    .and_then(|id| Some(encode_media_id(MediaType::ProfilePicture, id))),
+                    .and_then(|id| Some(media_ids.encode(MediaType::ProfilePicture, id))),
                 banner_photo_id: self
                     .user_banner_photo_id
-                    .and_then(|id| Some(encode_media_id(MediaType::Banner, id))),
+                    .and_then(|id| Some(media_ids.encode(MediaType::Banner, id))),
             },
             media,
+            link_preview: self.post_link_preview.map(|p| PostLinkPreview {
+                url: p.url,
+                title: p.title,
+                description: p.description,
+                site_name: p.site_name,
+                photo: p.photo_id.map(|id| media_ids.encode(MediaType::Photo, id)),
+                width: p.width,
+                height: p.height,
+                blurhash: p.blurhash,
+            }),
             mentions: vec![],
             comment: self.post_comment,
         }
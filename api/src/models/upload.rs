@@ -0,0 +1,142 @@
+use sqlx::{FromRow, Row};
+
+use super::{DefaultRow, ReadWritePool};
+
+pub struct Upload {
+    pub id: i64,
+    pub media_type: String,
+    pub length: i64,
+    pub received: i64,
+    pub crop: Option<String>,
+    pub focus: Option<String>,
+}
+
+impl FromRow<'_, DefaultRow> for Upload {
+    fn from_row(row: &DefaultRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            id: row.try_get("id")?,
+            media_type: row.try_get("media_type")?,
+            length: row.try_get("length")?,
+            received: row.try_get("received")?,
+            crop: row.try_get("crop")?,
+            focus: row.try_get("focus")?,
+        })
+    }
+}
+
+impl Upload {
+    pub async fn insert(
+        db: &ReadWritePool,
+        user_id: i64,
+        media_type: &str,
+        length: i64,
+        crop: Option<&str>,
+        focus: Option<&str>,
+    ) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar("INSERT INTO uploads (user_id, media_type, length, received, crop, focus, created_at, updated_at) VALUES ($1, $2, $3, 0, $4, $5, $6, $6) RETURNING id")
+            .bind(user_id)
+            .bind(media_type)
+            .bind(length)
+            .bind(crop)
+            .bind(focus)
+            .bind(chrono::Utc::now().timestamp())
+            .fetch_one(&db.0)
+            .await
+    }
+
+    pub async fn find(db: &ReadWritePool, id: i64, user_id: i64) -> Result<Upload, sqlx::Error> {
+        sqlx::query_as("SELECT * FROM uploads WHERE id = $1 AND user_id = $2")
+            .bind(id)
+            .bind(user_id)
+            .fetch_one(&db.0)
+            .await
+    }
+
+    pub async fn find_stale(db: &ReadWritePool, before: i64) -> Result<Vec<Upload>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT * FROM uploads WHERE updated_at < $1 AND (locked_until_ms IS NULL OR locked_until_ms < $2)",
+        )
+        .bind(before)
+        .bind(chrono::Utc::now().timestamp_millis())
+        .fetch_all(&db.0)
+        .await
+    }
+
+    pub async fn lock(
+        db: &ReadWritePool,
+        id: i64,
+        offset: i64,
+        until_ms: i64,
+    ) -> Result<bool, sqlx::Error> {
+        Ok(sqlx::query(
+            "UPDATE uploads SET locked_until_ms = $3
+            WHERE id = $1 AND received = $2 AND (locked_until_ms IS NULL OR locked_until_ms < $4)",
+        )
+        .bind(id)
+        .bind(offset)
+        .bind(until_ms)
+        .bind(chrono::Utc::now().timestamp_millis())
+        .execute(&db.0)
+        .await?
+        .rows_affected()
+            == 1)
+    }
+
+    pub async fn advance(
+        db: &ReadWritePool,
+        id: i64,
+        from: i64,
+        to: i64,
+        locked_until_ms: i64,
+    ) -> Result<bool, sqlx::Error> {
+        Ok(sqlx::query(
+            "UPDATE uploads SET received = $3, locked_until_ms = NULL, updated_at = $5
+            WHERE id = $1 AND received = $2 AND locked_until_ms = $4",
+        )
+        .bind(id)
+        .bind(from)
+        .bind(to)
+        .bind(locked_until_ms)
+        .bind(chrono::Utc::now().timestamp())
+        .execute(&db.0)
+        .await?
+        .rows_affected()
+            == 1)
+    }
+
+    pub async fn claim_complete(
+        db: &ReadWritePool,
+        id: i64,
+        user_id: i64,
+    ) -> Result<bool, sqlx::Error> {
+        Ok(sqlx::query(
+            "DELETE FROM uploads WHERE id = $1 AND user_id = $2 AND received = length
+            AND (locked_until_ms IS NULL OR locked_until_ms < $3)",
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(chrono::Utc::now().timestamp_millis())
+        .execute(&db.0)
+        .await?
+        .rows_affected()
+            == 1)
+    }
+
+    pub async fn delete_stale(
+        db: &ReadWritePool,
+        id: i64,
+        before: i64,
+    ) -> Result<bool, sqlx::Error> {
+        Ok(sqlx::query(
+            "DELETE FROM uploads WHERE id = $1 AND updated_at < $2
+            AND (locked_until_ms IS NULL OR locked_until_ms < $3)",
+        )
+        .bind(id)
+        .bind(before)
+        .bind(chrono::Utc::now().timestamp_millis())
+        .execute(&db.0)
+        .await?
+        .rows_affected()
+            == 1)
+    }
+}
@@ -1,14 +1,14 @@
-use sqlx::{FromRow, Row};
+use sqlx::{Connection, FromRow, Row};
 
 use crate::{
     cond,
     controllers::{
-        media::{MediaType, encode_media_id},
+        media::{MediaIdKey, MediaType},
         users::UserResponse,
     },
 };
 
-use super::{DefaultRow, ReadOnlyPool, ReadWritePool};
+use super::{DefaultConnection, DefaultRow, ReadOnlyPool, ReadWritePool};
 
 pub struct User {
     pub id: i64,
@@ -58,7 +58,7 @@ impl User {
             "
         SELECT
             *,
-            (SELECT COUNT(*) FROM follows WHERE sub_user_id = id) AS followers,
+            follower_count AS followers,
             (SELECT COUNT(*) FROM follows WHERE user_id = $3 AND sub_user_id = id) AS following
         FROM users
         {}
@@ -134,38 +134,53 @@ impl User {
     }
 
     pub async fn follow_insert(
-        db: &ReadWritePool,
+        conn: &mut DefaultConnection,
         user_id: i64,
         sub_user_id: i64,
     ) -> Result<(), sqlx::Error> {
+        let mut tx = conn.begin().await?;
         sqlx::query("INSERT INTO follows (user_id, sub_user_id) VALUES ($1, $2)")
             .bind(user_id)
             .bind(sub_user_id)
-            .execute(&db.0)
+            .execute(&mut *tx)
             .await?;
-        Ok(())
-    }
-
-    pub async fn follow_exists(db: &ReadOnlyPool, user_id: i64, sub_user_id: i64) -> bool {
-        sqlx::query("SELECT * FROM follows WHERE user_id = $1 AND sub_user_id = $2")
-            .bind(user_id)
+        sqlx::query("UPDATE users SET follower_count = follower_count + 1 WHERE id = $1")
             .bind(sub_user_id)
-            .fetch_one(&db.0)
-            .await
-            .map_or(false, |_| true)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
     }
 
     pub async fn follow_delete(
-        db: &ReadWritePool,
+        conn: &mut DefaultConnection,
         user_id: i64,
         sub_user_id: i64,
-    ) -> Result<(), sqlx::Error> {
-        sqlx::query("DELETE FROM follows WHERE user_id = $1 AND sub_user_id = $2")
+    ) -> Result<bool, sqlx::Error> {
+        let mut tx = conn.begin().await?;
+        let deleted = sqlx::query("DELETE FROM follows WHERE user_id = $1 AND sub_user_id = $2")
             .bind(user_id)
             .bind(sub_user_id)
-            .execute(&db.0)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        sqlx::query("UPDATE users SET follower_count = follower_count - $1 WHERE id = $2")
+            .bind(deleted as i64)
+            .bind(sub_user_id)
+            .execute(&mut *tx)
             .await?;
-        Ok(())
+        tx.commit().await?;
+        Ok(deleted > 0)
+    }
+
+    pub async fn recount(db: &ReadWritePool) -> Result<u64, sqlx::Error> {
+        Ok(sqlx::query(
+            "UPDATE users SET
+                follower_count = (SELECT COUNT(*) FROM follows WHERE follows.sub_user_id = users.id)
+            WHERE follower_count <> (SELECT COUNT(*) FROM follows WHERE follows.sub_user_id = users.id)",
+        )
+        .execute(&db.0)
+        .await?
+        .rows_affected())
     }
 }
 
@@ -185,8 +200,8 @@ impl FromRow<'_, DefaultRow> for User {
     }
 }
 
-impl Into<UserResponse> for User {
-    fn into(self) -> UserResponse {
+impl User {
+    pub fn into_response(self, media_ids: &MediaIdKey) -> UserResponse {
         UserResponse {
             id: self.id,
             followers: self.followers,
@@ -196,10 +211,10 @@ impl Into<UserResponse> for User {
             following: self.following,
             profile_picture_photo_id: self
                 .profile_picture_photo_id
-                .and_then(|id| Some(encode_media_id(MediaType::ProfilePicture, id))),
+                .and_then(|id| Some(media_ids.encode(MediaType::ProfilePicture, id))),
             banner_photo_id: self
                 .banner_photo_id
-                .and_then(|id| Some(encode_media_id(MediaType::Banner, id))),
+                .and_then(|id| Some(media_ids.encode(MediaType::Banner, id))),
         }
     }
 }
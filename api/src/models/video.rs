@@ -1,12 +1,17 @@
-use sqlx::{FromRow, QueryBuilder, Row};
+use sqlx::{FromRow, Row};
 
 use crate::{bind, impl_media_common_ops};
 
-use super::{DefaultRow, ReadOnlyPool, ReadWritePool};
+use super::{DefaultRow, ReadOnlyPool, ReadWritePool, UpdateBuilder};
 
 pub struct Video {
     pub processing: bool,
     pub processing_error: Option<String>,
+    pub animation: bool,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub duration_ms: Option<i64>,
+    pub blurhash: Option<String>,
     pub thumbnail: Option<Vec<u8>>,
     pub mp4_480p: Option<Vec<u8>>,
 }
@@ -16,6 +21,11 @@ impl FromRow<'_, DefaultRow> for Video {
         Ok(Self {
             processing: row.try_get::<i16, _>("processing")? == 1,
             processing_error: row.try_get("processing_error")?,
+            animation: row.try_get::<i16, _>("animation")? == 1,
+            width: row.try_get("width")?,
+            height: row.try_get("height")?,
+            duration_ms: row.try_get("duration_ms")?,
+            blurhash: row.try_get("blurhash")?,
             thumbnail: row.try_get("thumbnail")?,
             mp4_480p: row.try_get("mp4_480p")?,
         })
@@ -26,27 +36,36 @@ impl FromRow<'_, DefaultRow> for Video {
 pub struct VideoUpdateQuery {
     pub processing: Option<bool>,
     pub processing_error: Option<String>,
+    pub animation: Option<bool>,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub duration_ms: Option<i64>,
+    pub blurhash: Option<String>,
     pub thumbnail: Option<Vec<u8>>,
     pub mp4_480p: Option<Vec<u8>>,
+    pub content_hash: Option<String>,
 }
 
 impl VideoUpdateQuery {
     pub async fn update(&self, db: &ReadWritePool, id: i64) -> Result<(), sqlx::Error> {
-        let mut builder = QueryBuilder::new("UPDATE videos SET ");
-        let mut match_builder = builder.separated(", ");
-        bind!(match_builder, self.processing as boolint);
-        bind!(match_builder, self.processing_error);
-        bind!(match_builder, self.thumbnail);
-        bind!(match_builder, self.mp4_480p);
-        builder.push(" WHERE id = ");
-        builder.push_bind(id);
-        builder.build().execute(&db.0).await?;
-        Ok(())
+        let mut builder = UpdateBuilder::new("videos");
+        bind!(builder, self.processing as boolint);
+        bind!(builder, self.processing_error);
+        bind!(builder, self.animation as boolint);
+        bind!(builder, self.width);
+        bind!(builder, self.height);
+        bind!(builder, self.duration_ms);
+        bind!(builder, self.blurhash);
+        bind!(builder, self.thumbnail);
+        bind!(builder, self.mp4_480p);
+        bind!(builder, self.content_hash);
+        builder.execute(db, id).await
     }
 }
 
 impl_media_common_ops!(
     Video,
     "videos",
-    "INSERT INTO videos (user_id, processing, processing_error, thumbnail, mp4_480p) VALUES ($1, 1, NULL, NULL, NULL) RETURNING id"
+    "INSERT INTO videos (user_id, processing, processing_error, animation, thumbnail, mp4_480p, created_at) VALUES ($1, 1, NULL, 0, NULL, NULL, $2) RETURNING id",
+    "INSERT INTO videos (user_id, processing, processing_error, animation, width, height, duration_ms, blurhash, thumbnail, mp4_480p, created_at, content_hash, source_id) SELECT $1, 0, NULL, animation, width, height, duration_ms, blurhash, NULL, NULL, $2, content_hash, id FROM videos WHERE id = $3 RETURNING id"
 );
@@ -0,0 +1,203 @@
+use std::{
+    convert::Infallible,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::{HeaderValue, header, request::Parts},
+    middleware::Next,
+    response::Response,
+};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::SharedState;
+
+pub const LAST_WRITE_COOKIE: &str = "last_write";
+const MAC_SIZE: usize = 16;
+
+pub struct StickyPrimary {
+    window: Duration,
+    key: Vec<u8>,
+}
+
+#[derive(Clone, Default)]
+pub struct Consistency {
+    last_write_ms: Option<i64>,
+    wrote: Arc<AtomicBool>,
+}
+
+impl Consistency {
+    pub fn wrote(&self) {
+        self.wrote.store(true, Ordering::Relaxed);
+    }
+}
+
+impl<S> FromRequestParts<S> for Consistency
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<Consistency>()
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+impl StickyPrimary {
+    pub fn new(window: Duration, secret: &[u8]) -> Self {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(b"sticky-primary");
+        Self {
+            window,
+            key: mac.finalize().into_bytes().to_vec(),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(
+            Duration::from_secs(
+                std::env::var("STICKY_PRIMARY_SECONDS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(5),
+            ),
+            std::env::var("JWT_SECRET")
+                .expect("JWT_SECRET not set")
+                .as_bytes(),
+        )
+    }
+
+    fn mac(&self, last_write_ms: i64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(&last_write_ms.to_le_bytes());
+        mac
+    }
+
+    pub fn token(&self, last_write_ms: i64) -> String {
+        let mac = self.mac(last_write_ms).finalize().into_bytes();
+        format!(
+            "{last_write_ms}.{}",
+            BASE64_URL_SAFE_NO_PAD.encode(&mac[..MAC_SIZE])
+        )
+    }
+
+    pub fn verify(&self, token: &str) -> Option<i64> {
+        let (last_write_ms, mac) = token.split_once('.')?;
+        let last_write_ms = last_write_ms.parse().ok()?;
+        let mac = BASE64_URL_SAFE_NO_PAD.decode(mac).ok()?;
+        self.mac(last_write_ms)
+            .verify_truncated_left(&mac)
+            .ok()
+            .filter(|_| mac.len() == MAC_SIZE)
+            .map(|_| last_write_ms)
+    }
+
+    pub fn is_sticky(&self, consistency: &Consistency) -> bool {
+        if self.window.is_zero() {
+            return false;
+        }
+        let now = chrono::Utc::now().timestamp_millis();
+        consistency.wrote.load(Ordering::Relaxed)
+            || consistency
+                .last_write_ms
+                .is_some_and(|at| at <= now && now - at < self.window.as_millis() as i64)
+    }
+
+    fn read(&self, parts: &Parts) -> Option<i64> {
+        parts
+            .headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(';'))
+            .filter_map(|c| c.trim().split_once('='))
+            .find(|(name, _)| *name == LAST_WRITE_COOKIE)
+            .and_then(|(_, value)| self.verify(value))
+    }
+
+    fn cookie(&self) -> HeaderValue {
+        let token = self.token(chrono::Utc::now().timestamp_millis());
+        HeaderValue::from_str(&format!(
+            "{LAST_WRITE_COOKIE}={token}; Path=/api; Max-Age={}; HttpOnly; SameSite=Lax",
+            self.window.as_secs().max(1)
+        ))
+        .unwrap()
+    }
+}
+
+pub async fn track(
+    State(state): State<Arc<SharedState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (mut parts, body) = request.into_parts();
+    let consistency = Consistency {
+        last_write_ms: state.sticky.read(&parts),
+        wrote: Default::default(),
+    };
+    parts.extensions.insert(consistency.clone());
+    let mut response = next.run(Request::from_parts(parts, body)).await;
+    if consistency.wrote.load(Ordering::Relaxed) && !state.sticky.window.is_zero() {
+        response
+            .headers_mut()
+            .append(header::SET_COOKIE, state.sticky.cookie());
+    }
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use axum::http::{Request, header};
+
+    use crate::services::consistency::{Consistency, LAST_WRITE_COOKIE, StickyPrimary};
+
+    #[test]
+    fn sticky_window() {
+        let sticky = StickyPrimary::new(Duration::from_millis(50), b"test");
+        let now = chrono::Utc::now().timestamp_millis();
+        let token = sticky.token(now);
+        assert_eq!(sticky.verify(&token), Some(now));
+        assert_eq!(sticky.verify(&token.replacen('1', "2", 1)), None);
+        assert_eq!(
+            StickyPrimary::new(Duration::from_millis(50), b"other").verify(&token),
+            None
+        );
+        let (parts, _) = Request::builder()
+            .header(
+                header::COOKIE,
+                format!("theme=dark; {LAST_WRITE_COOKIE}={token}"),
+            )
+            .body(())
+            .unwrap()
+            .into_parts();
+        assert_eq!(sticky.read(&parts), Some(now));
+
+        let recent = Consistency {
+            last_write_ms: Some(now),
+            ..Default::default()
+        };
+        assert!(sticky.is_sticky(&recent));
+        assert!(!sticky.is_sticky(&Consistency::default()));
+        let current = Consistency::default();
+        current.wrote();
+        assert!(sticky.is_sticky(&current));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!sticky.is_sticky(&recent));
+
+        let disabled = StickyPrimary::new(Duration::ZERO, b"test");
+        assert!(!disabled.is_sticky(&current));
+    }
+}
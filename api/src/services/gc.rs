@@ -0,0 +1,315 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use sqlx::Row;
+use tokio::fs;
+
+use crate::{
+    SharedState,
+    controllers::media::{upload_path, upload_root},
+    models::{MediaUsage, ReadWritePool, Upload},
+};
+
+struct MediaTable {
+    table: &'static str,
+    link_table: &'static str,
+    link_column: &'static str,
+    size: &'static str,
+    referenced: &'static str,
+}
+
+const MEDIA_TABLES: [MediaTable; 3] = [
+    MediaTable {
+        table: "photos",
+        link_table: "posts_photos",
+        link_column: "photo_id",
+        size: "COALESCE(LENGTH(jpg_small), 0) + COALESCE(LENGTH(jpg_medium), 0) + COALESCE(LENGTH(jpg_large), 0)",
+        referenced: "(EXISTS (SELECT 1 FROM users WHERE users.profile_picture_photo_id = photos.id OR users.banner_photo_id = photos.id) OR EXISTS (SELECT 1 FROM link_previews WHERE link_previews.photo_id = photos.id))",
+    },
+    MediaTable {
+        table: "videos",
+        link_table: "posts_videos",
+        link_column: "video_id",
+        size: "COALESCE(LENGTH(thumbnail), 0) + COALESCE(LENGTH(mp4_480p), 0)",
+        referenced: "1 = 0",
+    },
+    MediaTable {
+        table: "audios",
+        link_table: "posts_audios",
+        link_column: "audio_id",
+        size: "COALESCE(LENGTH(thumbnail), 0) + COALESCE(LENGTH(mp3_128k), 0) + COALESCE(LENGTH(opus_96k), 0)",
+        referenced: "1 = 0",
+    },
+];
+
+pub struct GcConfig {
+    pub orphan_age: i64,
+    pub failed_retention: i64,
+    pub upload_age: i64,
+    pub interval: u64,
+}
+
+impl GcConfig {
+    pub fn from_env() -> Self {
+        let seconds = |name: &str, default: i64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+        Self {
+            orphan_age: seconds("MEDIA_GC_ORPHAN_AGE", 24 * 60 * 60),
+            failed_retention: seconds("MEDIA_GC_FAILED_RETENTION", 60 * 60),
+            upload_age: seconds("MEDIA_GC_UPLOAD_AGE", 24 * 60 * 60),
+            interval: seconds("MEDIA_GC_INTERVAL", 60 * 60) as u64,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct GcReport {
+    pub photos: u64,
+    pub videos: u64,
+    pub audios: u64,
+    pub uploads: u64,
+    pub bytes: u64,
+}
+
+impl fmt::Display for GcReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "removed {} photos, {} videos, {} audios and {} uploads, reclaimed {} bytes",
+            self.photos, self.videos, self.audios, self.uploads, self.bytes
+        )
+    }
+}
+
+async fn collect_table(
+    db: &ReadWritePool,
+    media: &MediaTable,
+    now: i64,
+    config: &GcConfig,
+    report: &mut GcReport,
+) -> Result<u64, sqlx::Error> {
+    let condition = format!(
+        "((created_at < $1 AND NOT EXISTS (SELECT 1 FROM {link} WHERE {link}.{column} = {table}.id) AND NOT {referenced})
+        OR (processing_error IS NOT NULL AND created_at < $2)
+        OR user_id IN (SELECT id FROM users WHERE deleted = 1))
+        AND NOT EXISTS (SELECT 1 FROM {table} AS refs WHERE refs.source_id = {table}.id)",
+        table = media.table,
+        link = media.link_table,
+        column = media.link_column,
+        referenced = media.referenced,
+    );
+    let rows = sqlx::query(&format!(
+        "SELECT id, user_id, CAST({} AS BIGINT) AS size FROM {} WHERE {condition}",
+        media.size, media.table
+    ))
+    .bind(now - config.orphan_age)
+    .bind(now - config.failed_retention)
+    .fetch_all(&db.0)
+    .await?;
+
+    let mut removed = 0;
+    for row in rows {
+        let id: i64 = row.try_get("id")?;
+        let user_id: i64 = row.try_get("user_id")?;
+        let size: i64 = row.try_get("size")?;
+        let mut tx = db.begin().await?;
+        let deleted = sqlx::query(&format!(
+            "DELETE FROM {} WHERE id = $3 AND {condition}",
+            media.table
+        ))
+        .bind(now - config.orphan_age)
+        .bind(now - config.failed_retention)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if deleted == 0 {
+            continue;
+        }
+        if size > 0 {
+            MediaUsage::add_storage(&mut tx, user_id, -size).await?;
+        }
+        tx.commit().await?;
+        removed += 1;
+        report.bytes += size as u64;
+    }
+    Ok(removed)
+}
+
+pub async fn collect(db: &ReadWritePool, config: &GcConfig) -> Result<GcReport, sqlx::Error> {
+    let now = chrono::Utc::now().timestamp();
+    let mut report = GcReport::default();
+
+    report.photos = collect_table(db, &MEDIA_TABLES[0], now, config, &mut report).await?;
+    report.videos = collect_table(db, &MEDIA_TABLES[1], now, config, &mut report).await?;
+    report.audios = collect_table(db, &MEDIA_TABLES[2], now, config, &mut report).await?;
+
+    let upload_root = upload_root();
+    for upload in Upload::find_stale(db, now - config.upload_age).await? {
+        if !Upload::delete_stale(db, upload.id, now - config.upload_age).await? {
+            continue;
+        }
+        let path = upload_path(&upload_root, upload.id);
+        if let Ok(metadata) = fs::metadata(&path).await {
+            report.bytes += metadata.len();
+        }
+        let _ = fs::remove_file(&path).await;
+        report.uploads += 1;
+    }
+
+    Ok(report)
+}
+
+pub async fn run(state: Arc<SharedState>) {
+    let config = GcConfig::from_env();
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+    loop {
+        interval.tick().await;
+        match collect(&state.rwdb, &config).await {
+            Ok(report) => println!("GC: {report}"),
+            Err(e) => println!("GC ERROR: {e:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        models::{MediaUsage, Photo, Post, Upload, Video, photo::PhotoUpdateQuery},
+        services::gc::{GcConfig, collect},
+        test::instrumentation::init,
+    };
+
+    #[tokio::test]
+    async fn collect_media() {
+        let (state, _) = init().await;
+        let config = GcConfig {
+            orphan_age: 60,
+            failed_retention: 60,
+            upload_age: 60,
+            interval: 60,
+        };
+
+        let orphan_id = Photo::insert(&state.rwdb, 1).await.unwrap();
+        PhotoUpdateQuery {
+            processing: Some(false),
+            jpg_small: Some(vec![0; 100]),
+            ..Default::default()
+        }
+        .update(&state.rwdb, orphan_id)
+        .await
+        .unwrap();
+        let mut conn = state.rwdb.acquire().await.unwrap();
+        MediaUsage::add_storage(&mut conn, 1, 100).await.unwrap();
+        drop(conn);
+
+        let attached_id = Photo::insert(&state.rwdb, 1).await.unwrap();
+        let mut conn = state.rwdb.acquire().await.unwrap();
+        let post_id = Post::insert(&mut conn, 1, None, false).await.unwrap();
+        Post::photo_insert(&mut conn, post_id, attached_id, None, 0)
+            .await
+            .unwrap();
+        drop(conn);
+
+        let source_id = Photo::insert(&state.rwdb, 1).await.unwrap();
+        PhotoUpdateQuery {
+            processing: Some(false),
+            jpg_small: Some(vec![0; 50]),
+            ..Default::default()
+        }
+        .update(&state.rwdb, source_id)
+        .await
+        .unwrap();
+        let reference_id = Photo::insert_reference(&state.rwdb, 1, source_id)
+            .await
+            .unwrap();
+        let mut conn = state.rwdb.acquire().await.unwrap();
+        let post_id = Post::insert(&mut conn, 1, None, false).await.unwrap();
+        Post::photo_insert(&mut conn, post_id, reference_id, None, 0)
+            .await
+            .unwrap();
+        drop(conn);
+
+        let fresh_id = Photo::insert(&state.rwdb, 1).await.unwrap();
+
+        let failed_id = Video::insert(&state.rwdb, 1).await.unwrap();
+        sqlx::query("UPDATE videos SET processing = 0, processing_error = 'error' WHERE id = $1")
+            .bind(failed_id)
+            .execute(&state.rwdb.0)
+            .await
+            .unwrap();
+
+        sqlx::query("UPDATE photos SET created_at = 0 WHERE id <> $1")
+            .bind(fresh_id)
+            .execute(&state.rwdb.0)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE videos SET created_at = 0")
+            .execute(&state.rwdb.0)
+            .await
+            .unwrap();
+
+        let report = collect(&state.rwdb, &config).await.unwrap();
+        assert_eq!(report.photos, 1);
+        assert_eq!(report.videos, 1);
+        assert_eq!(report.bytes, 100);
+        assert!(Photo::find(&state.db(), orphan_id).await.is_err());
+        assert!(Photo::find(&state.db(), attached_id).await.is_ok());
+        assert!(Photo::find(&state.db(), fresh_id).await.is_ok());
+        assert!(Photo::find(&state.db(), source_id).await.is_ok());
+        assert!(Photo::find(&state.db(), reference_id).await.is_ok());
+        assert!(Video::find(&state.db(), failed_id).await.is_err());
+        assert_eq!(
+            MediaUsage::find(&state.rwdb, 1)
+                .await
+                .unwrap()
+                .storage_bytes,
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn collect_uploads() {
+        let (state, _) = init().await;
+        let config = GcConfig {
+            orphan_age: 60,
+            failed_retention: 60,
+            upload_age: 60,
+            interval: 60,
+        };
+
+        let mut ids = vec![];
+        for _ in 0..3 {
+            ids.push(
+                Upload::insert(&state.rwdb, 1, "photo", 1024, None, None)
+                    .await
+                    .unwrap(),
+            );
+        }
+        let (stale_id, active_id, locked_id) = (ids[0], ids[1], ids[2]);
+        sqlx::query("UPDATE uploads SET created_at = 0")
+            .execute(&state.rwdb.0)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE uploads SET updated_at = 0 WHERE id <> $1")
+            .bind(active_id)
+            .execute(&state.rwdb.0)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE uploads SET locked_until_ms = $1 WHERE id = $2")
+            .bind(chrono::Utc::now().timestamp_millis() + 60_000)
+            .bind(locked_id)
+            .execute(&state.rwdb.0)
+            .await
+            .unwrap();
+
+        let report = collect(&state.rwdb, &config).await.unwrap();
+        assert_eq!(report.uploads, 1);
+        assert!(Upload::find(&state.rwdb, stale_id, 1).await.is_err());
+        assert!(Upload::find(&state.rwdb, active_id, 1).await.is_ok());
+        assert!(Upload::find(&state.rwdb, locked_id, 1).await.is_ok());
+    }
+}
@@ -1,16 +1,36 @@
+use futures::future::BoxFuture;
+use sha2::{Digest, Sha256};
 use std::{
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::{Output, Stdio},
+    time::Duration,
 };
-use tokio::{fs, process::Command};
+use tokio::{fs, io::AsyncReadExt, process::Command};
 
 pub struct PhotoResult {
+    pub width: i64,
+    pub height: i64,
+    pub focus: Option<(f64, f64)>,
+    pub blurhash: String,
     pub jpg_small: Vec<u8>,
     pub jpg_medium: Option<Vec<u8>>,
     pub jpg_large: Option<Vec<u8>>,
 }
 
 pub struct VideoResult {
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub duration_ms: Option<i64>,
+    pub blurhash: String,
+    pub thumbnail: Vec<u8>,
+    pub mp4_480p: Vec<u8>,
+}
+
+pub struct AnimationResult {
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub duration_ms: Option<i64>,
+    pub blurhash: String,
     pub thumbnail: Vec<u8>,
     pub mp4_480p: Vec<u8>,
 }
@@ -18,8 +38,82 @@ pub struct VideoResult {
 pub struct AudioResult {
     pub title: Option<String>,
     pub artist: Option<String>,
+    pub duration_ms: Option<i64>,
+    pub waveform: Vec<u8>,
     pub thumbnail: Option<Vec<u8>>,
     pub mp3_128k: Vec<u8>,
+    pub opus_96k: Option<Vec<u8>>,
+}
+
+pub const WAVEFORM_BUCKETS: usize = 128;
+
+const FFMPEG_PROCESS_ERROR: &str = "cannot create ffmpeg process";
+const FFPROBE_PROCESS_ERROR: &str = "cannot create ffprobe process";
+
+pub trait MediaProcessor: Send + Sync {
+    fn process_photo<'a>(
+        &'a self,
+        input: &'a Path,
+        options: PhotoOptions,
+    ) -> BoxFuture<'a, Result<PhotoResult, MediaError>>;
+    fn process_video<'a>(
+        &'a self,
+        input: &'a Path,
+    ) -> BoxFuture<'a, Result<VideoResult, MediaError>>;
+    fn process_animation<'a>(
+        &'a self,
+        input: &'a Path,
+    ) -> BoxFuture<'a, Result<AnimationResult, MediaError>>;
+    fn process_audio<'a>(
+        &'a self,
+        input: &'a Path,
+    ) -> BoxFuture<'a, Result<AudioResult, MediaError>>;
+    fn process_voice<'a>(
+        &'a self,
+        input: &'a Path,
+    ) -> BoxFuture<'a, Result<AudioResult, MediaError>>;
+}
+
+pub struct FfmpegProcessor {
+    pub limits: ProcessLimits,
+}
+
+impl MediaProcessor for FfmpegProcessor {
+    fn process_photo<'a>(
+        &'a self,
+        input: &'a Path,
+        options: PhotoOptions,
+    ) -> BoxFuture<'a, Result<PhotoResult, MediaError>> {
+        Box::pin(process_photo(&self.limits, input, options))
+    }
+
+    fn process_video<'a>(
+        &'a self,
+        input: &'a Path,
+    ) -> BoxFuture<'a, Result<VideoResult, MediaError>> {
+        Box::pin(process_video(&self.limits, input))
+    }
+
+    fn process_animation<'a>(
+        &'a self,
+        input: &'a Path,
+    ) -> BoxFuture<'a, Result<AnimationResult, MediaError>> {
+        Box::pin(process_animation(&self.limits, input))
+    }
+
+    fn process_audio<'a>(
+        &'a self,
+        input: &'a Path,
+    ) -> BoxFuture<'a, Result<AudioResult, MediaError>> {
+        Box::pin(process_audio(&self.limits, input))
+    }
+
+    fn process_voice<'a>(
+        &'a self,
+        input: &'a Path,
+    ) -> BoxFuture<'a, Result<AudioResult, MediaError>> {
+        Box::pin(process_voice(&self.limits, input))
+    }
 }
 
 #[derive(serde::Deserialize)]
@@ -32,6 +126,7 @@ pub struct FfprobeResult {
 pub struct FfprobeFormat {
     pub format_name: String,
     pub nb_streams: u64,
+    pub duration: Option<String>,
     pub tags: Option<FfprobeTags>,
 }
 
@@ -50,93 +145,474 @@ pub struct FfprobeStream {
     pub height: Option<u64>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaErrorCode {
+    UnsupportedContainer,
+    TooManyStreams,
+    TooLarge,
+    TooLong,
+    CorruptInput,
+    InvalidCrop,
+    Timeout,
+    Internal,
+}
+
+impl MediaErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::UnsupportedContainer => "unsupported_container",
+            Self::TooManyStreams => "too_many_streams",
+            Self::TooLarge => "too_large",
+            Self::TooLong => "too_long",
+            Self::CorruptInput => "corrupt_input",
+            Self::InvalidCrop => "invalid_crop",
+            Self::Timeout => "timeout",
+            Self::Internal => "internal",
+        }
+    }
+
+    pub fn parse(value: &str) -> Self {
+        match value {
+            "unsupported_container" => Self::UnsupportedContainer,
+            "too_many_streams" => Self::TooManyStreams,
+            "too_large" => Self::TooLarge,
+            "too_long" => Self::TooLong,
+            "corrupt_input" => Self::CorruptInput,
+            "invalid_crop" => Self::InvalidCrop,
+            "timeout" => Self::Timeout,
+            _ => Self::Internal,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::UnsupportedContainer => "This file format is not supported",
+            Self::TooManyStreams => "The file has an unexpected number of streams",
+            Self::TooLarge => "The media resolution is too high",
+            Self::TooLong => "The media is too long",
+            Self::CorruptInput => "The file is damaged or cannot be decoded",
+            Self::InvalidCrop => "The crop rectangle is outside of the image",
+            Self::Timeout => "Processing took too long",
+            Self::Internal => "Internal error while processing the media",
+        }
+    }
+}
+
 #[derive(Debug)]
-#[allow(dead_code)]
 pub struct MediaError {
+    pub code: MediaErrorCode,
     pub error: String,
     pub ffmpeg_error: Option<String>,
 }
 
 impl MediaError {
-    pub fn new(error: String, ffmpeg_error: Option<String>) -> Self {
+    pub fn new(code: MediaErrorCode, error: &str) -> Self {
         Self {
-            error,
-            ffmpeg_error,
+            code,
+            error: error.to_string(),
+            ffmpeg_error: None,
+        }
+    }
+
+    pub fn ffmpeg(error: &str, stderr: Vec<u8>) -> Self {
+        Self {
+            code: MediaErrorCode::CorruptInput,
+            error: error.to_string(),
+            ffmpeg_error: Some(String::from_utf8_lossy(&stderr).into_owned()),
         }
     }
 }
 
-impl From<&str> for MediaError {
-    fn from(value: &str) -> Self {
+pub struct ProcessLimits {
+    pub timeout: Duration,
+    pub cpu_seconds: u64,
+    pub address_space: u64,
+    pub file_size: u64,
+    pub max_duration_ms: i64,
+    pub max_resolution: u64,
+}
+
+impl ProcessLimits {
+    pub fn from_env() -> Self {
+        let limit = |name: &str, default: u64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
         Self {
-            error: value.to_string(),
-            ffmpeg_error: None,
+            timeout: Duration::from_secs(limit("MEDIA_TIMEOUT_SECONDS", 10 * 60)),
+            cpu_seconds: limit("MEDIA_CPU_SECONDS", 20 * 60),
+            address_space: limit("MEDIA_ADDRESS_SPACE", 4 * 1024 * 1024 * 1024),
+            file_size: limit("MEDIA_OUTPUT_SIZE", 1024 * 1024 * 1024),
+            max_duration_ms: limit("MEDIA_MAX_DURATION_SECONDS", 60 * 60) as i64 * 1000,
+            max_resolution: limit("MEDIA_MAX_RESOLUTION", 8192),
         }
     }
 }
 
-async fn ffprobe(input_path: &PathBuf) -> Result<FfprobeResult, MediaError> {
-    let p = Command::new("ffprobe")
-        .args(&[
-            "-v",
-            "error",
-            "-output_format",
-            "json",
-            "-show_streams",
-            "-show_format",
-            input_path.to_str().unwrap(),
-        ])
+async fn run_limited(
+    mut command: Command,
+    limits: &ProcessLimits,
+    spawn_error: &str,
+) -> Result<Output, MediaError> {
+    command
+        .stdin(Stdio::null())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    #[cfg(unix)]
+    {
+        let (cpu_seconds, address_space, file_size) =
+            (limits.cpu_seconds, limits.address_space, limits.file_size);
+        unsafe {
+            command.pre_exec(move || {
+                let limit = |value: u64| libc::rlimit {
+                    rlim_cur: value as libc::rlim_t,
+                    rlim_max: value as libc::rlim_t,
+                };
+                if libc::setrlimit(libc::RLIMIT_CPU, &limit(cpu_seconds)) != 0
+                    || libc::setrlimit(libc::RLIMIT_AS, &limit(address_space)) != 0
+                    || libc::setrlimit(libc::RLIMIT_FSIZE, &limit(file_size)) != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    let child = command
         .spawn()
-        .map_err(|_| MediaError::from("cannot create ffprobe process"))?;
+        .map_err(|_| MediaError::new(MediaErrorCode::Internal, spawn_error))?;
+    match tokio::time::timeout(limits.timeout, child.wait_with_output()).await {
+        Ok(output) => output.map_err(|_| MediaError::new(MediaErrorCode::Internal, spawn_error)),
+        Err(_) => Err(MediaError::new(
+            MediaErrorCode::Timeout,
+            "media processing timed out",
+        )),
+    }
+}
 
-    let out = p
-        .wait_with_output()
-        .await
-        .map_err(|_| MediaError::from("cannot create ffprobe process"))?;
+fn check_probe(probe: &FfprobeResult, limits: &ProcessLimits) -> Result<(), MediaError> {
+    if probe
+        .format
+        .duration_ms()
+        .is_some_and(|d| d > limits.max_duration_ms)
+    {
+        return Err(MediaError::new(
+            MediaErrorCode::TooLong,
+            "media is too long",
+        ));
+    }
+    if probe.streams.iter().any(|s| {
+        s.width.is_some_and(|w| w > limits.max_resolution)
+            || s.height.is_some_and(|h| h > limits.max_resolution)
+    }) {
+        return Err(MediaError::new(
+            MediaErrorCode::TooLarge,
+            "media resolution is too high",
+        ));
+    }
+    Ok(())
+}
 
-    serde_json::from_str::<FfprobeResult>(
-        &String::from_utf8(out.stdout)
-            .map_err(|_| MediaError::from("cannot parse ffprobe data"))?,
-    )
-    .map_err(|e| MediaError::from(e.to_string().as_str()))
+async fn ffprobe(
+    limits: &ProcessLimits,
+    input_path: &PathBuf,
+) -> Result<FfprobeResult, MediaError> {
+    let mut command = Command::new("ffprobe");
+    command.args([
+        "-v",
+        "error",
+        "-protocol_whitelist",
+        "file",
+        "-output_format",
+        "json",
+        "-show_streams",
+        "-show_format",
+        input_path.to_str().unwrap(),
+    ]);
+    let out = run_limited(command, limits, FFPROBE_PROCESS_ERROR).await?;
+
+    let probe =
+        serde_json::from_str::<FfprobeResult>(&String::from_utf8(out.stdout).map_err(|_| {
+            MediaError::new(MediaErrorCode::CorruptInput, "cannot parse ffprobe data")
+        })?)
+        .map_err(|e| MediaError::new(MediaErrorCode::CorruptInput, &e.to_string()))?;
+    check_probe(&probe, limits)?;
+    Ok(probe)
+}
+
+impl FfprobeFormat {
+    pub fn duration_ms(&self) -> Option<i64> {
+        self.duration
+            .as_deref()
+            .and_then(|d| d.parse::<f64>().ok())
+            .map(|d| (d * 1000.0) as i64)
+    }
 }
 
 async fn ffmpeg(
+    limits: &ProcessLimits,
     input_path: &PathBuf,
     output_path: &PathBuf,
     input_args: &[&str],
+) -> Result<Output, MediaError> {
+    ffmpeg_with_options(limits, &[], input_path, output_path, input_args).await
+}
+
+async fn ffmpeg_with_options(
+    limits: &ProcessLimits,
+    input_options: &[&str],
+    input_path: &Path,
+    output_path: &Path,
+    input_args: &[&str],
 ) -> Result<Output, MediaError> {
     let args = [
+        &["-nostdin", "-protocol_whitelist", "file"],
+        input_options,
         &["-i", input_path.to_str().unwrap(), "-map_metadata", "-1"],
         input_args,
         &[output_path.to_str().unwrap()],
     ]
     .concat();
 
-    let p = Command::new("ffmpeg")
-        .args(&args)
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|_| MediaError::from("cannot create ffmpeg process"))?;
+    let mut command = Command::new("ffmpeg");
+    command.args(&args);
+    run_limited(command, limits, FFMPEG_PROCESS_ERROR).await
+}
 
-    p.wait_with_output()
+async fn blurhash(
+    limits: &ProcessLimits,
+    input_path: &PathBuf,
+    output_path: &PathBuf,
+) -> Result<String, MediaError> {
+    let output = ffmpeg(
+        limits,
+        input_path,
+        output_path,
+        &[
+            "-frames:v",
+            "1",
+            "-vf",
+            "scale=32:32",
+            "-f",
+            "rawvideo",
+            "-pix_fmt",
+            "rgba",
+        ],
+    )
+    .await?;
+    if !output.status.success() {
+        return Err(MediaError::ffmpeg("cannot process blurhash", output.stderr));
+    }
+
+    let pixels = fs::read(output_path)
         .await
-        .map_err(|_| MediaError::from("cannot create ffmpeg process"))
+        .map_err(|_| MediaError::new(MediaErrorCode::Internal, "cannot read blurhash pixels"))?;
+    blurhash::encode(4, 4, 32, 32, &pixels)
+        .map_err(|_| MediaError::new(MediaErrorCode::Internal, "cannot encode blurhash"))
+}
+
+pub async fn content_hash(input: &Path, key: &str) -> std::io::Result<String> {
+    let mut file = fs::File::open(input).await?;
+    let mut hasher = Sha256::new();
+    hasher.update(key.as_bytes());
+    hasher.update([0]);
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub fn waveform_peaks(samples: &[i16], buckets: usize) -> Vec<u8> {
+    if samples.is_empty() {
+        return vec![0; buckets];
+    }
+    let peaks = (0..buckets)
+        .map(|i| {
+            let from = i * samples.len() / buckets;
+            let to = ((i + 1) * samples.len() / buckets).max(from + 1);
+            samples[from..to.min(samples.len())]
+                .iter()
+                .map(|s| s.unsigned_abs())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<_>>();
+    let max = peaks.iter().copied().max().unwrap_or(0).max(1) as u32;
+    peaks
+        .into_iter()
+        .map(|p| (p as u32 * 255 / max) as u8)
+        .collect()
 }
 
-pub async fn process_photo(input: Vec<u8>) -> Result<PhotoResult, MediaError> {
-    let temp_dir = tempfile::tempdir().map_err(|_| MediaError::from("cannot create temp dir"))?;
+pub fn jpeg_exif_orientation(data: &[u8]) -> Option<u16> {
+    if data.get(..2)? != [0xFF, 0xD8] {
+        return None;
+    }
+
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            return None;
+        }
+        let marker = data[pos + 1];
+        if marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+        let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let segment = data.get(pos + 4..pos + 2 + length)?;
+        if marker == 0xE1 && segment.starts_with(b"Exif\0\0") {
+            let tiff = &segment[6..];
+            let read_u16 = |offset: usize| -> Option<u16> {
+                let bytes = [*tiff.get(offset)?, *tiff.get(offset + 1)?];
+                match tiff.get(..2)? {
+                    b"II" => Some(u16::from_le_bytes(bytes)),
+                    b"MM" => Some(u16::from_be_bytes(bytes)),
+                    _ => None,
+                }
+            };
+            let read_u32 = |offset: usize| -> Option<u32> {
+                let first = read_u16(offset)? as u32;
+                let second = read_u16(offset + 2)? as u32;
+                match tiff.get(..2)? {
+                    b"II" => Some(second << 16 | first),
+                    _ => Some(first << 16 | second),
+                }
+            };
+            let ifd = read_u32(4)? as usize;
+            let entries = read_u16(ifd)? as usize;
+            for i in 0..entries {
+                let entry = ifd + 2 + i * 12;
+                if read_u16(entry)? == 0x0112 {
+                    return read_u16(entry + 8).filter(|o| (1..=8).contains(o));
+                }
+            }
+            return None;
+        }
+        pos += 2 + length;
+    }
+
+    None
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CropRect {
+    pub x: u64,
+    pub y: u64,
+    pub width: u64,
+    pub height: u64,
+}
+
+#[derive(Default)]
+pub struct PhotoOptions {
+    pub crop: Option<CropRect>,
+    pub focus: Option<(f64, f64)>,
+    pub aspect_ratio: Option<(u64, u64)>,
+}
+
+pub fn photo_crop(
+    width: u64,
+    height: u64,
+    options: &PhotoOptions,
+) -> Result<(CropRect, Option<(f64, f64)>), MediaError> {
+    let mut crop = options.crop.unwrap_or(CropRect {
+        x: 0,
+        y: 0,
+        width,
+        height,
+    });
+    if crop.width == 0
+        || crop.height == 0
+        || crop.x.checked_add(crop.width).is_none_or(|end| end > width)
+        || crop
+            .y
+            .checked_add(crop.height)
+            .is_none_or(|end| end > height)
+    {
+        return Err(MediaError::new(
+            MediaErrorCode::InvalidCrop,
+            "invalid crop rectangle",
+        ));
+    }
+
+    let (focus_x, focus_y) = options
+        .focus
+        .map(|(x, y)| {
+            (
+                x.clamp(0.0, 1.0) * width as f64,
+                y.clamp(0.0, 1.0) * height as f64,
+            )
+        })
+        .unwrap_or((
+            crop.x as f64 + crop.width as f64 / 2.0,
+            crop.y as f64 + crop.height as f64 / 2.0,
+        ));
+
+    if let Some((ratio_width, ratio_height)) = options.aspect_ratio {
+        if crop.width * ratio_height > crop.height * ratio_width {
+            let new_width = (crop.height * ratio_width / ratio_height).max(1);
+            let offset = (focus_x - crop.x as f64 - new_width as f64 / 2.0)
+                .clamp(0.0, (crop.width - new_width) as f64);
+            crop.x += offset as u64;
+            crop.width = new_width;
+        } else {
+            let new_height = (crop.width * ratio_height / ratio_width).max(1);
+            let offset = (focus_y - crop.y as f64 - new_height as f64 / 2.0)
+                .clamp(0.0, (crop.height - new_height) as f64);
+            crop.y += offset as u64;
+            crop.height = new_height;
+        }
+    }
+
+    let focus = options.focus.map(|_| {
+        (
+            ((focus_x - crop.x as f64) / crop.width as f64).clamp(0.0, 1.0),
+            ((focus_y - crop.y as f64) / crop.height as f64).clamp(0.0, 1.0),
+        )
+    });
+
+    Ok((crop, focus))
+}
+
+pub async fn process_photo(
+    limits: &ProcessLimits,
+    input: &Path,
+    options: PhotoOptions,
+) -> Result<PhotoResult, MediaError> {
+    let temp_dir = tempfile::tempdir()
+        .map_err(|_| MediaError::new(MediaErrorCode::Internal, "cannot create temp dir"))?;
     let input_path_bin = temp_dir.path().join("input.bin");
 
-    fs::write(&input_path_bin, input)
+    let mut header = vec![];
+    fs::File::open(input)
         .await
-        .map_err(|_| MediaError::from("cannot write to input file"))?;
+        .map_err(|_| MediaError::new(MediaErrorCode::Internal, "cannot read input file"))?
+        .take(64 * 1024)
+        .read_to_end(&mut header)
+        .await
+        .map_err(|_| MediaError::new(MediaErrorCode::Internal, "cannot read input file"))?;
+    let orientation = jpeg_exif_orientation(&header).unwrap_or(1);
+
+    fs::copy(input, &input_path_bin)
+        .await
+        .map_err(|_| MediaError::new(MediaErrorCode::Internal, "cannot write to input file"))?;
 
-    let probe_result = ffprobe(&input_path_bin).await?;
+    let probe_result = ffprobe(limits, &input_path_bin).await?;
     if probe_result.format.nb_streams == 0 || probe_result.format.nb_streams > 1 {
-        return Err(MediaError::from("invalid stream count"));
+        return Err(MediaError::new(
+            MediaErrorCode::TooManyStreams,
+            "invalid stream count",
+        ));
     }
 
     let stream = &probe_result.streams[0];
@@ -144,91 +620,144 @@ pub async fn process_photo(input: Vec<u8>) -> Result<PhotoResult, MediaError> {
         "mjpeg" => temp_dir.path().join("input.jpg"),
         "png" => temp_dir.path().join("input.png"),
         "webp" => temp_dir.path().join("input.webp"),
-        _ => return Err(MediaError::from("unsupported codec")),
+        _ => {
+            return Err(MediaError::new(
+                MediaErrorCode::UnsupportedContainer,
+                "unsupported codec",
+            ));
+        }
     };
 
     fs::rename(input_path_bin, &input_path)
         .await
-        .map_err(|_| MediaError::from("cannot write to input file"))?;
+        .map_err(|_| MediaError::new(MediaErrorCode::Internal, "cannot write to input file"))?;
+
+    let (width, height) = match orientation {
+        5..=8 => (stream.height.unwrap(), stream.width.unwrap()),
+        _ => (stream.width.unwrap(), stream.height.unwrap()),
+    };
+    let (crop, focus) = photo_crop(width, height, &options)?;
+    let mut transform = match orientation {
+        2 => "hflip,",
+        3 => "hflip,vflip,",
+        4 => "vflip,",
+        5 => "transpose=0,",
+        6 => "transpose=1,",
+        7 => "transpose=3,",
+        8 => "transpose=2,",
+        _ => "",
+    }
+    .to_string();
+    if crop.width != width || crop.height != height {
+        transform += &format!("crop={}:{}:{}:{},", crop.width, crop.height, crop.x, crop.y);
+    }
 
     let output_small_path = temp_dir.path().join("output_small.jpg");
     let output_medium_path = temp_dir.path().join("output_medium.jpg");
     let output_large_path = temp_dir.path().join("output_large.jpg");
+    let output_blurhash_path = temp_dir.path().join("output_blurhash.rgba");
 
-    let output = ffmpeg(&input_path, &output_small_path, &[
-        "-vf",
-        "scale='min(512,iw)':'min(512,ih)':force_original_aspect_ratio=decrease",
-    ])
+    let output = ffmpeg_with_options(
+        limits,
+        &["-noautorotate"],
+        &input_path,
+        &output_small_path,
+        &[
+            "-vf",
+            &format!(
+                "{transform}scale='min(512,iw)':'min(512,ih)':force_original_aspect_ratio=decrease"
+            ),
+        ],
+    )
     .await?;
     if !output.status.success() {
-        return Err(MediaError::new(
-            "cannot process small photo".into(),
-            Some(String::from_utf8(output.stderr).unwrap()),
+        return Err(MediaError::ffmpeg(
+            "cannot process small photo",
+            output.stderr,
         ));
     }
 
-    if stream.width.unwrap() > 768 || stream.height.unwrap() > 768 {
-        let output = ffmpeg(&input_path, &output_medium_path, &[
+    if crop.width > 768 || crop.height > 768 {
+        let output = ffmpeg_with_options(limits, &["-noautorotate"], &input_path, &output_medium_path, &[
             "-vf",
-            "scale='min(max(768,iw), 1024)':'min(max(768,ih), 1024)':force_original_aspect_ratio=decrease",
+            &format!("{transform}scale='min(max(768,iw), 1024)':'min(max(768,ih), 1024)':force_original_aspect_ratio=decrease"),
         ])
         .await?;
         if !output.status.success() {
-            return Err(MediaError::new(
-                "cannot process medium photo".into(),
-                Some(String::from_utf8(output.stderr).unwrap()),
+            return Err(MediaError::ffmpeg(
+                "cannot process medium photo",
+                output.stderr,
             ));
         }
     }
 
-    if stream.width.unwrap() > 2048 || stream.height.unwrap() > 2048 {
-        let output = ffmpeg(&input_path, &output_large_path, &[
+    if crop.width > 2048 || crop.height > 2048 {
+        let output = ffmpeg_with_options(limits, &["-noautorotate"], &input_path, &output_large_path, &[
             "-vf",
-            "scale='min(2048,iw)':'min(2048,ih)':force_original_aspect_ratio=decrease",
+            &format!("{transform}scale='min(2048,iw)':'min(2048,ih)':force_original_aspect_ratio=decrease"),
         ])
         .await?;
         if !output.status.success() {
-            return Err(MediaError::new(
-                "cannot process large photo".into(),
-                Some(String::from_utf8(output.stderr).unwrap()),
+            return Err(MediaError::ffmpeg(
+                "cannot process large photo",
+                output.stderr,
             ));
         }
     }
 
+    let blurhash = blurhash(limits, &output_small_path, &output_blurhash_path).await?;
+
     Ok(PhotoResult {
+        width: crop.width as i64,
+        height: crop.height as i64,
+        focus,
+        blurhash,
         jpg_small: fs::read(output_small_path).await.unwrap(),
         jpg_medium: fs::read(output_medium_path).await.ok(),
         jpg_large: fs::read(output_large_path).await.ok(),
     })
 }
 
-pub async fn process_video(input: Vec<u8>) -> Result<VideoResult, MediaError> {
-    let temp_dir = tempfile::tempdir().map_err(|_| MediaError::from("cannot create temp dir"))?;
+pub async fn process_video(
+    limits: &ProcessLimits,
+    input: &Path,
+) -> Result<VideoResult, MediaError> {
+    let temp_dir = tempfile::tempdir()
+        .map_err(|_| MediaError::new(MediaErrorCode::Internal, "cannot create temp dir"))?;
     let input_path_bin = temp_dir.path().join("input.bin");
 
-    fs::write(&input_path_bin, input)
+    fs::copy(input, &input_path_bin)
         .await
-        .map_err(|_| MediaError::from("cannot write to input file"))?;
+        .map_err(|_| MediaError::new(MediaErrorCode::Internal, "cannot write to input file"))?;
 
-    let probe_result = ffprobe(&input_path_bin).await?;
+    let probe_result = ffprobe(limits, &input_path_bin).await?;
     if probe_result.format.nb_streams == 0 {
-        return Err(MediaError::from("invalid stream count"));
+        return Err(MediaError::new(
+            MediaErrorCode::TooManyStreams,
+            "invalid stream count",
+        ));
     }
 
     let input_path = match probe_result.format.format_name.as_str() {
         "mov,mp4,m4a,3gp,3g2,mj2" => temp_dir.path().join("input.mp4"),
         "matroska,webm" => temp_dir.path().join("input.webm"),
-        _ => return Err(MediaError::from("unsupported codec")),
+        _ => {
+            return Err(MediaError::new(
+                MediaErrorCode::UnsupportedContainer,
+                "unsupported codec",
+            ));
+        }
     };
 
     fs::rename(input_path_bin, &input_path)
         .await
-        .map_err(|_| MediaError::from("cannot write to input file"))?;
+        .map_err(|_| MediaError::new(MediaErrorCode::Internal, "cannot write to input file"))?;
 
     let output_thumbnail_path = temp_dir.path().join("output.jpg");
     let output_mp4_480p_path = temp_dir.path().join("output.mp4");
+    let output_blurhash_path = temp_dir.path().join("output_blurhash.rgba");
 
-    let output = ffmpeg(&input_path, &output_thumbnail_path, &[
+    let output = ffmpeg(limits, &input_path, &output_thumbnail_path, &[
         "-update",
         "true",
         "-frames:v",
@@ -238,13 +767,13 @@ pub async fn process_video(input: Vec<u8>) -> Result<VideoResult, MediaError> {
     ])
     .await?;
     if !output.status.success() {
-        return Err(MediaError::new(
-            "cannot process thumbnail photo".into(),
-            Some(String::from_utf8(output.stderr).unwrap()),
+        return Err(MediaError::ffmpeg(
+            "cannot process thumbnail photo",
+            output.stderr,
         ));
     }
 
-    let output = ffmpeg(&input_path, &output_mp4_480p_path, &[
+    let output = ffmpeg(limits, &input_path, &output_mp4_480p_path, &[
         "-map",
         "0:a:0",
         "-map",
@@ -260,93 +789,292 @@ pub async fn process_video(input: Vec<u8>) -> Result<VideoResult, MediaError> {
     ])
     .await?;
     if !output.status.success() {
-        return Err(MediaError::new(
-            "cannot process 480p video".into(),
-            Some(String::from_utf8(output.stderr).unwrap()),
+        return Err(MediaError::ffmpeg(
+            "cannot process 480p video",
+            output.stderr,
         ));
     }
 
+    let blurhash = blurhash(limits, &output_thumbnail_path, &output_blurhash_path).await?;
+    let stream = probe_result.streams.iter().find(|s| s.width.is_some());
+
     Ok(VideoResult {
+        width: stream.and_then(|s| s.width).map(|w| w as i64),
+        height: stream.and_then(|s| s.height).map(|h| h as i64),
+        duration_ms: probe_result.format.duration_ms(),
+        blurhash,
         thumbnail: fs::read(output_thumbnail_path).await.unwrap(),
         mp4_480p: fs::read(output_mp4_480p_path).await.unwrap(),
     })
 }
 
-pub async fn process_audio(input: Vec<u8>) -> Result<AudioResult, MediaError> {
-    let temp_dir = tempfile::tempdir().map_err(|_| MediaError::from("cannot create temp dir"))?;
+pub async fn process_animation(
+    limits: &ProcessLimits,
+    input: &Path,
+) -> Result<AnimationResult, MediaError> {
+    let temp_dir = tempfile::tempdir()
+        .map_err(|_| MediaError::new(MediaErrorCode::Internal, "cannot create temp dir"))?;
     let input_path_bin = temp_dir.path().join("input.bin");
 
-    fs::write(&input_path_bin, input)
+    fs::copy(input, &input_path_bin)
         .await
-        .map_err(|_| MediaError::from("cannot write to input file"))?;
+        .map_err(|_| MediaError::new(MediaErrorCode::Internal, "cannot write to input file"))?;
 
-    let probe_result = ffprobe(&input_path_bin).await?;
-    if probe_result.format.nb_streams == 0 {
-        return Err(MediaError::from("invalid stream count"));
+    let probe_result = ffprobe(limits, &input_path_bin).await?;
+    if probe_result.format.nb_streams == 0 || probe_result.format.nb_streams > 1 {
+        return Err(MediaError::new(
+            MediaErrorCode::TooManyStreams,
+            "invalid stream count",
+        ));
     }
 
-    let input_path = match probe_result.format.format_name.as_str() {
-        "mp3" => temp_dir.path().join("input.mp3"),
-        "flac" => temp_dir.path().join("input.flac"),
-        "ogg" => temp_dir.path().join("input.ogg"),
-        "mov,mp4,m4a,3gp,3g2,mj2" => temp_dir.path().join("input.m4a"),
-        _ => return Err(MediaError::from("unsupported codec")),
+    let input_path = match probe_result.streams[0].codec_name.as_str() {
+        "gif" => temp_dir.path().join("input.gif"),
+        "apng" => temp_dir.path().join("input.apng"),
+        "webp" => temp_dir.path().join("input.webp"),
+        _ => {
+            return Err(MediaError::new(
+                MediaErrorCode::UnsupportedContainer,
+                "unsupported codec",
+            ));
+        }
     };
 
     fs::rename(input_path_bin, &input_path)
         .await
-        .map_err(|_| MediaError::from("cannot write to input file"))?;
+        .map_err(|_| MediaError::new(MediaErrorCode::Internal, "cannot write to input file"))?;
 
-    let output_mp3_128k_path = temp_dir.path().join("output.mp3");
     let output_thumbnail_path = temp_dir.path().join("output.jpg");
+    let output_mp4_480p_path = temp_dir.path().join("output.mp4");
+    let output_blurhash_path = temp_dir.path().join("output_blurhash.rgba");
 
-    let output = ffmpeg(&input_path, &output_mp3_128k_path, &[
-        "-vn", "-map", "0:0", "-b:a", "128k",
+    let output = ffmpeg(limits, &input_path, &output_thumbnail_path, &[
+        "-update",
+        "true",
+        "-frames:v",
+        "1",
+        "-vf",
+        "scale='min(854,iw)':'min(854,ih)':force_original_aspect_ratio=decrease:force_divisible_by=2",
     ])
     .await?;
     if !output.status.success() {
+        return Err(MediaError::ffmpeg(
+            "cannot process thumbnail photo",
+            output.stderr,
+        ));
+    }
+
+    let output = ffmpeg(limits, &input_path, &output_mp4_480p_path, &[
+        "-an",
+        "-movflags",
+        "+faststart",
+        "-pix_fmt",
+        "yuv420p",
+        "-crf",
+        "28",
+        "-preset",
+        "veryfast",
+        "-vf",
+        "scale='min(854,iw)':'min(854,ih)':force_original_aspect_ratio=decrease:force_divisible_by=2",
+    ])
+    .await?;
+    if !output.status.success() {
+        return Err(MediaError::ffmpeg(
+            "cannot process 480p animation",
+            output.stderr,
+        ));
+    }
+
+    let blurhash = blurhash(limits, &output_thumbnail_path, &output_blurhash_path).await?;
+
+    Ok(AnimationResult {
+        width: probe_result.streams[0].width.map(|w| w as i64),
+        height: probe_result.streams[0].height.map(|h| h as i64),
+        duration_ms: probe_result.format.duration_ms(),
+        blurhash,
+        thumbnail: fs::read(output_thumbnail_path).await.unwrap(),
+        mp4_480p: fs::read(output_mp4_480p_path).await.unwrap(),
+    })
+}
+
+pub async fn process_audio(
+    limits: &ProcessLimits,
+    input: &Path,
+) -> Result<AudioResult, MediaError> {
+    process_audio_file(limits, input, false).await
+}
+
+pub async fn process_voice(
+    limits: &ProcessLimits,
+    input: &Path,
+) -> Result<AudioResult, MediaError> {
+    process_audio_file(limits, input, true).await
+}
+
+async fn process_audio_file(
+    limits: &ProcessLimits,
+    input: &Path,
+    voice: bool,
+) -> Result<AudioResult, MediaError> {
+    let temp_dir = tempfile::tempdir()
+        .map_err(|_| MediaError::new(MediaErrorCode::Internal, "cannot create temp dir"))?;
+    let input_path_bin = temp_dir.path().join("input.bin");
+
+    fs::copy(input, &input_path_bin)
+        .await
+        .map_err(|_| MediaError::new(MediaErrorCode::Internal, "cannot write to input file"))?;
+
+    let probe_result = ffprobe(limits, &input_path_bin).await?;
+    if probe_result.format.nb_streams == 0 {
         return Err(MediaError::new(
-            "cannot process 128k audio".into(),
-            Some(String::from_utf8(output.stderr).unwrap()),
+            MediaErrorCode::TooManyStreams,
+            "invalid stream count",
         ));
     }
 
-    if probe_result.format.nb_streams == 2 {
-        let output = ffmpeg(&input_path, &output_thumbnail_path, &[
-            "-an",
+    let input_path = match (probe_result.format.format_name.as_str(), voice) {
+        ("mp3", false) => temp_dir.path().join("input.mp3"),
+        ("flac", false) => temp_dir.path().join("input.flac"),
+        ("ogg", _) => temp_dir.path().join("input.ogg"),
+        ("mov,mp4,m4a,3gp,3g2,mj2", false) => temp_dir.path().join("input.m4a"),
+        ("matroska,webm", true) => temp_dir.path().join("input.webm"),
+        ("wav", true) => temp_dir.path().join("input.wav"),
+        _ => {
+            return Err(MediaError::new(
+                MediaErrorCode::UnsupportedContainer,
+                "unsupported codec",
+            ));
+        }
+    };
+
+    fs::rename(input_path_bin, &input_path)
+        .await
+        .map_err(|_| MediaError::new(MediaErrorCode::Internal, "cannot write to input file"))?;
+
+    let output_mp3_128k_path = temp_dir.path().join("output.mp3");
+    let output_opus_96k_path = temp_dir.path().join("output.ogg");
+    let output_pcm_path = temp_dir.path().join("output.pcm");
+    let output_thumbnail_path = temp_dir.path().join("output.jpg");
+
+    let audio_args: &[&str] = if voice {
+        &[
+            "-vn",
             "-map",
-            "0:1",
-            "-vf",
-            "scale=512:512",
-        ])
+            "0:a:0",
+            "-ac",
+            "1",
+            "-af",
+            "loudnorm=I=-16:TP=-1.5:LRA=11",
+            "-ar",
+            "48000",
+        ]
+    } else {
+        &["-vn", "-map", "0:0"]
+    };
+
+    let output = ffmpeg(
+        limits,
+        &input_path,
+        &output_mp3_128k_path,
+        &[audio_args, &["-b:a", "128k"]].concat(),
+    )
+    .await?;
+    if !output.status.success() {
+        return Err(MediaError::ffmpeg(
+            "cannot process 128k audio",
+            output.stderr,
+        ));
+    }
+
+    let output = ffmpeg(
+        limits,
+        &input_path,
+        &output_opus_96k_path,
+        &[audio_args, &["-c:a", "libopus", "-b:a", "96k"]].concat(),
+    )
+    .await?;
+    if !output.status.success() {
+        return Err(MediaError::ffmpeg("cannot process 96k opus", output.stderr));
+    }
+
+    let output = ffmpeg(
+        limits,
+        &input_path,
+        &output_pcm_path,
+        &[audio_args, &["-ac", "1", "-ar", "8000", "-f", "s16le"]].concat(),
+    )
+    .await?;
+    if !output.status.success() {
+        return Err(MediaError::ffmpeg(
+            "cannot process audio waveform",
+            output.stderr,
+        ));
+    }
+    let samples = fs::read(&output_pcm_path)
+        .await
+        .map_err(|_| MediaError::new(MediaErrorCode::Internal, "cannot read waveform"))?
+        .chunks_exact(2)
+        .map(|s| i16::from_le_bytes([s[0], s[1]]))
+        .collect::<Vec<_>>();
+
+    if !voice && probe_result.format.nb_streams == 2 {
+        let output = ffmpeg(
+            limits,
+            &input_path,
+            &output_thumbnail_path,
+            &["-an", "-map", "0:1", "-vf", "scale=512:512"],
+        )
         .await?;
         if !output.status.success() {
-            return Err(MediaError::new(
-                "cannot process thumbnail audio".into(),
-                Some(String::from_utf8(output.stderr).unwrap()),
+            return Err(MediaError::ffmpeg(
+                "cannot process thumbnail audio",
+                output.stderr,
             ));
         }
     }
 
+    let tags = if voice {
+        None
+    } else {
+        probe_result.format.tags.clone()
+    };
+
     Ok(AudioResult {
-        title: probe_result.format.tags.clone().and_then(|v| v.title),
-        artist: probe_result.format.tags.and_then(|v| v.artist),
+        title: tags.clone().and_then(|v| v.title),
+        artist: tags.and_then(|v| v.artist),
+        duration_ms: probe_result
+            .format
+            .duration_ms()
+            .or(Some(samples.len() as i64 / 8)),
+        waveform: waveform_peaks(&samples, WAVEFORM_BUCKETS),
         thumbnail: fs::read(output_thumbnail_path).await.ok(),
         mp3_128k: fs::read(output_mp3_128k_path).await.unwrap(),
+        opus_96k: fs::read(output_opus_96k_path).await.ok(),
     })
 }
 
 #[cfg(test)]
 mod test {
-    use tokio::fs;
+    use std::{
+        path::Path,
+        time::{Duration, Instant},
+    };
+
+    use tokio::process::Command;
 
     use crate::services::media;
 
     #[tokio::test]
     async fn photo() {
-        let result = media::process_photo(fs::read("testdata/input.png").await.unwrap())
-            .await
-            .unwrap();
+        let result = media::process_photo(
+            &media::ProcessLimits::from_env(),
+            Path::new("testdata/input.png"),
+            media::PhotoOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert!(result.width == 500 && result.height == 500);
+        assert!(!result.blurhash.is_empty());
         assert!(result.jpg_small.len() != 0);
         assert!(
             result
@@ -362,33 +1090,239 @@ mod test {
         );
     }
 
+    #[test]
+    fn exif_orientation() {
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x22];
+        jpeg.extend_from_slice(b"Exif\0\0MM\0\x2a\0\0\0\x08");
+        jpeg.extend_from_slice(&[0x00, 0x01, 0x01, 0x12, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01]);
+        jpeg.extend_from_slice(&[0x00, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        jpeg.extend_from_slice(&[0xFF, 0xDA]);
+        assert!(media::jpeg_exif_orientation(&jpeg) == Some(6));
+        assert!(media::jpeg_exif_orientation(&[0xFF, 0xD8, 0xFF, 0xDA]).is_none());
+        assert!(media::jpeg_exif_orientation(b"\x89PNG").is_none());
+    }
+
+    #[test]
+    fn photo_crop() {
+        let (crop, focus) = media::photo_crop(
+            300,
+            100,
+            &media::PhotoOptions {
+                crop: None,
+                focus: Some((0.9, 0.5)),
+                aspect_ratio: Some((1, 1)),
+            },
+        )
+        .unwrap();
+        assert!(
+            crop == media::CropRect {
+                x: 200,
+                y: 0,
+                width: 100,
+                height: 100
+            }
+        );
+        assert!(focus == Some((0.7, 0.5)));
+
+        let (crop, _) = media::photo_crop(
+            600,
+            600,
+            &media::PhotoOptions {
+                crop: Some(media::CropRect {
+                    x: 0,
+                    y: 300,
+                    width: 600,
+                    height: 300,
+                }),
+                focus: None,
+                aspect_ratio: Some((3, 1)),
+            },
+        )
+        .unwrap();
+        assert!(
+            crop == media::CropRect {
+                x: 0,
+                y: 350,
+                width: 600,
+                height: 200
+            }
+        );
+
+        assert!(
+            media::photo_crop(
+                100,
+                100,
+                &media::PhotoOptions {
+                    crop: Some(media::CropRect {
+                        x: 50,
+                        y: 0,
+                        width: 100,
+                        height: 100,
+                    }),
+                    ..Default::default()
+                }
+            )
+            .is_err()
+        );
+
+        for crop in [
+            media::CropRect {
+                x: u64::MAX,
+                y: 0,
+                width: 1,
+                height: 100,
+            },
+            media::CropRect {
+                x: 0,
+                y: 1,
+                width: 100,
+                height: u64::MAX,
+            },
+        ] {
+            assert!(
+                media::photo_crop(
+                    100,
+                    100,
+                    &media::PhotoOptions {
+                        crop: Some(crop),
+                        ..Default::default()
+                    }
+                )
+                .is_err()
+            );
+        }
+    }
+
     #[tokio::test]
     async fn video() {
-        let result = media::process_video(fs::read("testdata/input.mp4").await.unwrap())
-            .await
-            .unwrap();
+        let result = media::process_video(
+            &media::ProcessLimits::from_env(),
+            Path::new("testdata/input.mp4"),
+        )
+        .await
+        .unwrap();
         assert!(result.thumbnail.len() != 0);
         assert!(result.mp4_480p.len() != 0);
     }
 
     #[tokio::test]
     async fn video_bad_scaling() {
-        let result =
-            media::process_video(fs::read("testdata/input_bad_scaling.mp4").await.unwrap())
-                .await
-                .unwrap();
+        let result = media::process_video(
+            &media::ProcessLimits::from_env(),
+            Path::new("testdata/input_bad_scaling.mp4"),
+        )
+        .await
+        .unwrap();
         assert!(result.thumbnail.len() != 0);
         assert!(result.mp4_480p.len() != 0);
     }
 
+    #[tokio::test]
+    async fn animation() {
+        let result = media::process_animation(
+            &media::ProcessLimits::from_env(),
+            Path::new("testdata/input.gif"),
+        )
+        .await
+        .unwrap();
+        assert!(result.width == Some(16) && result.height == Some(16));
+        assert!(!result.blurhash.is_empty());
+        assert!(!result.thumbnail.is_empty());
+        assert!(!result.mp4_480p.is_empty());
+    }
+
     #[tokio::test]
     async fn audio() {
-        let result = media::process_audio(fs::read("testdata/input.mp3").await.unwrap())
-            .await
-            .unwrap();
+        let result = media::process_audio(
+            &media::ProcessLimits::from_env(),
+            Path::new("testdata/input.mp3"),
+        )
+        .await
+        .unwrap();
         assert!(result.title.unwrap() == "Мой байк");
         assert!(result.artist.unwrap() == "Серега Пират");
         assert!(result.mp3_128k.len() != 0);
         assert!(result.thumbnail.unwrap().len() != 0);
+        assert!(result.duration_ms.unwrap() > 0);
+        assert!(result.waveform.len() == media::WAVEFORM_BUCKETS);
+    }
+
+    #[tokio::test]
+    async fn process_timeout() {
+        let mut limits = media::ProcessLimits::from_env();
+        limits.timeout = Duration::from_millis(100);
+        let mut command = Command::new("sleep");
+        command.arg("5");
+        let start = Instant::now();
+        let error = media::run_limited(command, &limits, media::FFMPEG_PROCESS_ERROR)
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, media::MediaErrorCode::Timeout);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn process_spawn_error() {
+        let limits = media::ProcessLimits::from_env();
+        let command = Command::new("/nonexistent/ffprobe");
+        let error = media::run_limited(command, &limits, media::FFPROBE_PROCESS_ERROR)
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, media::MediaErrorCode::Internal);
+        assert_eq!(error.error, media::FFPROBE_PROCESS_ERROR);
+    }
+
+    #[test]
+    fn probe_limits() {
+        let limits = media::ProcessLimits::from_env();
+        let probe = |duration: &str, width: u64| media::FfprobeResult {
+            streams: vec![media::FfprobeStream {
+                codec_name: "h264".to_string(),
+                width: Some(width),
+                height: Some(720),
+            }],
+            format: media::FfprobeFormat {
+                format_name: "mov,mp4,m4a,3gp,3g2,mj2".to_string(),
+                nb_streams: 1,
+                duration: Some(duration.to_string()),
+                tags: None,
+            },
+        };
+        assert!(media::check_probe(&probe("10.5", 1280), &limits).is_ok());
+        assert_eq!(
+            media::check_probe(&probe("100000", 1280), &limits)
+                .unwrap_err()
+                .code,
+            media::MediaErrorCode::TooLong
+        );
+        assert_eq!(
+            media::check_probe(&probe("10.5", 100000), &limits)
+                .unwrap_err()
+                .code,
+            media::MediaErrorCode::TooLarge
+        );
+    }
+
+    #[tokio::test]
+    async fn voice() {
+        let result = media::process_voice(
+            &media::ProcessLimits::from_env(),
+            Path::new("testdata/input.wav"),
+        )
+        .await
+        .unwrap();
+        assert!(result.title.is_none() && result.artist.is_none());
+        assert!(result.thumbnail.is_none());
+        assert!(!result.mp3_128k.is_empty());
+        assert!(result.duration_ms.unwrap() > 0);
+    }
+
+    #[test]
+    fn waveform_peaks() {
+        let samples = [0i16, 100, -200, 50, 0, 0, -400, 10];
+        let peaks = media::waveform_peaks(&samples, 4);
+        assert_eq!(peaks, vec![63, 127, 0, 255]);
+        assert_eq!(media::waveform_peaks(&[], 3), vec![0, 0, 0]);
+        assert_eq!(media::waveform_peaks(&[1000], 2).len(), 2);
     }
 }
@@ -1 +1,5 @@
+pub mod consistency;
+pub mod gc;
 pub mod media;
+pub mod replicas;
+pub mod unfurl;
@@ -0,0 +1,582 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use axum::http::{Request, StatusCode, header};
+use http_body_util::{BodyExt, Empty, Limited};
+use hyper::body::Bytes;
+use hyper_util::rt::TokioIo;
+use regex::Regex;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    TlsConnector,
+    rustls::{ClientConfig, RootCertStore, pki_types::ServerName},
+};
+use url::Url;
+
+use crate::{
+    SharedState,
+    models::{LinkPreview, Photo, link_preview::LinkPreviewData, photo::PhotoUpdateQuery},
+    services::media::PhotoOptions,
+};
+
+static URL_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"https?://[^\s<>"'`]+"#).unwrap());
+static META_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?is)<meta\s[^>]*>").unwrap());
+static ATTRIBUTE_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?is)([a-z:-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+static TITLE_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap());
+
+const MAX_URL_LENGTH: usize = 2048;
+const MAX_TITLE_LENGTH: usize = 300;
+const MAX_DESCRIPTION_LENGTH: usize = 1000;
+const MAX_SITE_NAME_LENGTH: usize = 100;
+
+pub struct UnfurlConfig {
+    pub timeout: Duration,
+    pub max_page_size: usize,
+    pub max_image_size: usize,
+    pub max_redirects: usize,
+    pub ttl: i64,
+    pub lease: i64,
+    pub allow_private: bool,
+}
+
+impl UnfurlConfig {
+    pub fn from_env() -> Self {
+        let limit = |name: &str, default: u64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+        Self {
+            timeout: Duration::from_secs(limit("LINK_PREVIEW_TIMEOUT_SECONDS", 10)),
+            max_page_size: limit("LINK_PREVIEW_MAX_PAGE_SIZE", 1024 * 1024) as usize,
+            max_image_size: limit("LINK_PREVIEW_MAX_IMAGE_SIZE", 10 * 1024 * 1024) as usize,
+            max_redirects: limit("LINK_PREVIEW_MAX_REDIRECTS", 3) as usize,
+            ttl: limit("LINK_PREVIEW_TTL", 24 * 60 * 60) as i64,
+            lease: limit("LINK_PREVIEW_LEASE_SECONDS", 5 * 60) as i64,
+            allow_private: std::env::var("LINK_PREVIEW_ALLOW_PRIVATE")
+                .is_ok_and(|v| v == "1" || v == "true"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnfurlError {
+    pub error: String,
+}
+
+impl From<&str> for UnfurlError {
+    fn from(value: &str) -> Self {
+        Self {
+            error: value.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct LinkMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub site_name: Option<String>,
+    pub image: Option<String>,
+}
+
+pub fn find_url(message: &str) -> Option<String> {
+    let found = URL_REGEX.find(message)?.as_str();
+    let found = found.trim_end_matches(['.', ',', ')', '!', '?', ';', ':']);
+    if found.len() > MAX_URL_LENGTH {
+        return None;
+    }
+    Url::parse(found).ok().map(String::from)
+}
+
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || a == 0
+                || a >= 240
+                || (a == 100 && (64..128).contains(&b))
+                || (a == 198 && (18..20).contains(&b)))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let segments = ip.segments();
+            let embedded = |high: u16, low: u16| {
+                let [a, b] = high.to_be_bytes();
+                let [c, d] = low.to_be_bytes();
+                IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+            };
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                return is_public(embedded(segments[6], segments[7]));
+            }
+            if segments[0] == 0x2002 {
+                return is_public(embedded(segments[1], segments[2]));
+            }
+            let first = segments[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                || (first & 0xffc0) == 0xfec0
+                || first == 0x2001 && segments[1] == 0xdb8)
+        }
+    }
+}
+
+async fn resolve(url: &Url, config: &UnfurlConfig) -> Result<SocketAddr, UnfurlError> {
+    let host = url.host_str().ok_or(UnfurlError::from("url has no host"))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or(UnfurlError::from("url has no port"))?;
+    let addresses = tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+        .await
+        .map_err(|_| UnfurlError::from("cannot resolve host"))?
+        .collect::<Vec<_>>();
+    if !config.allow_private && addresses.iter().any(|a| !is_public(a.ip())) {
+        return Err(UnfurlError::from("host resolves to a private address"));
+    }
+    addresses
+        .into_iter()
+        .next()
+        .ok_or(UnfurlError::from("cannot resolve host"))
+}
+
+async fn send<T>(
+    io: T,
+    request: Request<Empty<Bytes>>,
+) -> Result<hyper::Response<hyper::body::Incoming>, UnfurlError>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(io))
+        .await
+        .map_err(|_| UnfurlError::from("cannot connect"))?;
+    tokio::spawn(connection);
+    sender
+        .send_request(request)
+        .await
+        .map_err(|_| UnfurlError::from("cannot send request"))
+}
+
+fn tls_connector() -> TlsConnector {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config = ClientConfig::builder_with_provider(Arc::new(
+        tokio_rustls::rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+async fn fetch_once(
+    url: &Url,
+    config: &UnfurlConfig,
+    limit: usize,
+) -> Result<Result<(String, Vec<u8>), Url>, UnfurlError> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(UnfurlError::from("unsupported url scheme"));
+    }
+    let address = resolve(url, config).await?;
+    let stream = TcpStream::connect(address)
+        .await
+        .map_err(|_| UnfurlError::from("cannot connect"))?;
+    let host = url.host_str().unwrap_or_default();
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path = format!("{path}?{query}");
+    }
+    let request = Request::get(path)
+        .header(header::HOST, url.authority())
+        .header(header::USER_AGENT, "rutwt-link-preview")
+        .header(header::ACCEPT, "text/html, image/*")
+        .body(Empty::<Bytes>::new())
+        .map_err(|_| UnfurlError::from("invalid url"))?;
+
+    let response = match url.scheme() {
+        "http" => send(stream, request).await?,
+        _ => {
+            let server_name = ServerName::try_from(host.trim_matches(['[', ']']).to_string())
+                .map_err(|_| UnfurlError::from("invalid host"))?;
+            let stream = tls_connector()
+                .connect(server_name, stream)
+                .await
+                .map_err(|_| UnfurlError::from("tls handshake failed"))?;
+            send(stream, request).await?
+        }
+    };
+
+    if response.status().is_redirection() {
+        let location = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .ok_or(UnfurlError::from("redirect without location"))?;
+        return Ok(Err(url
+            .join(location)
+            .map_err(|_| UnfurlError::from("invalid redirect"))?));
+    }
+    if response.status() != StatusCode::OK {
+        return Err(UnfurlError::from("unexpected status code"));
+    }
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|c| c.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let body = Limited::new(response.into_body(), limit)
+        .collect()
+        .await
+        .map_err(|_| UnfurlError::from("response is too large"))?
+        .to_bytes();
+    Ok(Ok((content_type, body.to_vec())))
+}
+
+pub async fn fetch(
+    url: &str,
+    config: &UnfurlConfig,
+    limit: usize,
+) -> Result<(String, Vec<u8>), UnfurlError> {
+    let mut url = Url::parse(url).map_err(|_| UnfurlError::from("invalid url"))?;
+    let fetch = async {
+        for _ in 0..=config.max_redirects {
+            match fetch_once(&url, config, limit).await? {
+                Ok(response) => return Ok(response),
+                Err(location) => url = location,
+            }
+        }
+        Err(UnfurlError::from("too many redirects"))
+    };
+    tokio::time::timeout(config.timeout, fetch)
+        .await
+        .map_err(|_| UnfurlError::from("request timed out"))?
+}
+
+fn decode_entities(value: &str) -> String {
+    value
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+fn clean(value: &str, max_length: usize) -> Option<String> {
+    let value = decode_entities(value)
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect::<String>();
+    let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+    if value.is_empty() {
+        return None;
+    }
+    Some(value.chars().take(max_length).collect())
+}
+
+pub fn parse_metadata(html: &str, base: &Url) -> LinkMetadata {
+    let mut metadata = LinkMetadata::default();
+    let mut twitter = LinkMetadata::default();
+    let mut description = None;
+    for meta in META_REGEX.find_iter(html) {
+        let mut key = None;
+        let mut content = None;
+        for attribute in ATTRIBUTE_REGEX.captures_iter(meta.as_str()) {
+            let value = attribute
+                .get(2)
+                .or(attribute.get(3))
+                .map_or("", |v| v.as_str());
+            match attribute[1].to_ascii_lowercase().as_str() {
+                "property" | "name" => key = Some(value.to_ascii_lowercase()),
+                "content" => content = Some(value),
+                _ => {}
+            }
+        }
+        let (Some(key), Some(content)) = (key, content) else {
+            continue;
+        };
+        let field = match key.as_str() {
+            "og:title" => &mut metadata.title,
+            "og:description" => &mut metadata.description,
+            "og:site_name" => &mut metadata.site_name,
+            "og:image" | "og:image:url" => &mut metadata.image,
+            "twitter:title" => &mut twitter.title,
+            "twitter:description" => &mut twitter.description,
+            "twitter:image" | "twitter:image:src" => &mut twitter.image,
+            "description" => &mut description,
+            _ => continue,
+        };
+        if field.is_none() {
+            *field = Some(content.to_string());
+        }
+    }
+
+    let title = metadata
+        .title
+        .or(twitter.title)
+        .or_else(|| TITLE_REGEX.captures(html).map(|c| c[1].to_string()));
+    LinkMetadata {
+        title: title.and_then(|t| clean(&t, MAX_TITLE_LENGTH)),
+        description: metadata
+            .description
+            .or(twitter.description)
+            .or(description)
+            .and_then(|d| clean(&d, MAX_DESCRIPTION_LENGTH)),
+        site_name: metadata
+            .site_name
+            .and_then(|s| clean(&s, MAX_SITE_NAME_LENGTH)),
+        image: metadata
+            .image
+            .or(twitter.image)
+            .and_then(|i| base.join(decode_entities(i.trim()).as_str()).ok())
+            .filter(|i| i.scheme() == "http" || i.scheme() == "https")
+            .map(String::from),
+    }
+}
+
+async fn unfurl(
+    state: &SharedState,
+    url: &str,
+    config: &UnfurlConfig,
+) -> Result<LinkPreviewData, UnfurlError> {
+    let (content_type, body) = fetch(url, config, config.max_page_size).await?;
+    if !content_type.starts_with("text/html") {
+        return Err(UnfurlError::from("not a html page"));
+    }
+    let base = Url::parse(url).map_err(|_| UnfurlError::from("invalid url"))?;
+    let metadata = parse_metadata(&String::from_utf8_lossy(&body), &base);
+
+    let mut data = LinkPreviewData {
+        title: metadata.title,
+        description: metadata.description,
+        site_name: metadata.site_name,
+        ..Default::default()
+    };
+    if let Some(image) = metadata.image {
+        match unfurl_image(state, &image, config).await {
+            Ok(photo_id) => data.photo_id = Some(photo_id),
+            Err(e) => println!("LINK PREVIEW IMAGE ERROR: {image} {}", e.error),
+        }
+    }
+    Ok(data)
+}
+
+async fn unfurl_image(
+    state: &SharedState,
+    url: &str,
+    config: &UnfurlConfig,
+) -> Result<i64, UnfurlError> {
+    let (content_type, body) = fetch(url, config, config.max_image_size).await?;
+    if !content_type.starts_with("image/") {
+        return Err(UnfurlError::from("not an image"));
+    }
+    let file =
+        tempfile::NamedTempFile::new().map_err(|_| UnfurlError::from("cannot create temp file"))?;
+    tokio::fs::write(file.path(), &body)
+        .await
+        .map_err(|_| UnfurlError::from("cannot write temp file"))?;
+    let result = state
+        .media
+        .process_photo(file.path(), PhotoOptions::default())
+        .await
+        .map_err(|e| UnfurlError::from(e.code.as_str()))?;
+
+    let id = Photo::insert(&state.rwdb, 0)
+        .await
+        .map_err(|_| UnfurlError::from("cannot insert photo"))?;
+    PhotoUpdateQuery {
+        processing: Some(false),
+        width: Some(result.width),
+        height: Some(result.height),
+        blurhash: Some(result.blurhash),
+        jpg_small: Some(result.jpg_small),
+        jpg_medium: result.jpg_medium,
+        jpg_large: result.jpg_large,
+        ..Default::default()
+    }
+    .update(&state.rwdb, id)
+    .await
+    .map_err(|_| UnfurlError::from("cannot update photo"))?;
+    Ok(id)
+}
+
+pub async fn enqueue(state: Arc<SharedState>, post_id: i64, url: &str) -> Result<(), sqlx::Error> {
+    let now = chrono::Utc::now().timestamp();
+    let claimed = LinkPreview::claim(
+        &state.rwdb,
+        url,
+        now,
+        now - state.unfurl.ttl,
+        now + state.unfurl.lease,
+    )
+    .await?;
+    let id = match claimed {
+        Some(id) => id,
+        None => match LinkPreview::find_id(&state.rwdb, url).await? {
+            Some(id) => id,
+            None => return Ok(()),
+        },
+    };
+    LinkPreview::attach(&state.rwdb, post_id, id).await?;
+
+    if claimed.is_some() {
+        let url = url.to_string();
+        tokio::spawn(async move {
+            let result = unfurl(&state, &url, &state.unfurl).await;
+            let now = chrono::Utc::now().timestamp();
+            let result = match result {
+                Ok(data) => LinkPreview::store(&state.rwdb, id, &data, now).await,
+                Err(e) => {
+                    println!("LINK PREVIEW ERROR: {url} {}", e.error);
+                    LinkPreview::fail(&state.rwdb, id, &e.error, now).await
+                }
+            };
+            if let Err(e) = result {
+                println!("LINK PREVIEW ERROR: {url} {e:?}");
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, SocketAddr};
+
+    use axum::{Router, http::header, response::Redirect, routing::get};
+    use url::Url;
+
+    use crate::services::unfurl::{UnfurlConfig, fetch, find_url, is_public, parse_metadata};
+
+    const PAGE: &str = r#"<html><head>
+        <title>Fallback title</title>
+        <meta property="og:title" content="Hello &amp; welcome">
+        <meta name="description" content="Plain description">
+        <meta property='og:site_name' content='Example'>
+        <meta name="twitter:image" content="/image.png">
+        </head><body></body></html>"#;
+
+    async fn serve() -> SocketAddr {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|| async { ([(header::CONTENT_TYPE, "text/html")], PAGE) }),
+            )
+            .route("/redirect", get(|| async { Redirect::temporary("/") }))
+            .route("/large", get(|| async { "x".repeat(4096) }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        address
+    }
+
+    fn config(allow_private: bool) -> UnfurlConfig {
+        UnfurlConfig {
+            allow_private,
+            max_page_size: 1024,
+            ..UnfurlConfig::from_env()
+        }
+    }
+
+    #[test]
+    fn public_addresses() {
+        for ip in [
+            "1.1.1.1",
+            "2606:4700:4700::1111",
+            "64:ff9b::101:101",
+            "2002:101:101::1",
+        ] {
+            assert!(is_public(ip.parse::<IpAddr>().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::127.0.0.1",
+            "64:ff9b::7f00:1",
+            "64:ff9b::a9fe:a9fe",
+            "2002:7f00:1::1",
+            "2002:a00:1::",
+            "fec0::1",
+        ] {
+            assert!(!is_public(ip.parse::<IpAddr>().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn urls() {
+        assert_eq!(
+            find_url("look at https://example.com/a?b=c, nice").as_deref(),
+            Some("https://example.com/a?b=c")
+        );
+        assert_eq!(find_url("no links here"), None);
+        assert_eq!(find_url("ftp://example.com"), None);
+    }
+
+    #[test]
+    fn metadata() {
+        let base = Url::parse("https://example.com/post/1").unwrap();
+        let metadata = parse_metadata(PAGE, &base);
+        assert_eq!(metadata.title.as_deref(), Some("Hello & welcome"));
+        assert_eq!(metadata.description.as_deref(), Some("Plain description"));
+        assert_eq!(metadata.site_name.as_deref(), Some("Example"));
+        assert_eq!(
+            metadata.image.as_deref(),
+            Some("https://example.com/image.png")
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_page() {
+        let address = serve().await;
+        let (content_type, body) =
+            fetch(&format!("http://{address}/redirect"), &config(true), 1024)
+                .await
+                .unwrap();
+        assert!(content_type.starts_with("text/html"));
+        assert_eq!(body, PAGE.as_bytes());
+
+        let error = fetch(&format!("http://{address}/large"), &config(true), 1024)
+            .await
+            .unwrap_err();
+        assert_eq!(error.error, "response is too large");
+
+        let error = fetch(&format!("http://{address}/"), &config(false), 1024)
+            .await
+            .unwrap_err();
+        assert_eq!(error.error, "host resolves to a private address");
+    }
+}
@@ -1,23 +1,152 @@
 #[cfg(test)]
 pub mod instrumentation {
-    use std::sync::Arc;
+    use std::{
+        path::Path,
+        sync::{
+            Arc,
+            atomic::{AtomicU64, Ordering},
+        },
+        time::Duration,
+    };
 
     use axum::{
         body::Body,
         http::{Request, header},
         response::Response,
     };
+    use futures::future::BoxFuture;
     use http_body_util::BodyExt;
     use serde::{Deserialize, Serialize};
-    use sqlx::sqlite::SqlitePoolOptions;
+    use sqlx::any::AnyPoolOptions;
     use tower::ServiceExt;
 
     use crate::{
         SharedState, app,
-        controllers::auth::{RegisterRequest, UserMixedAuthResponse},
-        models::{ReadOnlyPool, ReadWritePool},
+        controllers::{
+            auth::{RegisterRequest, UserMixedAuthResponse},
+            media::{MediaIdKey, MediaQuota},
+        },
+        models::{DefaultPool, ReadWritePool, migrations},
+        services::{
+            consistency::StickyPrimary,
+            media::{
+                self, AnimationResult, AudioResult, MediaError, MediaErrorCode, MediaProcessor,
+                PhotoOptions, PhotoResult, VideoResult,
+            },
+            replicas::{ReplicaConfig, Replicas},
+            unfurl::UnfurlConfig,
+        },
     };
 
+    pub const FAKE_BLURHASH: &str = "LEHV6nWB2yk8pyo0adR*.7kCMdnj";
+
+    pub struct FakeMediaProcessor;
+
+    async fn read_input(input: &Path) -> Result<Vec<u8>, MediaError> {
+        let data = tokio::fs::read(input)
+            .await
+            .map_err(|_| MediaError::new(MediaErrorCode::Internal, "cannot read input file"))?;
+        if data.starts_with(b"invalid") {
+            return Err(MediaError::new(
+                MediaErrorCode::UnsupportedContainer,
+                "unsupported codec",
+            ));
+        }
+        Ok(data)
+    }
+
+    impl MediaProcessor for FakeMediaProcessor {
+        fn process_photo<'a>(
+            &'a self,
+            input: &'a Path,
+            options: PhotoOptions,
+        ) -> BoxFuture<'a, Result<PhotoResult, MediaError>> {
+            Box::pin(async move {
+                let data = read_input(input).await?;
+                let (crop, focus) = media::photo_crop(640, 480, &options)?;
+                Ok(PhotoResult {
+                    width: crop.width as i64,
+                    height: crop.height as i64,
+                    focus,
+                    blurhash: FAKE_BLURHASH.to_string(),
+                    jpg_small: data,
+                    jpg_medium: None,
+                    jpg_large: None,
+                })
+            })
+        }
+
+        fn process_video<'a>(
+            &'a self,
+            input: &'a Path,
+        ) -> BoxFuture<'a, Result<VideoResult, MediaError>> {
+            Box::pin(async move {
+                let data = read_input(input).await?;
+                Ok(VideoResult {
+                    width: Some(640),
+                    height: Some(360),
+                    duration_ms: Some(1000),
+                    blurhash: FAKE_BLURHASH.to_string(),
+                    thumbnail: b"thumbnail".to_vec(),
+                    mp4_480p: data,
+                })
+            })
+        }
+
+        fn process_animation<'a>(
+            &'a self,
+            input: &'a Path,
+        ) -> BoxFuture<'a, Result<AnimationResult, MediaError>> {
+            Box::pin(async move {
+                let data = read_input(input).await?;
+                Ok(AnimationResult {
+                    width: Some(16),
+                    height: Some(16),
+                    duration_ms: Some(200),
+                    blurhash: FAKE_BLURHASH.to_string(),
+                    thumbnail: b"thumbnail".to_vec(),
+                    mp4_480p: data,
+                })
+            })
+        }
+
+        fn process_audio<'a>(
+            &'a self,
+            input: &'a Path,
+        ) -> BoxFuture<'a, Result<AudioResult, MediaError>> {
+            Box::pin(async move {
+                let data = read_input(input).await?;
+                Ok(AudioResult {
+                    title: Some("title".to_string()),
+                    artist: Some("artist".to_string()),
+                    duration_ms: Some(1000),
+                    waveform: vec![0; media::WAVEFORM_BUCKETS],
+                    thumbnail: None,
+                    mp3_128k: data,
+                    opus_96k: None,
+                })
+            })
+        }
+
+        fn process_voice<'a>(
+            &'a self,
+            input: &'a Path,
+        ) -> BoxFuture<'a, Result<AudioResult, MediaError>> {
+            Box::pin(async move {
+                let data = read_input(input).await?;
+                Ok(AudioResult {
+                    title: None,
+                    artist: None,
+                    duration_ms: Some(1000),
+                    waveform: vec![0; media::WAVEFORM_BUCKETS],
+                    thumbnail: None,
+                    mp3_128k: data,
+                    opus_96k: None,
+                })
+            })
+        }
+    }
+
     pub async fn send_post<T: Serialize>(
         state: Arc<SharedState>,
         uri: &str,
@@ -37,27 +166,114 @@ pub mod instrumentation {
         app(state).oneshot(request).await.unwrap()
     }
 
-    pub async fn init() -> (Arc<SharedState>, String) {
-        unsafe { std::env::set_var("JWT_SECRET", "test") };
+    pub async fn send_get(
+        state: Arc<SharedState>,
+        uri: &str,
+        token: Option<&str>,
+    ) -> Response<Body> {
+        let mut builder = Request::builder().method("GET").uri(uri);
+        if let Some(token) = token {
+            builder = builder.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let request = builder.body(Body::empty()).unwrap();
+        app(state).oneshot(request).await.unwrap()
+    }
+
+    pub async fn send_multipart(
+        state: Arc<SharedState>,
+        uri: &str,
+        token: Option<&str>,
+        fields: &[(&str, &[u8])],
+    ) -> Response<Body> {
+        let boundary = "rutwt-test-boundary";
+        let mut body = Vec::new();
+        for (name, value) in fields {
+            body.extend_from_slice(
+                format!("--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n")
+                    .as_bytes(),
+            );
+            body.extend_from_slice(value);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+
+        let mut builder = Request::builder().method("POST").uri(uri).header(
+            header::CONTENT_TYPE,
+            format!("multipart/form-data; boundary={boundary}"),
+        );
+        if let Some(token) = token {
+            builder = builder.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let request = builder.body(Body::from(body)).unwrap();
+        app(state).oneshot(request).await.unwrap()
+    }
+
+    pub async fn send(state: Arc<SharedState>, request: Request<Body>) -> Response<Body> {
+        app(state).oneshot(request).await.unwrap()
+    }
 
-        let pool = SqlitePoolOptions::new()
+    pub async fn test_pool() -> DefaultPool {
+        static SCHEMA_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        sqlx::any::install_default_drivers();
+        let url = std::env::var("TEST_DATABASE_URL").unwrap_or("sqlite::memory:".to_string());
+        if !url.starts_with("postgres") {
+            return AnyPoolOptions::new()
+                .max_connections(1)
+                .connect_lazy(&url)
+                .unwrap();
+        }
+
+        let schema = format!(
+            "test_{}_{}",
+            chrono::Utc::now().timestamp_micros(),
+            SCHEMA_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let admin = AnyPoolOptions::new()
             .max_connections(1)
-            .connect_lazy("sqlite://")
+            .connect(&url)
+            .await
+            .unwrap();
+        sqlx::query(&format!("CREATE SCHEMA {schema}"))
+            .execute(&admin)
+            .await
             .unwrap();
+        admin.close().await;
+
+        AnyPoolOptions::new()
+            .max_connections(4)
+            .after_connect(move |conn, _| {
+                let schema = schema.clone();
+                Box::pin(async move {
+                    sqlx::query(&format!("SET search_path TO {schema}"))
+                        .execute(conn)
+                        .await?;
+                    Ok(())
+                })
+            })
+            .connect_lazy(&url)
+            .unwrap()
+    }
+
+    pub async fn init() -> (Arc<SharedState>, String) {
+        unsafe { std::env::set_var("JWT_SECRET", "test") };
+
+        let pool = test_pool().await;
         let state = Arc::new(SharedState {
-            db: ReadOnlyPool(pool.clone()),
+            replicas: Arc::new(Replicas::new(
+                pool.clone(),
+                vec![],
+                ReplicaConfig::from_env(),
+            )),
             rwdb: ReadWritePool(pool),
+            media: Arc::new(FakeMediaProcessor),
+            sticky: Arc::new(StickyPrimary::new(Duration::from_secs(5), b"test")),
+            media_ids: Arc::new(MediaIdKey::new(b"test", None)),
+            quota: Arc::new(MediaQuota::from_env()),
+            unfurl: Arc::new(UnfurlConfig::from_env()),
         });
 
-        sqlx::query(include_str!("../data/0000-base-schema.sql"))
-            .execute(&state.db.0)
-            .await
-            .unwrap();
-
-        sqlx::query(include_str!("../data/0001-media-update.sql"))
-            .execute(&state.db.0)
-            .await
-            .unwrap();
+        migrations::migrate(&state.rwdb).await.unwrap();
 
         let response = send_post(
             state.clone(),
@@ -2,45 +2,258 @@ extern crate proc_macro;
 
 use proc_macro2::TokenStream;
 use quote::quote;
-use syn::{self, Data, Fields, FieldsNamed};
+use syn::{self, Data, Fields, FieldsNamed, Ident, LitStr};
+
+const PROGRESS_TABLE: &str = "sqlite2pg_progress";
 
 fn error(span: proc_macro2::Span, message: &'static str) -> proc_macro::TokenStream {
     syn::Error::new(span, message).into_compile_error().into()
 }
 
-#[proc_macro_derive(Migrate, attributes(table, seq_key))]
+#[proc_macro_derive(Migrate, attributes(table, seq_key, migrate))]
 pub fn migrate_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let ast = syn::parse(input).unwrap();
     impl_migrate(&ast)
 }
 
-fn impl_migrate_fields_push(fields: &FieldsNamed) -> proc_macro2::TokenStream {
-    let field_name = fields.named.iter().map(|field| &field.ident);
+struct Column<'a> {
+    field: &'a Ident,
+    name: String,
+    skip: bool,
+    key: bool,
+    seq_key: bool,
+}
+
+fn parse_columns(fields: &FieldsNamed) -> syn::Result<Vec<Column<'_>>> {
+    let mut columns = vec![];
+    for field in &fields.named {
+        let ident = field.ident.as_ref().unwrap();
+        let mut column = Column {
+            field: ident,
+            name: ident.to_string(),
+            skip: false,
+            key: false,
+            seq_key: field.attrs.iter().any(|a| a.path().is_ident("seq_key")),
+        };
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("migrate")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("skip") {
+                    column.skip = true;
+                } else if meta.path.is_ident("key") {
+                    column.key = true;
+                } else if meta.path.is_ident("rename") {
+                    column.name = meta.value()?.parse::<LitStr>()?.value();
+                } else {
+                    return Err(meta.error("expected `skip`, `key` or `rename = \"...\"`"));
+                }
+                Ok(())
+            })?;
+        }
+        if column.skip && (column.key || column.seq_key) {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "skipped fields cannot be keys",
+            ));
+        }
+        columns.push(column);
+    }
+    Ok(columns)
+}
+
+fn impl_migrate_from_row(columns: &[Column], row: TokenStream) -> TokenStream {
+    let fields = columns.iter().map(|c| {
+        let field = c.field;
+        let name = &c.name;
+        if c.skip {
+            quote! { #field: Default::default() }
+        } else {
+            quote! { #field: row.try_get(#name)? }
+        }
+    });
     quote! {
-        #(fields_builder.push(stringify!(#field_name));)*
+        |row: &#row| -> Result<Self, sqlx::Error> {
+            use sqlx::Row;
+            Ok(Self { #(#fields),* })
+        }
     }
 }
 
-fn impl_migrate_fields_bind(fields: &FieldsNamed) -> proc_macro2::TokenStream {
-    let field_name = fields.named.iter().map(|field| &field.ident);
+fn keys<'a>(columns: &'a [Column<'a>]) -> Vec<&'a Column<'a>> {
+    columns.iter().filter(|c| c.key || c.seq_key).collect()
+}
+
+fn select_sql(table: &str, columns: &[Column]) -> String {
+    let names = columns
+        .iter()
+        .filter(|c| !c.skip)
+        .map(|c| c.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("SELECT {names} FROM {table}")
+}
+
+fn order_sql(columns: &[Column]) -> String {
+    let keys = keys(columns)
+        .iter()
+        .map(|c| c.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("ORDER BY {keys}")
+}
+
+fn impl_migrate_copy(
+    table: &str,
+    columns: &[Column],
+    source: TokenStream,
+    target: TokenStream,
+) -> TokenStream {
+    let copied = columns.iter().filter(|c| !c.skip).collect::<Vec<_>>();
+    let names = copied
+        .iter()
+        .map(|c| c.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let keys = keys(columns);
+    let key_names = keys
+        .iter()
+        .map(|c| c.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let key_binds = (1..=keys.len())
+        .map(|i| format!("${i}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let key_fields = keys.iter().map(|c| c.field);
+    let select = select_sql(table, columns);
+    let order = order_sql(columns);
+    let first = format!("{select} {order} LIMIT $1");
+    let next = format!(
+        "{select} WHERE ({key_names}) > ({key_binds}) {order} LIMIT ${}",
+        keys.len() + 1
+    );
+    let insert = format!("INSERT INTO {table} ({names}) ");
+    let max_rows = 30000 / copied.len().max(1);
+    let binds = copied.iter().map(|c| c.field);
+    let from_row = impl_migrate_from_row(columns, quote! { <#source as sqlx::Database>::Row });
+    let load_progress = format!("SELECT last_key FROM {PROGRESS_TABLE} WHERE table_name = $1");
+    let save_progress = format!(
+        "INSERT INTO {PROGRESS_TABLE} (table_name, last_key) VALUES ($1, $2)
+        ON CONFLICT (table_name) DO UPDATE SET last_key = excluded.last_key"
+    );
     quote! {
-        #(values_builder.push_bind(&row.#field_name);)*
+        let from_row = #from_row;
+        let mut last_key = sqlx::query_scalar::<_, String>(#load_progress)
+            .bind(Self::TABLE)
+            .fetch_optional(target)
+            .await?
+            .map(|key| {
+                key.split(',')
+                    .map(str::parse::<i64>)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| sqlx::Error::Decode(Box::new(e)))
+            })
+            .transpose()?;
+        let batch_size = batch_size.clamp(1, #max_rows);
+        let mut copied = 0;
+        loop {
+            let query = match &last_key {
+                Some(key) => key
+                    .iter()
+                    .fold(sqlx::query::<#source>(#next), |query, k| query.bind(*k)),
+                None => sqlx::query::<#source>(#first),
+            };
+            let batch = query
+                .bind(batch_size as i64)
+                .fetch_all(source)
+                .await?
+                .iter()
+                .map(from_row)
+                .collect::<Result<Vec<_>, _>>()?;
+            let Some(last) = batch.last() else {
+                break;
+            };
+            let key = vec![#(last.#key_fields),*];
+            let rows = batch.len();
+            let mut tx = target.begin().await?;
+            let mut builder = sqlx::QueryBuilder::<#target>::new(#insert);
+            builder.push_values(batch, |mut values, row| {
+                #(values.push_bind(row.#binds);)*
+            });
+            builder.build().execute(&mut *tx).await?;
+            sqlx::query(#save_progress)
+                .bind(Self::TABLE)
+                .bind(
+                    key.iter()
+                        .map(i64::to_string)
+                        .collect::<Vec<_>>()
+                        .join(","),
+                )
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            copied += rows as u64;
+            last_key = Some(key);
+            if rows < batch_size {
+                break;
+            }
+        }
     }
 }
 
-fn impl_migrate_fields_seq_sync(
-    table_name: &TokenStream,
-    fields: &FieldsNamed,
-) -> proc_macro2::TokenStream {
-    if let Some(field) = fields.named.iter().find(|f| {
-        f.attrs
-            .iter()
-            .find(|a| a.path().is_ident("seq_key"))
-            .is_some()
-    }) {
-        let name = &field.ident;
+fn impl_migrate_checksum(table: &str, columns: &[Column], db: TokenStream) -> TokenStream {
+    let select = format!("{} {}", select_sql(table, columns), order_sql(columns));
+    let fields = columns.iter().filter(|c| !c.skip).map(|c| c.field);
+    let from_row = impl_migrate_from_row(columns, quote! { <#db as sqlx::Database>::Row });
+    quote! {
+        use futures::TryStreamExt;
+        use std::hash::{Hash, Hasher};
+        let from_row = #from_row;
+        let mut hasher = std::hash::DefaultHasher::new();
+        let mut count = 0;
+        let mut rows = sqlx::query::<#db>(#select).fetch(pool);
+        while let Some(row) = rows.try_next().await? {
+            let row = from_row(&row)?;
+            #(format!("{:?}", row.#fields).hash(&mut hasher);)*
+            count += 1;
+        }
+        Ok((count, hasher.finish()))
+    }
+}
+
+fn impl_migrate_truncate(table: &str) -> TokenStream {
+    let delete = format!("DELETE FROM {table}");
+    let reset_progress = format!("DELETE FROM {PROGRESS_TABLE} WHERE table_name = $1");
+    quote! {
+        let mut tx = pool.begin().await?;
+        sqlx::query(#delete).execute(&mut *tx).await?;
+        sqlx::query(#reset_progress)
+            .bind(Self::TABLE)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+}
+
+fn impl_migrate_drop_progress() -> TokenStream {
+    let remaining = format!("SELECT COUNT(*) FROM {PROGRESS_TABLE}");
+    let drop = format!("DROP TABLE {PROGRESS_TABLE}");
+    quote! {
+        let remaining: i64 = sqlx::query_scalar(#remaining).fetch_one(pool).await?;
+        if remaining == 0 {
+            sqlx::query(#drop).execute(pool).await?;
+        }
+        Ok(())
+    }
+}
+
+fn impl_migrate_seq_sync(table: &str, columns: &[Column]) -> TokenStream {
+    if let Some(column) = columns.iter().find(|c| c.seq_key) {
+        let name = &column.name;
+        let sql = format!(
+            "SELECT setval(pg_get_serial_sequence('{table}', '{name}'), COALESCE(MAX({name}), 1), MAX({name}) IS NOT NULL) FROM {table}"
+        );
         quote! {
-            sqlx::query(concat!("SELECT setval('", #table_name, "_", stringify!(#name), "_seq', max(", stringify!(#name), ")) FROM ", #table_name)).execute(pg).await.expect("failed to sync seq");
+            sqlx::query(#sql).execute(target).await?;
         }
     } else {
         quote! {}
@@ -52,37 +265,127 @@ fn impl_migrate(ast: &syn::DeriveInput) -> proc_macro::TokenStream {
     let Some(table_attr) = ast.attrs.iter().find(|a| a.path().is_ident("table")) else {
         return error(name.span(), "`table` attribute is required");
     };
+    let table = match table_attr.parse_args::<LitStr>() {
+        Ok(table) => table.value(),
+        Err(e) => return e.into_compile_error().into(),
+    };
 
-    let table_name = &table_attr.meta.require_list().unwrap().tokens;
-
-    if let Data::Struct(ref data) = ast.data {
-        if let Fields::Named(ref named) = data.fields {
-            let fields_push = impl_migrate_fields_push(named);
-            let fields_bind = impl_migrate_fields_bind(named);
-            let sync = impl_migrate_fields_seq_sync(table_name, named);
-            quote! {
-                impl #name {
-                    pub async fn migrate(sqlite: &SqlitePool, pg: &PgPool) {
-                        let mut stream = sqlx::query_as::<_, Self>(concat!("SELECT * FROM ", #table_name)).fetch(sqlite);
-                        while let Some(row) = stream.try_next().await.expect("cannot read record") {
-                            let mut builder = QueryBuilder::new(concat!("INSERT INTO ", #table_name, " ("));
-                            let mut fields_builder = builder.separated(", ");
-                            #fields_push
-                            builder.push(") VALUES (");
-                            let mut values_builder = builder.separated(", ");
-                            #fields_bind
-                            builder.push(")");
-                            builder.build().execute(pg).await.expect("failed to migrate");
-                            #sync
-                        }
-                    }
-                }
+    let Data::Struct(ref data) = ast.data else {
+        return error(name.span(), "Cannot use `Migrate` on non-structs");
+    };
+    let Fields::Named(ref named) = data.fields else {
+        return error(name.span(), "Cannot use `Migrate` on unnamed fields");
+    };
+    let columns = match parse_columns(named) {
+        Ok(columns) => columns,
+        Err(e) => return e.into_compile_error().into(),
+    };
+    if keys(&columns).is_empty() {
+        return error(
+            name.span(),
+            "`Migrate` requires a `seq_key` or `migrate(key)` field",
+        );
+    }
+
+    let to_pg = impl_migrate_copy(
+        &table,
+        &columns,
+        quote! { sqlx::Sqlite },
+        quote! { sqlx::Postgres },
+    );
+    let to_sqlite = impl_migrate_copy(
+        &table,
+        &columns,
+        quote! { sqlx::Postgres },
+        quote! { sqlx::Sqlite },
+    );
+    let seq_sync = impl_migrate_seq_sync(&table, &columns);
+    let sqlite_checksum = impl_migrate_checksum(&table, &columns, quote! { sqlx::Sqlite });
+    let pg_checksum = impl_migrate_checksum(&table, &columns, quote! { sqlx::Postgres });
+    let truncate = impl_migrate_truncate(&table);
+    let count = format!("SELECT COUNT(*) FROM {table}");
+    let create_progress = format!(
+        "CREATE TABLE IF NOT EXISTS {PROGRESS_TABLE} (table_name TEXT PRIMARY KEY NOT NULL, last_key TEXT NOT NULL)"
+    );
+    let clear_progress = format!("DELETE FROM {PROGRESS_TABLE} WHERE table_name = $1");
+    let drop_progress = impl_migrate_drop_progress();
+    quote! {
+        impl #name {
+            pub const TABLE: &'static str = #table;
+
+            pub async fn sqlite_to_pg(
+                source: &sqlx::SqlitePool,
+                target: &sqlx::PgPool,
+                batch_size: usize,
+            ) -> Result<u64, sqlx::Error> {
+                #to_pg
+                #seq_sync
+                Ok(copied)
+            }
+
+            pub async fn pg_to_sqlite(
+                source: &sqlx::PgPool,
+                target: &sqlx::SqlitePool,
+                batch_size: usize,
+            ) -> Result<u64, sqlx::Error> {
+                #to_sqlite
+                Ok(copied)
+            }
+
+            pub async fn count_rows(
+                sqlite: &sqlx::SqlitePool,
+                pg: &sqlx::PgPool,
+            ) -> Result<(i64, i64), sqlx::Error> {
+                Ok((
+                    sqlx::query_scalar(#count).fetch_one(sqlite).await?,
+                    sqlx::query_scalar(#count).fetch_one(pg).await?,
+                ))
+            }
+
+            pub async fn sqlite_checksum(pool: &sqlx::SqlitePool) -> Result<(i64, u64), sqlx::Error> {
+                #sqlite_checksum
+            }
+
+            pub async fn pg_checksum(pool: &sqlx::PgPool) -> Result<(i64, u64), sqlx::Error> {
+                #pg_checksum
+            }
+
+            pub async fn truncate_sqlite(pool: &sqlx::SqlitePool) -> Result<(), sqlx::Error> {
+                #truncate
+            }
+
+            pub async fn truncate_pg(pool: &sqlx::PgPool) -> Result<(), sqlx::Error> {
+                #truncate
+            }
+
+            pub async fn create_progress_sqlite(pool: &sqlx::SqlitePool) -> Result<(), sqlx::Error> {
+                sqlx::query(#create_progress).execute(pool).await?;
+                Ok(())
+            }
+
+            pub async fn create_progress_pg(pool: &sqlx::PgPool) -> Result<(), sqlx::Error> {
+                sqlx::query(#create_progress).execute(pool).await?;
+                Ok(())
+            }
+
+            pub async fn clear_progress_sqlite(pool: &sqlx::SqlitePool) -> Result<(), sqlx::Error> {
+                sqlx::query(#clear_progress).bind(Self::TABLE).execute(pool).await?;
+                Ok(())
+            }
+
+            pub async fn clear_progress_pg(pool: &sqlx::PgPool) -> Result<(), sqlx::Error> {
+                sqlx::query(#clear_progress).bind(Self::TABLE).execute(pool).await?;
+                Ok(())
+            }
+
+            pub async fn drop_progress_sqlite(pool: &sqlx::SqlitePool) -> Result<(), sqlx::Error> {
+                #drop_progress
+            }
+
+            pub async fn drop_progress_pg(pool: &sqlx::PgPool) -> Result<(), sqlx::Error> {
+                #drop_progress
             }
-            .into()
-        } else {
-            error(name.span(), "Cannot use `Migrate` on unnamed fields")
         }
-    } else {
-        error(name.span(), "Cannot use `Migrate` on non-structs")
     }
+    .into()
 }